@@ -1,68 +1,227 @@
+pub mod boot;
+pub mod clock;
+pub mod descriptor;
+pub mod disassemble;
+pub mod dos;
+pub mod error;
+pub mod fpu;
+pub mod instruction;
+pub mod interrupt;
+pub mod lockstep;
+pub mod machine;
+pub mod mmio;
 pub mod modrm;
+pub mod msr;
+pub mod operand_size;
+pub mod port;
+pub mod segment;
+pub mod serial;
+pub mod state;
+#[cfg(test)]
+mod test_util;
+pub mod unsupported;
 
+use crate::emulator::clock::ClockPort;
+use crate::emulator::descriptor::DescriptorTable;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::fpu::Fpu;
+use crate::emulator::instruction::{Instruction, Repeat};
+use crate::emulator::interrupt::InterruptHandler;
+use crate::emulator::mmio::MappedDevice;
 use crate::emulator::modrm::ModRM;
+use crate::emulator::operand_size::CpuMode;
+use crate::emulator::port::PortDevice;
+use crate::emulator::segment::{Segment, SEGMENT_COUNT};
+use crate::emulator::serial::{Serial, SERIAL_PORT};
+use crate::emulator::unsupported::UnsupportedOpcode;
 use bit_field::BitField;
-use log::info;
+use log::{info, warn};
 use paste::paste;
+use std::collections::{BTreeMap, HashSet};
 use std::num::Wrapping;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
-const REGISTER_COUNT: usize = 8;
-
-const EAX: u8 = 0;
-const ECX: u8 = 1;
-const EDX: u8 = 2;
-const EBX: u8 = 3;
-const ESP: u8 = 4;
-const EBP: u8 = 5;
-const ESI: u8 = 6;
-const EDI: u8 = 7;
-
-const AL: u8 = EAX;
-const AH: u8 = AL + 4;
-const CL: u8 = ECX;
-const CH: u8 = CL + 4;
-const DL: u8 = EDX;
-const DH: u8 = DL + 4;
-const BL: u8 = EBX;
-const BH: u8 = BL + 4;
+pub const REGISTER_COUNT: usize = 8;
+
+pub const EAX: u8 = 0;
+pub const ECX: u8 = 1;
+pub const EDX: u8 = 2;
+pub const EBX: u8 = 3;
+pub const ESP: u8 = 4;
+pub const EBP: u8 = 5;
+pub const ESI: u8 = 6;
+pub const EDI: u8 = 7;
+
+pub const AL: u8 = EAX;
+pub const AH: u8 = AL + 4;
+pub const CL: u8 = ECX;
+pub const CH: u8 = CL + 4;
+pub const DL: u8 = EDX;
+pub const DH: u8 = DL + 4;
+pub const BL: u8 = EBX;
+pub const BH: u8 = BL + 4;
+
+/// value `set_poison_init()` fills registers with; memory gets its low byte
+pub const POISON: u32 = 0xcccccccc;
+
+/// how many instructions `run_with_timeout()` executes between clock checks
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
 
 const CARRY_FLAG: usize = 0;
+const PARITY_FLAG: usize = 2;
+const AUXILIARY_FLAG: usize = 4;
 const ZERO_FLAG: usize = 6;
 const SIGN_FLAG: usize = 7;
+const TRAP_FLAG: usize = 8;
+const INTERRUPT_FLAG: usize = 9;
+const DIRECTION_FLAG: usize = 10;
 const OVERFLOW_FLAG: usize = 11;
 
-macro_rules! define_jcc_8 {
-    ($cc:stmt, $f:ident) => {
+/// names accepted by `flag()`, in bit order
+const FLAG_NAMES: [&str; 9] = ["CF", "PF", "AF", "ZF", "SF", "TF", "IF", "DF", "OF"];
+
+/// bit 1 of EFLAGS, which always reads as 1
+const EFLAGS_RESERVED: u32 = 1 << 1;
+/// EFLAGS bits POPFD can change: the status flags, TF, IF and DF. Reserved
+/// bits and system flags (IOPL, NT, RF, VM, AC, ID, ...) are left untouched.
+const POPFD_MASK: u32 = 1 << CARRY_FLAG
+    | 1 << PARITY_FLAG
+    | 1 << AUXILIARY_FLAG
+    | 1 << ZERO_FLAG
+    | 1 << SIGN_FLAG
+    | 1 << TRAP_FLAG
+    | 1 << INTERRUPT_FLAG
+    | 1 << DIRECTION_FLAG
+    | 1 << OVERFLOW_FLAG;
+
+macro_rules! define_logic {
+    ($name:ident, $op:tt) => {
         paste! {
-        fn [<j $cc>](&mut self) {
-            let diff = self.get_sign_code8(1) as u32;
-            if self.$f() {
-                self.eip += diff.wrapping_add(2);
-            } else {
-                self.eip += 2;
-            }
+        fn [<$name _rm32_r32>](&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+            let modrm = instruction.modrm();
+            let result = self.get_rm32(modrm)? $op self.get_r32(modrm);
+            self.update_eflags_logic(result);
+            self.set_rm32(modrm, result)
         }
-        fn [<jn $cc>](&mut self) {
-            let diff = self.get_sign_code8(1) as u32;
-            if !self.$f() {
-                self.eip += diff.wrapping_add(2);
-            } else {
-                self.eip += 2;
-            }
+        fn [<$name _r32_rm32>](&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+            let modrm = instruction.modrm();
+            let result = self.get_r32(modrm) $op self.get_rm32(modrm)?;
+            self.update_eflags_logic(result);
+            self.set_r32(modrm, result);
+            Ok(())
         }
         }
     };
 }
 
+/// Operand size an arithmetic flag update is computed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperandWidth {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl OperandWidth {
+    fn bits(self) -> usize {
+        match self {
+            OperandWidth::Byte => 8,
+            OperandWidth::Word => 16,
+            OperandWidth::Dword => 32,
+        }
+    }
+}
+
+/// Operation whose flags `update_eflags()` sets; decides how OF is derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlagOp {
+    Add,
+    Sub,
+}
+
+/// Called by `step()` with each decoded instruction right before it executes.
+/// Changes made to the instruction affect what is executed.
+pub type PreExecuteHook = Box<dyn FnMut(&Emulator, &mut Instruction)>;
+
+/// Called by `step()` with EIP and the byte there before anything is decoded.
+pub type TraceHook = Box<dyn FnMut(&Emulator, u32, u8)>;
+
+/// Why `run()` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunExit {
+    /// EIP reached 0, i.e. the program returned from its entry point or
+    /// exited through INT 20h or INT 21h AH = 0x4c
+    Terminated,
+    /// EIP reached a breakpoint; the instruction there is not executed yet
+    Breakpoint { address: u32 },
+    /// the last instruction changed a watched byte
+    Watchpoint { address: u32, old: u8, new: u8 },
+    /// the wall-clock limit of `run_with_timeout()` passed
+    Timeout,
+    /// the CPU executed HLT
+    Halted,
+    /// `run_with_limit()` executed as many instructions as it was allowed
+    StepLimit,
+}
+
 pub struct Emulator {
     /// general purpose registers
     pub registers: [u32; REGISTER_COUNT],
     /// eflags register
     pub eflags: u32,
-    /// program counter
-    pub eip: Wrapping<u32>,
+    /// program counter; see `eip()` and `set_eip()`
+    eip: Wrapping<u32>,
     /// memory
     pub memory: Vec<u8>,
+    /// x87 register stack
+    pub fpu: Fpu,
+    /// segment register selectors
+    segments: [u16; SEGMENT_COUNT],
+    /// segment base addresses; all 0 (flat memory) by default
+    segment_bases: [u32; SEGMENT_COUNT],
+    /// global descriptor table register, set by LGDT
+    gdtr: DescriptorTable,
+    /// size linear addresses wrap around at, if any
+    address_wrap: Option<usize>,
+    /// address line 20; when disabled, bit 20 of every linear address is cleared
+    a20_enabled: bool,
+    /// lowest ESP allowed when stack discipline checks are enabled
+    stack_guard: Option<u32>,
+    /// whether `run()` skips unknown opcodes instead of failing
+    skip_unsupported: bool,
+    /// unknown opcodes skipped by `run()`
+    unsupported: BTreeMap<u16, UnsupportedOpcode>,
+    /// called between decode and execute
+    pre_execute_hook: Option<PreExecuteHook>,
+    /// called before each instruction is decoded
+    trace_hook: Option<TraceHook>,
+    /// named address ranges used to label dumps
+    regions: Vec<(String, Range<u32>)>,
+    /// addresses `run()` stops at
+    breakpoints: HashSet<u32>,
+    /// watched addresses and the value last seen there
+    watchpoints: BTreeMap<u32, u8>,
+    /// serial console at `SERIAL_PORT`
+    serial: Serial,
+    /// devices attached to I/O ports
+    ports: BTreeMap<u16, Box<dyn PortDevice>>,
+    /// model-specific registers RDMSR/WRMSR can access
+    msrs: BTreeMap<u32, u64>,
+    /// instructions executed so far
+    instruction_count: u64,
+    /// port the instruction counter can be read from
+    clock: Option<ClockPort>,
+    /// set by HLT; `run()` does not execute anything while it is set
+    halted: bool,
+    /// whether registers and memory start out as `POISON`
+    poison_init: bool,
+    /// memory ranges routed to devices
+    mmio: Vec<MappedDevice>,
+    /// what INT n calls, by vector
+    interrupt_handlers: BTreeMap<u8, InterruptHandler>,
+    /// default operand size
+    mode: CpuMode,
 }
 
 impl Emulator {
@@ -72,560 +231,4023 @@ impl Emulator {
             eflags: 0,
             eip: Wrapping(eip),
             memory: vec![0; size],
+            fpu: Fpu::default(),
+            segments: [0; SEGMENT_COUNT],
+            segment_bases: [0; SEGMENT_COUNT],
+            gdtr: DescriptorTable::default(),
+            address_wrap: None,
+            a20_enabled: true,
+            stack_guard: None,
+            skip_unsupported: false,
+            unsupported: BTreeMap::new(),
+            pre_execute_hook: None,
+            trace_hook: None,
+            regions: Vec::new(),
+            breakpoints: HashSet::new(),
+            watchpoints: BTreeMap::new(),
+            serial: Serial::default(),
+            ports: BTreeMap::new(),
+            msrs: BTreeMap::new(),
+            instruction_count: 0,
+            clock: None,
+            halted: false,
+            poison_init: false,
+            mmio: Vec::new(),
+            interrupt_handlers: interrupt::default_handlers().into_iter().collect(),
+            mode: CpuMode::Protected32,
         };
         emulator.registers[ESP as usize] = esp;
         emulator
     }
 
-    pub fn instruction(&mut self) -> fn(&mut Emulator) {
-        let code = self.get_code8(0);
-        info!("EIP = {:08x}, Code = {:02x}", self.eip, code);
-        match self.get_code8(0) {
-            0x01 => Self::add_rm32_r32,
-            0x3b => Self::cmp_r32_rm32,
-            0x3c => Self::cmp_al_imm8,
-            0x3d => Self::cmp_eax_imm32,
-            0x40..=0x47 => Self::inc_r32,
-            0x50..=0x57 => Self::push_r32,
-            0x58..=0x5f => Self::pop_r32,
-            0x68 => Self::push_imm32,
-            0x6a => Self::push_imm8,
-            0x70 => Self::jo,
-            0x71 => Self::jno,
-            0x72 => Self::jc,
-            0x73 => Self::jnc,
-            0x74 => Self::jz,
-            0x75 => Self::jnz,
-            0x76 => Self::jbe,
-            0x77 => Self::jnbe,
-            0x78 => Self::js,
-            0x79 => Self::jns,
-            0x7c => Self::jl,
-            0x7d => Self::jnl,
-            0x7e => Self::jle,
-            0x7f => Self::jnle,
-            0x83 => Self::code_83,
-            0x88 => Self::mov_rm8_r8,
-            0x89 => Self::mov_rm32_r32,
-            0x8a => Self::mov_r8_rm8,
-            0x8b => Self::mov_r32_rm32,
-            0xb0..=0xb7 => Self::mov_r8_imm8,
-            0xb8..=0xbf => Self::mov_r32_imm32,
-            0xc3 => Self::ret,
-            0xc7 => Self::mov_rm32_imm32,
-            0xc9 => Self::leave,
-            0xe8 => Self::call_rel32,
-            0xe9 => Self::near_jump,
-            0xeb => Self::short_jump,
-            0xec => Self::in_al_dx,
-            0xee => Self::out_dx_al,
-            0xff => Self::code_ff,
-            _ => unimplemented!("Not implemented code: {:02x}", code),
-        }
-    }
-
-    pub fn parse_modrm(&mut self) -> ModRM {
-        let code = self.get_code8(0);
-        let mut modrm = ModRM::from_code(code);
-        self.eip += 1;
-
-        if modrm.has_sib() {
-            modrm.set_sib(self.get_code8(0));
-            self.eip += 1;
-        }
-
-        if modrm.has_disp32() {
-            modrm.set_disp32(self.get_sign_code32(0));
-            self.eip += 4;
-        } else if modrm.has_disp8() {
-            modrm.set_disp8(self.get_sign_code8(0));
-            self.eip += 1;
-        }
+    /// Puts the CPU back into its initial state with the given EIP and ESP:
+    /// registers (`POISON` under `set_poison_init()`), EFLAGS, the x87 stack,
+    /// segment registers and bases, GDTR, the halted state and the instruction counter. Memory,
+    /// devices and debugging aids are kept; see `clear_memory()`.
+    pub fn reset(&mut self, eip: u32, esp: u32) {
+        let register = if self.poison_init { POISON } else { 0 };
+        self.registers = [register; REGISTER_COUNT];
+        self.registers[ESP as usize] = esp;
+        self.eflags = 0;
+        self.eip = Wrapping(eip);
+        self.fpu = Fpu::default();
+        self.segments = [0; SEGMENT_COUNT];
+        self.segment_bases = [0; SEGMENT_COUNT];
+        self.gdtr = DescriptorTable::default();
+        self.halted = false;
+        self.instruction_count = 0;
+    }
 
-        info!(
-            "mod = {}, op = {}, rm = {} ({:02X})",
-            modrm.md, modrm.op, modrm.rm, code
-        );
-        modrm
+    /// Zeroes memory, or fills it with `POISON` under `set_poison_init()`,
+    /// without reallocating it.
+    pub fn clear_memory(&mut self) {
+        let byte = if self.poison_init { POISON as u8 } else { 0 };
+        self.memory.fill(byte);
     }
 
-    fn mov_r32_imm32(&mut self) {
-        let reg = self.get_code8(0) - 0xb8;
-        let value = self.get_code32(1);
-        self.set_register32(reg, value);
-        self.eip += 5;
-    }
-
-    fn mov_rm32_imm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let value = self.get_code32(0);
-        self.eip += 4;
-        self.set_rm32(&modrm, value);
-    }
-    fn mov_rm32_r32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r32 = self.get_r32(&modrm);
-        self.set_rm32(&modrm, r32);
-    }
-    fn mov_r32_rm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let rm32 = self.get_rm32(&modrm);
-        self.set_r32(&modrm, rm32);
-    }
-    fn add_rm32_r32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r32 = self.get_r32(&modrm);
-        let rm32 = self.get_rm32(&modrm);
-        self.set_rm32(&modrm, rm32.wrapping_add(r32));
-    }
-    fn add_rm32_imm8(&mut self, modrm: &ModRM) {
-        let rm32 = self.get_rm32(modrm);
-        let imm8 = self.get_sign_code8(0) as u32;
-        self.eip += 1;
-        self.set_rm32(modrm, rm32.wrapping_add(imm8));
-    }
-    fn sub_rm32_imm8(&mut self, modrm: &ModRM) {
-        let rm32 = self.get_rm32(modrm);
-        let imm8 = self.get_sign_code8(0) as u32;
-        self.eip += 1;
-        let result = (rm32 as u64).wrapping_sub(imm8 as u64);
-        self.update_eflags_sub(rm32, imm8, result);
-        self.set_rm32(modrm, rm32.wrapping_sub(imm8));
-    }
-    fn code_83(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        match modrm.op {
-            0 => self.add_rm32_imm8(&modrm),
-            5 => self.sub_rm32_imm8(&modrm),
-            7 => self.cmp_rm32_imm8(&modrm),
-            _ => unimplemented!("Not implemented 0x83 /{}", modrm.op),
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Extends memory to `new_size` bytes, filled like `clear_memory()`
+    /// would. Existing contents are kept; shrinking is an error.
+    pub fn grow_memory(&mut self, new_size: usize) -> Result<(), EmulatorError> {
+        if new_size < self.memory.len() {
+            return Err(EmulatorError::MemoryShrink {
+                size: self.memory.len(),
+                requested: new_size,
+            });
         }
+        let byte = if self.poison_init { POISON as u8 } else { 0 };
+        self.memory.resize(new_size, byte);
+        Ok(())
     }
-    fn inc_rm32(&mut self, modrm: &ModRM) {
-        let value = self.get_rm32(modrm);
-        self.set_rm32(modrm, value.wrapping_add(1));
+
+    /// Copies `bytes` to physical memory at `address`. Fails without writing
+    /// anything if they do not fit.
+    pub fn load(&mut self, bytes: &[u8], address: u32) -> Result<(), EmulatorError> {
+        let start = address as usize;
+        let end = start + bytes.len();
+        if end > self.memory.len() {
+            return Err(EmulatorError::OutOfBounds {
+                address: start.max(self.memory.len()) as u32,
+            });
+        }
+        self.memory[start..end].copy_from_slice(bytes);
+        Ok(())
     }
 
-    fn inc_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x40;
-        let value = self.get_register32(reg);
-        self.set_register32(reg, value.wrapping_add(1));
-        self.eip += 1;
+    /// address of the next instruction
+    pub fn eip(&self) -> u32 {
+        self.eip.0
     }
-    fn dec_rm32(&mut self, modrm: &ModRM) {
-        let value = self.get_rm32(modrm);
-        self.set_rm32(modrm, value.wrapping_sub(1));
+    pub fn set_eip(&mut self, address: u32) {
+        self.eip = Wrapping(address);
     }
-    fn code_ff(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        match modrm.op {
-            0 => self.inc_rm32(&modrm),
-            1 => self.dec_rm32(&modrm),
-            _ => unimplemented!("Not implemented 0xff /{}", modrm.op),
-        }
+
+    /// Runs until one of the stop conditions in `RunExit` holds.
+    ///
+    /// A breakpoint at the address execution starts from is not reported,
+    /// so calling `run()` again after `RunExit::Breakpoint` continues past it.
+    pub fn run(&mut self) -> Result<RunExit, EmulatorError> {
+        self.run_until(None, None)
     }
-    fn cmp_r32_rm32(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r32 = self.get_r32(&modrm);
-        let rm32 = self.get_rm32(&modrm);
-        let result = (r32 as u64).wrapping_sub(rm32 as u64);
-        self.update_eflags_sub(r32, rm32, result);
+
+    /// Like `run()`, but gives up with `RunExit::Timeout` once `duration`
+    /// has elapsed. The clock is checked every `TIMEOUT_CHECK_INTERVAL` (1024)
+    /// instructions.
+    pub fn run_with_timeout(&mut self, duration: Duration) -> Result<RunExit, EmulatorError> {
+        self.run_until(Some(Instant::now() + duration), None)
     }
 
-    fn cmp_eax_imm32(&mut self) {
-        self.eip += 1;
-        let value = self.get_code32(1);
-        let eax = self.get_register32(EAX);
-        let result = (eax as u64).wrapping_sub(value as u64);
-        self.update_eflags_sub(value, eax, result);
+    /// Like `run()`, but stops with `RunExit::StepLimit` after executing
+    /// `max` instructions.
+    pub fn run_with_limit(&mut self, max: u64) -> Result<RunExit, EmulatorError> {
+        self.run_until(None, Some(max))
     }
 
-    fn cmp_rm32_imm8(&mut self, modrm: &ModRM) {
-        let rm32 = self.get_rm32(modrm);
-        let imm8 = self.get_sign_code8(0) as u32;
-        self.eip += 1;
-        let result = (rm32 as u64).wrapping_sub(imm8 as u64);
-        self.update_eflags_sub(rm32, imm8, result);
+    fn run_until(
+        &mut self,
+        deadline: Option<Instant>,
+        limit: Option<u64>,
+    ) -> Result<RunExit, EmulatorError> {
+        let start = self.eip.0;
+        let start_count = self.instruction_count;
+        let mut first = true;
+        let mut steps: u64 = 0;
+        loop {
+            if self.halted {
+                return Ok(RunExit::Halted);
+            }
+            if limit.is_some_and(|max| self.instruction_count - start_count >= max) {
+                return Ok(RunExit::StepLimit);
+            }
+            if let Some(deadline) = deadline {
+                if steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && Instant::now() >= deadline {
+                    return Ok(RunExit::Timeout);
+                }
+            }
+            steps += 1;
+            if !(first && self.eip.0 == start) && self.breakpoints.contains(&self.eip.0) {
+                return Ok(RunExit::Breakpoint {
+                    address: self.eip.0,
+                });
+            }
+            first = false;
+            match self.step() {
+                Err(EmulatorError::UnknownOpcode(opcode)) if self.skip_unsupported => {
+                    self.skip_unsupported_opcode(opcode)?
+                }
+                result => result?,
+            }
+            if let Some(exit) = self.check_watchpoints()? {
+                return Ok(exit);
+            }
+            if self.eip.0 == 0 {
+                return Ok(RunExit::Terminated);
+            }
+        }
     }
 
-    fn short_jump(&mut self) {
-        let diff = self.get_sign_code8(1);
-        self.eip = Wrapping(
-            ((self.eip.0 as i32)
-                .wrapping_add(diff as i32)
-                .wrapping_add(2)) as u32,
-        );
+    pub fn is_halted(&self) -> bool {
+        self.halted
     }
 
-    fn near_jump(&mut self) {
-        let diff = self.get_code32(1);
-        self.eip += diff.wrapping_add(5);
+    /// Makes linear addresses wrap modulo `size` (e.g. 1 MiB for real mode
+    /// without A20), or disables wrapping with `None`.
+    pub fn set_address_wrap(&mut self, size: Option<usize>) {
+        self.address_wrap = size;
     }
 
-    define_jcc_8!(c, get_carry);
-    define_jcc_8!(z, get_zero);
-    define_jcc_8!(s, get_sign);
-    define_jcc_8!(o, get_overflow);
-    define_jcc_8!(be, get_cond_be);
-    define_jcc_8!(l, get_cond_l);
-    define_jcc_8!(le, get_cond_le);
+    /// Sets the base address of a segment. Only string instructions apply
+    /// segment bases so far; other memory operands are still flat.
+    pub fn set_segment_base(&mut self, segment: Segment, base: u32) {
+        self.segment_bases[segment as usize] = base;
+    }
+    pub fn segment_base(&self, segment: Segment) -> u32 {
+        self.segment_bases[segment as usize]
+    }
 
-    fn get_cond_be(&self) -> bool {
-        self.get_carry() || self.get_zero()
+    /// When enabled, `reset()` fills every register but ESP with `POISON`,
+    /// `clear_memory()` and `grow_memory()` fill memory with its low byte
+    /// (0xcc, INT3), and a poisoned register used as a memory operand base
+    /// is warned about. Current registers and memory are left alone.
+    pub fn set_poison_init(&mut self, enabled: bool) {
+        self.poison_init = enabled;
     }
-    fn get_cond_l(&self) -> bool {
-        self.get_sign() != self.get_overflow()
+
+    pub fn set_a20(&mut self, enabled: bool) {
+        self.a20_enabled = enabled;
     }
-    fn get_cond_le(&self) -> bool {
-        self.get_zero() || self.get_sign() != self.get_overflow()
+    pub fn is_a20_enabled(&self) -> bool {
+        self.a20_enabled
     }
 
-    fn get_code8(&self, index: usize) -> u8 {
-        self.memory[(self.eip + Wrapping(index as u32)).0 as usize]
+    pub fn set_pre_execute_hook(&mut self, hook: PreExecuteHook) {
+        self.pre_execute_hook = Some(hook);
+    }
+    pub fn clear_pre_execute_hook(&mut self) {
+        self.pre_execute_hook = None;
     }
 
-    fn get_sign_code8(&self, index: usize) -> i8 {
-        self.get_code8(index) as i8
+    pub fn set_trace_hook(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
+    }
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
     }
 
-    fn get_code32(&self, index: usize) -> u32 {
-        u32::from_le_bytes([
-            self.get_code8(index),
-            self.get_code8(index + 1),
-            self.get_code8(index + 2),
-            self.get_code8(index + 3),
-        ])
+    /// Makes `step()` fail with `EmulatorError::StackGuard` after any
+    /// instruction that leaves ESP below `guard`, i.e. grows the stack
+    /// (downwards) past its declared limit, or that pushes without ESP
+    /// going down. The instruction has already run when the error is
+    /// returned, so its stores and register updates stay in place.
+    pub fn enable_stack_discipline_checks(&mut self, guard: u32) {
+        self.stack_guard = Some(guard);
+    }
+    pub fn disable_stack_discipline_checks(&mut self) {
+        self.stack_guard = None;
     }
 
-    fn get_sign_code32(&self, index: usize) -> i32 {
-        self.get_code32(index) as i32
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+    pub fn remove_breakpoint(&mut self, address: u32) {
+        self.breakpoints.remove(&address);
     }
 
-    pub fn dump(&self) -> String {
-        let mut s = String::new();
-        s.push_str(&format!("EIP = {:08x}\n", self.eip));
-        s.push_str(&self.dump_registers());
-        s.push_str(&self.dump_eflags());
-        s
+    /// Watches the byte at `address`; `run()` stops after any instruction that changes it.
+    /// Fails with `EmulatorError::OutOfBounds` if `address` is outside memory.
+    pub fn add_watchpoint(&mut self, address: u32) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory8(address)?;
+        self.watchpoints.insert(address, value);
+        Ok(())
     }
-    fn dump_registers(&self) -> String {
-        let mut s = String::new();
-        for i in 0..REGISTER_COUNT {
-            s.push_str(&format!(
-                "{} = {:08x}\n",
-                Self::register_name(i),
-                self.registers[i]
-            ));
-        }
-        s
+    pub fn remove_watchpoint(&mut self, address: u32) {
+        self.watchpoints.remove(&address);
     }
-    fn dump_eflags(&self) -> String {
-        format!("EFLAGS = {:08x}\n", self.eflags)
+
+    /// Reads watched bytes through the same translation as `add_watchpoint()`.
+    fn check_watchpoints(&mut self) -> Result<Option<RunExit>, EmulatorError> {
+        let mut changed = None;
+        for (&address, &old) in &self.watchpoints {
+            let new = self.try_get_memory8(address)?;
+            if new != old {
+                changed = Some((address, old, new));
+                break;
+            }
+        }
+        let Some((address, old, new)) = changed else {
+            return Ok(None);
+        };
+        self.watchpoints.insert(address, new);
+        Ok(Some(RunExit::Watchpoint { address, old, new }))
     }
-    fn register_name(index: usize) -> &'static str {
-        match index {
-            0 => "EAX",
-            1 => "ECX",
-            2 => "EDX",
-            3 => "EBX",
-            4 => "ESP",
-            5 => "EBP",
-            6 => "ESI",
-            7 => "EDI",
-            _ => unreachable!(),
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        if self.physical_address(self.eip.0) >= self.memory.len() {
+            return Err(EmulatorError::OutOfBounds {
+                address: self.eip.0,
+            });
         }
+        if let Some(mut hook) = self.trace_hook.take() {
+            hook(self, self.eip.0, self.try_get_memory8(self.eip.0)?);
+            self.trace_hook = Some(hook);
+        }
+        let mut instruction = self.decode(self.eip.0)?;
+        if let Some(mut hook) = self.pre_execute_hook.take() {
+            hook(self, &mut instruction);
+            self.pre_execute_hook = Some(hook);
+        }
+        let esp = self.get_register32(ESP);
+        self.execute(&instruction)?;
+        self.instruction_count += 1;
+        self.check_stack_guard(&instruction, esp)
     }
 
-    fn get_rm32(&self, modrm: &ModRM) -> u32 {
-        if modrm.is_reg() {
-            self.get_register32(modrm.rm)
-        } else {
-            let address = self.calc_memory_address(modrm);
-            self.get_memory32(address)
+    /// `esp_before` is ESP before `instruction` ran.
+    fn check_stack_guard(
+        &self,
+        instruction: &Instruction,
+        esp_before: u32,
+    ) -> Result<(), EmulatorError> {
+        let esp = self.get_register32(ESP);
+        match self.stack_guard {
+            Some(guard) if esp < guard || (Self::pushes(instruction) && esp >= esp_before) => {
+                Err(EmulatorError::StackGuard {
+                    address: instruction.address,
+                    esp,
+                })
+            }
+            _ => Ok(()),
         }
     }
 
-    fn set_rm32(&mut self, modrm: &ModRM, value: u32) {
-        if modrm.is_reg() {
-            self.set_register32(modrm.rm, value);
-        } else {
-            let address = self.calc_memory_address(modrm);
-            self.set_memory32(address, value);
+    /// PUSH, CALL and ENTER, which always move ESP down.
+    fn pushes(instruction: &Instruction) -> bool {
+        match instruction.opcode {
+            0x50..=0x57 | 0x68 | 0x6a | 0x9a | 0x9c | 0xc8 | 0xe8 => true,
+            0xff => instruction.modrm().op == 2,
+            _ => false,
         }
     }
 
-    fn get_rm8(&self, modrm: &ModRM) -> u8 {
-        if modrm.is_reg() {
-            self.get_register8(modrm.rm)
-        } else {
-            let address = self.calc_memory_address(modrm);
-            self.get_memory8(address)
+    /// Executes a decoded instruction. EIP is moved past it first, so
+    /// relative jumps and calls work from the address of the next instruction.
+    pub fn execute(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        info!(
+            "EIP = {:08x}, Code = {:02x}",
+            instruction.address, instruction.opcode
+        );
+        // the pre-execute hook may have replaced a decoded memory operand
+        if instruction.modrm.as_ref().is_some_and(ModRM::is_reg)
+            && Self::memory_only(instruction.opcode)
+        {
+            return Err(EmulatorError::IllegalOperand {
+                opcode: instruction.opcode,
+            });
+        }
+        self.eip = Wrapping(instruction.next());
+        if instruction.prefixes.operand_size && !Self::ignores_operand_size(instruction.opcode) {
+            return self.execute16(instruction);
         }
+        match instruction.opcode {
+            0x00 => return self.add_rm8_r8(instruction),
+            0x01 => return self.add_rm32_r32(instruction),
+            0x02 => return self.add_r8_rm8(instruction),
+            0x09 => return self.or_rm32_r32(instruction),
+            0x0b => return self.or_r32_rm32(instruction),
+            0x11 => return self.adc_rm32_r32(instruction),
+            0x13 => return self.adc_r32_rm32(instruction),
+            0x19 => return self.sbb_rm32_r32(instruction),
+            0x1b => return self.sbb_r32_rm32(instruction),
+            0x21 => return self.and_rm32_r32(instruction),
+            0x23 => return self.and_r32_rm32(instruction),
+            0x28 => return self.sub_rm8_r8(instruction),
+            0x29 => return self.sub_rm32_r32(instruction),
+            0x2b => return self.sub_r32_rm32(instruction),
+            0x31 => return self.xor_rm32_r32(instruction),
+            0x33 => return self.xor_r32_rm32(instruction),
+            0x38 => return self.cmp_rm8_r8(instruction),
+            0x3a => return self.cmp_r8_rm8(instruction),
+            0x3b => return self.cmp_r32_rm32(instruction),
+            0x3c => self.cmp_al_imm8(instruction),
+            0x3d => self.cmp_eax_imm32(instruction),
+            0x40..=0x47 => self.inc_r32(instruction),
+            0x48..=0x4f => self.dec_r32(instruction),
+            0x50..=0x57 => return self.push_r32(instruction),
+            0x58..=0x5f => return self.pop_r32(instruction),
+            0x68 => return self.push_imm32(instruction),
+            0x69 | 0x6b => return self.imul_r32_rm32_imm(instruction),
+            0x6a => return self.push_imm8(instruction),
+            0x70..=0x7f => self.jcc(instruction),
+            0x80 => return self.code_80(instruction),
+            0x81 | 0x83 => return self.code_81(instruction),
+            0x84 => return self.test_rm8_r8(instruction),
+            0x85 => return self.test_rm32_r32(instruction),
+            0x86 => return self.xchg_rm8_r8(instruction),
+            0x87 => return self.xchg_rm32_r32(instruction),
+            0x88 => return self.mov_rm8_r8(instruction),
+            0x89 => return self.mov_rm32_r32(instruction),
+            0x8a => return self.mov_r8_rm8(instruction),
+            0x8b => return self.mov_r32_rm32(instruction),
+            0x8c => return self.mov_rm16_sreg(instruction),
+            0x8d => self.lea_r32_m(instruction),
+            0x8e => return self.mov_sreg_rm16(instruction),
+            0x90 => {}
+            0x91..=0x97 => self.xchg_eax_r32(instruction),
+            0x98 => self.cwde(),
+            0x99 => self.cdq(),
+            0x9a => return self.call_far(instruction),
+            0x9b => self.fwait(),
+            0x9c => return self.pushfd(),
+            0x9d => return self.popfd(),
+            0xa0 => return self.mov_al_moffs8(instruction),
+            0xa1 => return self.mov_eax_moffs32(instruction),
+            0xa2 => return self.mov_moffs8_al(instruction),
+            0xa3 => return self.mov_moffs32_eax(instruction),
+            0xa8 => self.test_al_imm8(instruction),
+            0xa9 => self.test_eax_imm32(instruction),
+            0xa4 => return self.repeat(instruction, Self::movs_m8),
+            0xa5 => return self.repeat(instruction, Self::movs_m32),
+            0xaa => return self.repeat(instruction, Self::stos_m8),
+            0xab => return self.repeat(instruction, Self::stos_m32),
+            0xac => return self.repeat(instruction, Self::lods_m8),
+            0xad => return self.repeat(instruction, Self::lods_m32),
+            0xb0..=0xb7 => self.mov_r8_imm8(instruction),
+            0xb8..=0xbf => self.mov_r32_imm32(instruction),
+            0xc1 => return self.code_c1(instruction),
+            0xc2 => return self.ret_imm16(instruction),
+            0xc3 => return self.ret(),
+            0xc7 => return self.mov_rm32_imm32(instruction),
+            0xc8 => return self.enter(instruction),
+            0xc9 => return self.leave(),
+            0xcd => return self.int_imm8(instruction),
+            0xd1 => return self.code_d1(instruction),
+            0xd3 => return self.code_d3(instruction),
+            0xd8..=0xdf => return self.x87(instruction),
+            0xe0..=0xe3 => self.loop_rel8(instruction),
+            0xe8 => return self.call_rel32(instruction),
+            0xe9 => self.near_jump(instruction),
+            0xea => self.jmp_far(instruction),
+            0xeb => self.short_jump(instruction),
+            0xe4 => self.in_al_imm8(instruction),
+            0xe5 => self.in_eax_imm8(instruction),
+            0xe6 => self.out_imm8_al(instruction),
+            0xe7 => self.out_imm8_eax(instruction),
+            0xec => self.in_al_dx(),
+            0xed => self.in_eax_dx(),
+            0xee => self.out_dx_al(),
+            0xef => self.out_dx_eax(),
+            0xf4 => self.hlt(),
+            0xfc => self.cld(),
+            0xfd => self.std(),
+            0xf7 => return self.code_f7(instruction),
+            0xfe => return self.code_fe(instruction),
+            0xff => return self.code_ff(instruction),
+            0x0f01 => return self.code_0f01(instruction),
+            0x0f1f => {}
+            0x0f30 => return self.wrmsr(),
+            0x0f32 => return self.rdmsr(),
+            0x0f33 => return self.rdpmc(),
+            0x0f40..=0x0f4f => return self.cmovcc(instruction),
+            0x0f80..=0x0f8f => self.jcc(instruction),
+            0x0f90..=0x0f9f => return self.setcc(instruction),
+            0x0fa3 | 0x0fab | 0x0fb3 | 0x0fbb => return self.bit_rm32_r32(instruction),
+            0x0faf => return self.imul_r32_rm32(instruction),
+            0x0fb6 => return self.movzx_r32_rm8(instruction),
+            0x0fb7 => return self.movzx_r32_rm16(instruction),
+            0x0fb8 => return self.popcnt(instruction),
+            0x0fba => return self.code_0fba(instruction),
+            0x0fbc | 0x0fbd => return self.bit_scan(instruction),
+            0x0fbe => return self.movsx_r32_rm8(instruction),
+            0x0fbf => return self.movsx_r32_rm16(instruction),
+            0x0fc8..=0x0fcf => self.bswap(instruction),
+            opcode => return Err(EmulatorError::UnknownOpcode(opcode)),
+        }
+        Ok(())
     }
 
-    fn set_rm8(&mut self, modrm: &ModRM, value: u8) {
+    /// Executes an x87 instruction (escape opcode 0xd8-0xdf).
+    /// Unsupported forms are still consumed, so EIP points past them on error.
+    fn x87(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let opcode = instruction.opcode as u8;
+        let modrm = instruction.modrm();
         if modrm.is_reg() {
-            self.set_register8(modrm.rm, value);
+            self.x87_register(opcode, modrm)
+                .ok_or(EmulatorError::UnsupportedFpu { opcode })
         } else {
-            let address = self.calc_memory_address(modrm);
-            self.set_memory8(address, value);
+            self.x87_memory(opcode, modrm)
         }
     }
-    fn set_register32(&mut self, reg: u8, value: u32) {
-        self.registers[reg as usize] = value;
-    }
-    fn get_register32(&self, reg: u8) -> u32 {
-        self.registers[reg as usize]
-    }
-    fn calc_memory_address(&self, modrm: &ModRM) -> u32 {
-        match modrm.md {
-            0 => {
-                if modrm.rm == 4 {
-                    unimplemented!("Not implemented ModRM mod = 0, rm = 4");
-                } else if modrm.rm == 5 {
-                    modrm.disp as u32
-                } else {
-                    self.get_register32(modrm.rm)
-                }
+
+    fn x87_memory(&mut self, opcode: u8, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let address = self.calc_memory_address(modrm);
+        match (opcode, modrm.op) {
+            (0xd8, op) => {
+                let value = f32::from_bits(self.try_get_memory32(address)?) as f64;
+                let result = Self::x87_arith(op, self.fpu.st(0), value)
+                    .ok_or(EmulatorError::UnsupportedFpu { opcode })?;
+                self.fpu.set_st(0, result);
             }
-            1 => {
-                if modrm.rm == 4 {
-                    unimplemented!("Not implemented ModRM mod = 1, rm = 4");
-                } else {
-                    self.get_register32(modrm.rm)
-                        .wrapping_add(modrm.disp as u32)
-                }
+            (0xdc, op) => {
+                let value = f64::from_bits(self.try_get_memory64(address)?);
+                let result = Self::x87_arith(op, self.fpu.st(0), value)
+                    .ok_or(EmulatorError::UnsupportedFpu { opcode })?;
+                self.fpu.set_st(0, result);
             }
-            2 => {
-                if modrm.rm == 4 {
-                    unimplemented!("Not implemented ModRM mod = 2, rm = 4");
-                } else {
-                    self.get_register32(modrm.rm)
-                        .wrapping_add(modrm.disp as u32)
+            (0xd9, 0) => {
+                let value = f32::from_bits(self.try_get_memory32(address)?) as f64;
+                self.fpu.push(value);
+            }
+            (0xd9, 2) => return self.try_set_memory32(address, (self.fpu.st(0) as f32).to_bits()),
+            (0xd9, 3) => {
+                let value = self.fpu.pop();
+                self.try_set_memory32(address, (value as f32).to_bits())?;
+            }
+            (0xdd, 0) => {
+                let value = f64::from_bits(self.try_get_memory64(address)?);
+                self.fpu.push(value);
+            }
+            (0xdd, 2) => return self.try_set_memory64(address, self.fpu.st(0).to_bits()),
+            (0xdd, 3) => {
+                let value = self.fpu.pop();
+                self.try_set_memory64(address, value.to_bits())?;
+            }
+            (0xdb, 0) => {
+                let value = self.try_get_memory32(address)? as i32;
+                self.fpu.push(value as f64);
+            }
+            (0xdb, 2) => {
+                return self.try_set_memory32(address, Self::x87_to_i32(self.fpu.st(0)) as u32)
+            }
+            (0xdb, 3) => {
+                let value = self.fpu.pop();
+                self.try_set_memory32(address, Self::x87_to_i32(value) as u32)?;
+            }
+            (0xdf, 5) => {
+                let value = self.try_get_memory64(address)? as i64;
+                self.fpu.push(value as f64);
+            }
+            (0xdf, 7) => {
+                let value = self.fpu.pop();
+                self.try_set_memory64(address, Self::x87_to_i64(value) as u64)?;
+            }
+            _ => return Err(EmulatorError::UnsupportedFpu { opcode }),
+        }
+        Ok(())
+    }
+
+    fn x87_register(&mut self, opcode: u8, modrm: &ModRM) -> Option<()> {
+        let i = modrm.rm;
+        match (opcode, modrm.op) {
+            (0xd8, op) => {
+                let result = Self::x87_arith(op, self.fpu.st(0), self.fpu.st(i))?;
+                self.fpu.set_st(0, result);
+            }
+            (0xdc | 0xde, op) => {
+                // the reversed forms swap SUB/SUBR and DIV/DIVR encodings
+                let op = match op {
+                    0 | 1 => op,
+                    5 => 4,
+                    7 => 6,
+                    _ => return None,
+                };
+                let result = Self::x87_arith(op, self.fpu.st(i), self.fpu.st(0))?;
+                self.fpu.set_st(i, result);
+                if opcode == 0xde {
+                    self.fpu.pop();
                 }
             }
-            3 => {
-                unimplemented!("Not implemented ModRM mod = 3");
+            (0xd9, 0) => self.fpu.push(self.fpu.st(i)),
+            (0xdd, 2) => self.fpu.set_st(i, self.fpu.st(0)),
+            (0xdd, 3) => {
+                self.fpu.set_st(i, self.fpu.st(0));
+                self.fpu.pop();
             }
-            _ => unreachable!(),
+            _ => return None,
         }
+        Some(())
     }
-    fn get_memory32(&self, address: u32) -> u32 {
-        u32::from_le_bytes([
-            self.get_memory8(address),
-            self.get_memory8(address + 1),
-            self.get_memory8(address + 2),
-            self.get_memory8(address + 3),
-        ])
+
+    /// FADD (/0), FMUL (/1), FSUB (/4) and FDIV (/6) of the 0xd8/0xdc groups.
+    fn x87_arith(op: u8, dst: f64, src: f64) -> Option<f64> {
+        match op {
+            0 => Some(dst + src),
+            1 => Some(dst * src),
+            4 => Some(dst - src),
+            6 => Some(dst / src),
+            _ => None,
+        }
     }
-    fn get_memory8(&self, address: u32) -> u8 {
-        self.memory[address as usize]
+
+    /// Rounds to nearest even (the default control word) and returns the
+    /// integer indefinite value when the result does not fit.
+    fn x87_to_i32(value: f64) -> i32 {
+        let value = value.round_ties_even();
+        if value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+            value as i32
+        } else {
+            i32::MIN
+        }
     }
-    fn set_memory32(&mut self, address: u32, value: u32) {
-        value
-            .to_le_bytes()
-            .iter()
-            .enumerate()
-            .for_each(|(i, &b)| self.set_memory8(address + i as u32, b));
+    fn x87_to_i64(value: f64) -> i64 {
+        let value = value.round_ties_even();
+        if value >= i64::MIN as f64 && value < i64::MAX as f64 {
+            value as i64
+        } else {
+            i64::MIN
+        }
     }
-    fn set_memory8(&mut self, address: u32, value: u8) {
-        self.memory[address as usize] = value;
+
+    fn mov_r32_imm32(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        self.set_register32(reg, instruction.imm);
     }
 
-    fn get_r32(&self, modrm: &ModRM) -> u32 {
-        self.get_register32(modrm.op)
+    fn mov_rm32_imm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.set_rm32(instruction.modrm(), instruction.imm)
     }
-    fn set_r32(&mut self, modrm: &ModRM, value: u32) {
-        self.set_register32(modrm.op, value);
+    fn mov_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        self.set_rm32(modrm, r32)
     }
-    fn get_r8(&self, modrm: &ModRM) -> u8 {
-        self.get_register8(modrm.op)
+    fn mov_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm32 = self.get_rm32(modrm)?;
+        self.set_r32(modrm, rm32);
+        Ok(())
     }
-    fn set_r8(&mut self, modrm: &ModRM, value: u8) {
-        self.set_register8(modrm.op, value);
+    /// Both operands are read before either is written, so rm may alias r.
+    fn xchg_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        self.set_r8(modrm, rm8);
+        self.set_rm8(modrm, r8)
     }
-
-    fn push_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x50;
-        self.push32(self.get_register32(reg));
-        self.eip += 1;
+    fn xchg_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        let rm32 = self.get_rm32(modrm)?;
+        self.set_r32(modrm, rm32);
+        self.set_rm32(modrm, r32)
     }
-
-    fn push_imm32(&mut self) {
-        let value = self.get_code32(1);
-        self.push32(value);
-        self.eip += 5;
+    /// XCHG EAX, r32 (0x91..0x97); 0x90 would be XCHG EAX, EAX, i.e. NOP
+    fn xchg_eax_r32(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let eax = self.get_register32(EAX);
+        self.set_register32(EAX, self.get_register32(reg));
+        self.set_register32(reg, eax);
     }
-
-    fn push_imm8(&mut self) {
-        let value = self.get_code8(1);
-        self.push32(value as u32);
-        self.eip += 2;
+    fn cwde(&mut self) {
+        let ax = self.get_register32(EAX) as i16;
+        self.set_register32(EAX, ax as u32);
     }
-
-    fn push32(&mut self, value: u32) {
-        let address = self.get_register32(ESP) - 4;
-        self.set_register32(ESP, address);
-        self.set_memory32(address, value);
+    fn cdq(&mut self) {
+        let eax = self.get_register32(EAX) as i32;
+        self.set_register32(EDX, (eax >> 31) as u32);
     }
-
-    fn pop_r32(&mut self) {
-        let reg = self.get_code8(0) - 0x58;
-        let value = self.pop32();
-        self.set_register32(reg, value);
-        self.eip += 1;
+    /// Stores the effective address itself; memory is not accessed.
+    fn lea_r32_m(&mut self, instruction: &Instruction) {
+        let modrm = instruction.modrm();
+        let address = self.calc_memory_address(modrm);
+        self.set_r32(modrm, address);
     }
 
-    fn pop32(&mut self) -> u32 {
-        let address = self.get_register32(ESP);
-        let value = self.get_memory32(address);
-        self.set_register32(ESP, address + 4);
-        value
+    fn add_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (rm32 as u64).wrapping_add(r32 as u64);
+        self.update_eflags_add(rm32, r32, result);
+        self.set_rm32(modrm, rm32.wrapping_add(r32))
     }
-
-    fn call_rel32(&mut self) {
-        let diff = self.get_sign_code32(1);
-        self.push32(self.eip.0 + 5);
-        self.eip += (diff + 5) as u32;
+    fn add_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (rm32 as u64).wrapping_add(imm as u64);
+        self.update_eflags_add(rm32, imm, result);
+        self.set_rm32(modrm, rm32.wrapping_add(imm))
     }
-
-    fn ret(&mut self) {
-        let address = self.pop32();
-        self.eip = Wrapping(address);
+    /// `v1 + v2 + CF`; the sum is taken in 64 bits, so a carry out of
+    /// `v2 + CF` alone still reaches CF.
+    fn adc32(&mut self, v1: u32, v2: u32) -> u32 {
+        let carry = self.get_carry() as u64;
+        let result = (v1 as u64) + (v2 as u64) + carry;
+        self.update_eflags_add(v1, v2, result);
+        result as u32
     }
-
-    fn leave(&mut self) {
-        let ebp = self.get_register32(EBP);
-        self.set_register32(ESP, ebp);
-        let value = self.pop32();
-        self.set_register32(EBP, value);
-        self.eip += 1;
+    fn adc_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let result = self.adc32(self.get_rm32(modrm)?, imm);
+        self.set_rm32(modrm, result)
     }
-
-    fn in_al_dx(&mut self) {
-        let address = (self.get_register32(EDX) & 0xffff) as u16;
-        let value = self.io_in8(address);
-        self.set_register8(AL, value);
-        self.eip += 1;
+    fn adc_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.adc32(self.get_rm32(modrm)?, self.get_r32(modrm));
+        self.set_rm32(modrm, result)
     }
-
-    fn out_dx_al(&mut self) {
-        let address = (self.get_register32(EDX) & 0xffff) as u16;
-        let value = self.get_register8(AL);
-        self.io_out8(address, value);
-        self.eip += 1;
+    fn adc_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.adc32(self.get_r32(modrm), self.get_rm32(modrm)?);
+        self.set_r32(modrm, result);
+        Ok(())
     }
-
-    fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64) {
-        let sign1 = v1.get_bit(31);
-        let sign2 = v2.get_bit(31);
-        let signr = result.get_bit(31);
-
-        self.set_carry(result >> 32 > 0);
-        self.set_zero(result == 0);
-        self.set_sign(signr);
-        self.set_overflow(sign1 != sign2 && sign1 != signr);
+    fn sub_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (rm32 as u64).wrapping_sub(r32 as u64);
+        self.update_eflags_sub(rm32, r32, result);
+        self.set_rm32(modrm, rm32.wrapping_sub(r32))
     }
-
-    fn get_flag(&self, flag: usize) -> bool {
-        self.eflags.get_bit(flag)
+    fn sub_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (r32 as u64).wrapping_sub(rm32 as u64);
+        self.update_eflags_sub(r32, rm32, result);
+        self.set_r32(modrm, r32.wrapping_sub(rm32));
+        Ok(())
     }
-    fn get_carry(&self) -> bool {
-        self.eflags.get_bit(CARRY_FLAG)
+    fn sub_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (rm32 as u64).wrapping_sub(imm as u64);
+        self.update_eflags_sub(rm32, imm, result);
+        self.set_rm32(modrm, rm32.wrapping_sub(imm))
     }
-    fn get_zero(&self) -> bool {
-        self.eflags.get_bit(ZERO_FLAG)
+    /// `v1 - (v2 + CF)`, with the borrow taken in 64 bits like `adc32`
+    fn sbb32(&mut self, v1: u32, v2: u32) -> u32 {
+        let carry = self.get_carry() as u64;
+        let result = (v1 as u64).wrapping_sub(v2 as u64).wrapping_sub(carry);
+        self.update_eflags_sub(v1, v2, result);
+        result as u32
     }
-    fn get_sign(&self) -> bool {
-        self.eflags.get_bit(SIGN_FLAG)
+    fn sbb_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let result = self.sbb32(self.get_rm32(modrm)?, imm);
+        self.set_rm32(modrm, result)
     }
-    fn get_overflow(&self) -> bool {
-        self.eflags.get_bit(OVERFLOW_FLAG)
+    fn sbb_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.sbb32(self.get_rm32(modrm)?, self.get_r32(modrm));
+        self.set_rm32(modrm, result)
     }
-    fn set_carry(&mut self, is_carry: bool) {
-        self.eflags.set_bit(CARRY_FLAG, is_carry);
+    fn sbb_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.sbb32(self.get_r32(modrm), self.get_rm32(modrm)?);
+        self.set_r32(modrm, result);
+        Ok(())
     }
-    fn set_zero(&mut self, is_zero: bool) {
-        self.eflags.set_bit(ZERO_FLAG, is_zero);
+    fn and_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)? & imm;
+        self.update_eflags_logic(result);
+        self.set_rm32(modrm, result)
     }
-    fn set_sign(&mut self, is_sign: bool) {
-        self.eflags.set_bit(SIGN_FLAG, is_sign);
+    fn or_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)? | imm;
+        self.update_eflags_logic(result);
+        self.set_rm32(modrm, result)
     }
-    fn set_overflow(&mut self, is_overflow: bool) {
-        self.eflags.set_bit(OVERFLOW_FLAG, is_overflow);
+    fn xor_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)? ^ imm;
+        self.update_eflags_logic(result);
+        self.set_rm32(modrm, result)
     }
-    fn io_in8(&self, address: u16) -> u8 {
-        match address {
-            0x03f8 => {
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf).expect("stdio is dead");
-                buf.as_bytes()[0]
-            }
-            _ => 0,
+    /// 0x81 /op r/m32, imm32, and 0x83 whose imm8 the decoder sign-extends.
+    fn code_81(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let imm = instruction.imm;
+        match modrm.op {
+            0 => self.add_rm32_imm(modrm, imm),
+            1 => self.or_rm32_imm(modrm, imm),
+            2 => self.adc_rm32_imm(modrm, imm),
+            3 => self.sbb_rm32_imm(modrm, imm),
+            4 => self.and_rm32_imm(modrm, imm),
+            5 => self.sub_rm32_imm(modrm, imm),
+            6 => self.xor_rm32_imm(modrm, imm),
+            7 => self.cmp_rm32_imm(modrm, imm),
+            _ => unreachable!(),
         }
     }
-    fn io_out8(&self, address: u16, value: u8) {
-        match address {
-            0x03f8 => {
-                if value.is_ascii() {
-                    print!("{}", value as char);
-                } else {
-                    print!("{:02x}", value);
-                }
-            }
-            _ => {}
-        }
+    fn inc_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)?.wrapping_add(1);
+        self.update_eflags_inc(result);
+        self.set_rm32(modrm, result)
     }
 
-    fn mov_r8_imm8(&mut self) {
-        let reg = self.get_code8(0) - 0xb0;
-        let value = self.get_code8(1);
-        self.set_register8(reg, value);
-        self.eip += 2;
+    fn inc_r32(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let result = self.get_register32(reg).wrapping_add(1);
+        self.update_eflags_inc(result);
+        self.set_register32(reg, result);
     }
-    fn mov_rm8_r8(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let r8 = self.get_r8(&modrm);
-        self.set_rm8(&modrm, r8);
+    fn dec_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)?.wrapping_sub(1);
+        self.update_eflags_dec(result);
+        self.set_rm32(modrm, result)
     }
-
-    fn mov_r8_rm8(&mut self) {
-        self.eip += 1;
-        let modrm = self.parse_modrm();
-        let rm8 = self.get_rm8(&modrm);
-        self.set_r8(&modrm, rm8);
+    fn dec_r32(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let result = self.get_register32(reg).wrapping_sub(1);
+        self.update_eflags_dec(result);
+        self.set_register32(reg, result);
     }
-
-    fn cmp_al_imm8(&mut self) {
-        let value = self.get_code8(1);
-        let al = self.get_register8(AL);
-        let result = (al as u64).wrapping_sub(value as u64);
-        self.update_eflags_sub(al as u32, value as u32, result);
-        self.eip += 2;
+    /// INC and DEC set the same flags as ADD and SUB, except CF.
+    fn inc_rm8(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let rm8 = self.get_rm8(modrm)?;
+        let result = rm8 as u16 + 1;
+        let carry = self.get_carry();
+        self.update_eflags_add8(rm8, 1, result);
+        self.set_carry(carry);
+        self.set_rm8(modrm, result as u8)
     }
-
-    fn get_register8(&self, index: u8) -> u8 {
-        if index < 4 {
-            (self.get_register32(index) & 0xff) as u8
-        } else {
-            (self.get_register32(index - 4) & 0xff00) as u8
+    fn dec_rm8(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let rm8 = self.get_rm8(modrm)?;
+        let result = (rm8 as u16).wrapping_sub(1);
+        let carry = self.get_carry();
+        self.update_eflags_sub8(rm8, 1, result);
+        self.set_carry(carry);
+        self.set_rm8(modrm, result as u8)
+    }
+    fn code_fe(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        match modrm.op {
+            0 => self.inc_rm8(modrm),
+            1 => self.dec_rm8(modrm),
+            op => Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op,
+            }),
         }
     }
-    fn set_register8(&mut self, index: u8, value: u8) {
-        if index < 4 {
-            let r = self.get_register32(index) & 0xffffff00;
-            self.set_register32(index, r | value as u32);
-        } else {
-            let r = self.get_register32(index - 4) & 0xffff00ff;
-            self.set_register32(index, r | ((value as u32) << 8));
+    fn add_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        let result = rm8 as u16 + r8 as u16;
+        self.update_eflags_add8(rm8, r8, result);
+        self.set_rm8(modrm, result as u8)
+    }
+    fn add_r8_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        let result = r8 as u16 + rm8 as u16;
+        self.update_eflags_add8(r8, rm8, result);
+        self.set_r8(modrm, result as u8);
+        Ok(())
+    }
+    fn sub_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        let result = (rm8 as u16).wrapping_sub(r8 as u16);
+        self.update_eflags_sub8(rm8, r8, result);
+        self.set_rm8(modrm, result as u8)
+    }
+    fn cmp_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        let result = (rm8 as u16).wrapping_sub(r8 as u16);
+        self.update_eflags_sub8(rm8, r8, result);
+        Ok(())
+    }
+    fn cmp_r8_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        let rm8 = self.get_rm8(modrm)?;
+        let result = (r8 as u16).wrapping_sub(rm8 as u16);
+        self.update_eflags_sub8(r8, rm8, result);
+        Ok(())
+    }
+    /// ADD/OR/ADC/SBB/AND/SUB/XOR/CMP r/m8, imm8 (0x80 /0../7)
+    fn code_80(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm8 = self.get_rm8(modrm)?;
+        let imm8 = instruction.imm as u8;
+        let carry = self.get_carry() as u16;
+        let result = match modrm.op {
+            0 | 2 => {
+                let carry = if modrm.op == 2 { carry } else { 0 };
+                let result = rm8 as u16 + imm8 as u16 + carry;
+                self.update_eflags_add8(rm8, imm8, result);
+                result as u8
+            }
+            3 | 5 | 7 => {
+                let carry = if modrm.op == 3 { carry } else { 0 };
+                let result = (rm8 as u16).wrapping_sub(imm8 as u16).wrapping_sub(carry);
+                self.update_eflags_sub8(rm8, imm8, result);
+                if modrm.op == 7 {
+                    return Ok(());
+                }
+                result as u8
+            }
+            op => {
+                let result = match op {
+                    1 => rm8 | imm8,
+                    4 => rm8 & imm8,
+                    _ => rm8 ^ imm8,
+                };
+                self.update_eflags_logic8(result);
+                result
+            }
+        };
+        self.set_rm8(modrm, result)
+    }
+
+    fn code_c1(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.shift_rm32(instruction, instruction.imm)
+    }
+    fn code_d1(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.shift_rm32(instruction, 1)
+    }
+    fn code_d3(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let count = self.get_register8(CL) as u32;
+        self.shift_rm32(instruction, count)
+    }
+    /// /0 ROL, /1 ROR, /2 RCL, /3 RCR, /4 SHL (/6 SAL), /5 SHR, /7 SAR; the
+    /// count is masked to 5 bits and a zero count leaves the flags alone.
+    fn shift_rm32(&mut self, instruction: &Instruction, count: u32) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let count = count & 0x1f;
+        let rm32 = self.get_rm32(modrm)?;
+        if modrm.op < 4 {
+            let (result, carry, overflow) = Self::rotate32(modrm.op, rm32, count, self.get_carry());
+            self.set_rm32(modrm, result)?;
+            if count > 0 {
+                // rotates leave ZF, SF and PF alone
+                self.set_carry(carry);
+                self.set_overflow(overflow);
+            }
+            return Ok(());
         }
+        let (result, carry, overflow) = match modrm.op {
+            5 => {
+                let carry = count > 0 && rm32.get_bit(count as usize - 1);
+                (rm32 >> count, carry, rm32.get_bit(31))
+            }
+            7 => {
+                let carry = count > 0 && rm32.get_bit(count as usize - 1);
+                (((rm32 as i32) >> count) as u32, carry, false)
+            }
+            _ => {
+                let result = rm32 << count;
+                let carry = count > 0 && rm32.get_bit(32 - count as usize);
+                (result, carry, result.get_bit(31) != carry)
+            }
+        };
+        self.set_rm32(modrm, result)?;
+        if count > 0 {
+            self.set_carry(carry);
+            self.set_zero(result == 0);
+            self.set_sign(result.get_bit(31));
+            self.set_parity(Self::even_parity(result as u8));
+            // only defined for 1-bit shifts
+            self.set_overflow(overflow);
+        }
+        Ok(())
+    }
+    /// Rotates `value` by `count` (already masked, non-zero for a change)
+    /// and returns the result, CF and OF. RCL/RCR rotate the 33-bit
+    /// CF:value; OF is only defined for 1-bit rotates.
+    fn rotate32(op: u8, value: u32, count: u32, carry: bool) -> (u32, bool, bool) {
+        let (result, carry) = match op {
+            0 => {
+                let result = value.rotate_left(count);
+                (result, result.get_bit(0))
+            }
+            1 => {
+                let result = value.rotate_right(count);
+                (result, result.get_bit(31))
+            }
+            _ => {
+                let wide = (carry as u64) << 32 | value as u64;
+                let count = count % 33;
+                let rotated = if count == 0 {
+                    wide
+                } else if op == 2 {
+                    wide << count | wide >> (33 - count)
+                } else {
+                    wide >> count | wide << (33 - count)
+                };
+                (rotated as u32, rotated.get_bit(32))
+            }
+        };
+        let overflow = if matches!(op, 0 | 2) {
+            result.get_bit(31) != carry
+        } else {
+            result.get_bit(31) != result.get_bit(30)
+        };
+        (result, carry, overflow)
+    }
+
+    fn code_f7(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        match modrm.op {
+            0 => self.test_rm32_imm32(modrm, instruction.imm),
+            2 => self.not_rm32(modrm),
+            3 => self.neg_rm32(modrm),
+            4 => self.mul_rm32(modrm),
+            5 => self.imul_rm32(modrm),
+            6 => self.div_rm32(modrm),
+            7 => self.idiv_rm32(modrm),
+            op => Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op,
+            }),
+        }
+    }
+    fn not_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let rm32 = self.get_rm32(modrm)?;
+        self.set_rm32(modrm, !rm32)
+    }
+    /// r/m32 = 0 - r/m32; CF is set unless the operand was 0.
+    fn neg_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let rm32 = self.get_rm32(modrm)?;
+        let result = 0u64.wrapping_sub(rm32 as u64);
+        self.update_eflags_sub(0, rm32, result);
+        self.set_rm32(modrm, rm32.wrapping_neg())
+    }
+    /// EDX:EAX = EAX * r/m32; CF and OF are set when EDX is not zero.
+    fn mul_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let result = self.get_register32(EAX) as u64 * self.get_rm32(modrm)? as u64;
+        self.set_register32(EAX, result as u32);
+        self.set_register32(EDX, (result >> 32) as u32);
+        let upper = result >> 32 != 0;
+        self.set_carry(upper);
+        self.set_overflow(upper);
+        Ok(())
+    }
+    /// Signed EDX:EAX = EAX * r/m32; CF and OF are set when EDX is not the
+    /// sign extension of EAX.
+    fn imul_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let result = self.imul32(self.get_register32(EAX), self.get_rm32(modrm)?);
+        self.set_register32(EAX, result as u32);
+        self.set_register32(EDX, (result >> 32) as u32);
+        Ok(())
+    }
+    /// IMUL r32, r/m32 (0x0f 0xaf)
+    fn imul_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.imul32(self.get_r32(modrm), self.get_rm32(modrm)?);
+        self.set_r32(modrm, result as u32);
+        Ok(())
+    }
+    /// IMUL r32, r/m32, imm32 (0x69) and imm8 (0x6b)
+    fn imul_r32_rm32_imm(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.imul32(self.get_rm32(modrm)?, instruction.imm);
+        self.set_r32(modrm, result as u32);
+        Ok(())
+    }
+    /// Signed 64-bit product; CF and OF tell whether it is wider than 32 bits.
+    fn imul32(&mut self, v1: u32, v2: u32) -> i64 {
+        let result = v1 as i32 as i64 * v2 as i32 as i64;
+        let truncated = result != result as i32 as i64;
+        self.set_carry(truncated);
+        self.set_overflow(truncated);
+        result
+    }
+
+    /// EAX = EDX:EAX / r/m32, EDX = remainder. A zero divisor or a quotient
+    /// that does not fit in 32 bits is a divide error (#DE).
+    fn div_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let divisor = self.get_rm32(modrm)? as u64;
+        if divisor == 0 {
+            return Err(EmulatorError::DivideByZero);
+        }
+        let dividend = (self.get_register32(EDX) as u64) << 32 | self.get_register32(EAX) as u64;
+        let quotient = dividend / divisor;
+        if quotient > u32::MAX as u64 {
+            return Err(EmulatorError::DivideOverflow);
+        }
+        self.set_register32(EAX, quotient as u32);
+        self.set_register32(EDX, (dividend % divisor) as u32);
+        Ok(())
+    }
+    /// Signed `div_rm32()`; the quotient truncates toward zero and the
+    /// remainder takes the sign of the dividend.
+    fn idiv_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let divisor = self.get_rm32(modrm)? as i32 as i64;
+        if divisor == 0 {
+            return Err(EmulatorError::DivideByZero);
+        }
+        let dividend =
+            ((self.get_register32(EDX) as u64) << 32 | self.get_register32(EAX) as u64) as i64;
+        let quotient = dividend
+            .checked_div(divisor)
+            .filter(|&quotient| quotient == quotient as i32 as i64)
+            .ok_or(EmulatorError::DivideOverflow)?;
+        self.set_register32(EAX, quotient as u32);
+        self.set_register32(EDX, (dividend % divisor) as u32);
+        Ok(())
+    }
+
+    fn code_ff(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        match modrm.op {
+            0 => self.inc_rm32(modrm),
+            1 => self.dec_rm32(modrm),
+            2 => self.call_rm32(modrm),
+            4 => self.jmp_rm32(modrm),
+            op => Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op,
+            }),
+        }
+    }
+    fn cmp_r32_rm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r32 = self.get_r32(modrm);
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (r32 as u64).wrapping_sub(rm32 as u64);
+        self.update_eflags_sub(r32, rm32, result);
+        Ok(())
+    }
+
+    fn cmp_eax_imm32(&mut self, instruction: &Instruction) {
+        let value = instruction.imm;
+        let eax = self.get_register32(EAX);
+        let result = (eax as u64).wrapping_sub(value as u64);
+        self.update_eflags_sub(eax, value, result);
+    }
+
+    fn test_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.get_rm8(modrm)? & self.get_r8(modrm);
+        self.update_eflags_logic8(result);
+        Ok(())
+    }
+    fn test_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.get_rm32(modrm)? & self.get_r32(modrm);
+        self.update_eflags_logic(result);
+        Ok(())
+    }
+    fn test_rm32_imm32(&mut self, modrm: &ModRM, imm32: u32) -> Result<(), EmulatorError> {
+        let result = self.get_rm32(modrm)? & imm32;
+        self.update_eflags_logic(result);
+        Ok(())
+    }
+    fn test_al_imm8(&mut self, instruction: &Instruction) {
+        let result = self.get_register8(AL) & instruction.imm as u8;
+        self.update_eflags_logic8(result);
+    }
+    fn test_eax_imm32(&mut self, instruction: &Instruction) {
+        let result = self.get_register32(EAX) & instruction.imm;
+        self.update_eflags_logic(result);
+    }
+
+    fn cmp_rm32_imm(&mut self, modrm: &ModRM, imm: u32) -> Result<(), EmulatorError> {
+        let rm32 = self.get_rm32(modrm)?;
+        let result = (rm32 as u64).wrapping_sub(imm as u64);
+        self.update_eflags_sub(rm32, imm, result);
+        Ok(())
+    }
+
+    fn short_jump(&mut self, instruction: &Instruction) {
+        self.eip += instruction.imm;
+    }
+
+    fn near_jump(&mut self, instruction: &Instruction) {
+        self.eip += instruction.imm;
+    }
+
+    define_logic!(and, &);
+    define_logic!(or, |);
+    define_logic!(xor, ^);
+
+    /// Condition `cc` (the low 4 bits of a Jcc, SETcc or CMOVcc opcode):
+    /// even codes test a predicate, odd codes its negation.
+    fn condition(&self, cc: u8) -> bool {
+        let predicate = match cc >> 1 {
+            0 => self.get_overflow(),
+            1 => self.get_carry(),
+            2 => self.get_zero(),
+            3 => self.get_cond_be(),
+            4 => self.get_sign(),
+            5 => self.get_parity(),
+            6 => self.get_cond_l(),
+            7 => self.get_cond_le(),
+            _ => unreachable!(),
+        };
+        predicate != cc.get_bit(0)
+    }
+
+    /// SETcc r/m8 (0x0f 0x90..0x9f)
+    fn setcc(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.condition((instruction.opcode & 0xf) as u8) as u8;
+        self.set_rm8(instruction.modrm(), value)
+    }
+
+    /// CMOVcc r32, r/m32 (0x0f 0x40..0x4f). The source is read even when
+    /// the condition is false, as on hardware.
+    fn cmovcc(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm32 = self.get_rm32(modrm)?;
+        if self.condition((instruction.opcode & 0xf) as u8) {
+            self.set_r32(modrm, rm32);
+        }
+        Ok(())
+    }
+
+    /// Jcc rel8 (0x70..0x7f) and Jcc rel32 (0x0f 0x80..0x8f)
+    fn jcc(&mut self, instruction: &Instruction) {
+        if self.condition((instruction.opcode & 0xf) as u8) {
+            self.eip += instruction.imm;
+        }
+    }
+
+    /// LOOPNE, LOOPE, LOOP and JECXZ (0xe0..0xe3), counting in CX with
+    /// 16-bit addressing. Decrementing ECX leaves the flags alone.
+    fn loop_rel8(&mut self, instruction: &Instruction) {
+        let ecx = self.get_index_register(instruction, ECX);
+        let taken = if instruction.opcode == 0xe3 {
+            ecx == 0
+        } else {
+            let ecx = ecx.wrapping_sub(1);
+            self.set_index_register(instruction, ECX, ecx);
+            ecx != 0
+                && match instruction.opcode {
+                    0xe0 => !self.get_zero(),
+                    0xe1 => self.get_zero(),
+                    _ => true,
+                }
+        };
+        if taken {
+            self.eip += instruction.imm;
+        }
+    }
+
+    fn get_cond_be(&self) -> bool {
+        self.get_carry() || self.get_zero()
+    }
+    fn get_cond_l(&self) -> bool {
+        self.get_sign() != self.get_overflow()
+    }
+    fn get_cond_le(&self) -> bool {
+        self.get_zero() || self.get_sign() != self.get_overflow()
+    }
+
+    /// Names the address range `range` (e.g. "code", "stack") so that dumps
+    /// label addresses inside it. The first defined region wins on overlap.
+    pub fn define_region(&mut self, name: &str, range: Range<u32>) {
+        self.regions.push((name.to_string(), range));
+    }
+
+    /// Name of the region containing `address`, if any.
+    pub fn region_of(&self, address: u32) -> Option<&str> {
+        self.regions
+            .iter()
+            .find(|(_, range)| range.contains(&address))
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn region_label(&self, address: u32) -> String {
+        match self.region_of(address) {
+            Some(name) => format!(" ({})", name),
+            None => String::new(),
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        let mut s = String::new();
+        s.push_str(&format!(
+            "EIP = {:08x}{}\n",
+            self.eip,
+            self.region_label(self.eip.0)
+        ));
+        s.push_str(&self.dump_registers());
+        s.push_str(&self.dump_eflags());
+        s
+    }
+    fn dump_registers(&self) -> String {
+        let mut s = String::new();
+        for i in 0..REGISTER_COUNT {
+            let label = match i as u8 {
+                ESP | EBP => self.region_label(self.registers[i]),
+                _ => String::new(),
+            };
+            s.push_str(&format!(
+                "{} = {:08x}{}\n",
+                Self::register_name(i),
+                self.registers[i],
+                label
+            ));
+        }
+        s
+    }
+
+    /// Hex dump of `len` bytes from `start`, 16 bytes per line with an
+    /// offset column, an ASCII gutter and the region of the line's first byte.
+    /// Stops at the end of memory instead of panicking.
+    pub fn dump_memory(&self, start: u32, len: usize) -> String {
+        let start = (start as usize).min(self.memory.len());
+        let end = start.saturating_add(len).min(self.memory.len());
+        let mut s = String::new();
+        for (i, line) in self.memory[start..end].chunks(16).enumerate() {
+            let address = (start + i * 16) as u32;
+            let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            s.push_str(&format!(
+                "{:08x}  {:<47}  |{}|{}\n",
+                address,
+                hex.join(" "),
+                ascii,
+                self.region_label(address)
+            ));
+        }
+        s
+    }
+    fn dump_eflags(&self) -> String {
+        let set: Vec<&str> = FLAG_NAMES
+            .iter()
+            .copied()
+            .filter(|&name| self.flag(name).unwrap())
+            .collect();
+        format!("EFLAGS = {:08x} [{}]\n", self.eflags, set.join(" "))
+    }
+    fn register_name(index: usize) -> &'static str {
+        match index {
+            0 => "EAX",
+            1 => "ECX",
+            2 => "EDX",
+            3 => "EBX",
+            4 => "ESP",
+            5 => "EBP",
+            6 => "ESI",
+            7 => "EDI",
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_rm32(&self, modrm: &ModRM) -> Result<u32, EmulatorError> {
+        if modrm.is_reg() {
+            Ok(self.get_register32(modrm.rm))
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_get_memory32(address)
+        }
+    }
+
+    fn set_rm32(&mut self, modrm: &ModRM, value: u32) -> Result<(), EmulatorError> {
+        if modrm.is_reg() {
+            self.set_register32(modrm.rm, value);
+            Ok(())
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_set_memory32(address, value)
+        }
+    }
+
+    fn get_rm16(&self, modrm: &ModRM) -> Result<u16, EmulatorError> {
+        if modrm.is_reg() {
+            Ok(self.get_register32(modrm.rm) as u16)
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_get_memory16(address)
+        }
+    }
+
+    fn get_rm8(&self, modrm: &ModRM) -> Result<u8, EmulatorError> {
+        if modrm.is_reg() {
+            Ok(self.get_register8(modrm.rm))
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_get_memory8(address)
+        }
+    }
+
+    fn set_rm8(&mut self, modrm: &ModRM, value: u8) -> Result<(), EmulatorError> {
+        if modrm.is_reg() {
+            self.set_register8(modrm.rm, value);
+            Ok(())
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_set_memory8(address, value)
+        }
+    }
+    fn set_register32(&mut self, reg: u8, value: u32) {
+        self.registers[reg as usize] = value;
+    }
+    fn get_register32(&self, reg: u8) -> u32 {
+        self.registers[reg as usize]
+    }
+    /// Address a ModRM memory operand refers to with the current registers,
+    /// or `None` for a register operand.
+    pub fn effective_address(&self, modrm: &ModRM) -> Option<u32> {
+        if modrm.is_reg() {
+            None
+        } else {
+            Some(self.calc_memory_address(modrm))
+        }
+    }
+    /// Callers check `is_reg()` first; a register operand has no address.
+    fn calc_memory_address(&self, modrm: &ModRM) -> u32 {
+        if modrm.address16 {
+            return self.calc_memory_address16(modrm);
+        }
+        let base = if modrm.has_sib() {
+            let index = match modrm.index() {
+                4 => 0,
+                index => self.address_register(index) << modrm.scale(),
+            };
+            let base = if modrm.md == 0 && modrm.base() == 5 {
+                0
+            } else {
+                self.address_register(modrm.base())
+            };
+            base.wrapping_add(index)
+        } else if modrm.md == 0 && modrm.rm == 5 {
+            0
+        } else {
+            self.address_register(modrm.rm)
+        };
+        base.wrapping_add(modrm.disp as u32)
+    }
+
+    /// 16-bit addressing: BX or BP plus SI or DI, one of them alone, or a
+    /// bare disp16 (rm = 6 under mod = 0). The offset wraps within 64 KiB.
+    fn calc_memory_address16(&self, modrm: &ModRM) -> u32 {
+        let register = |reg| self.address_register(reg) as u16;
+        let base = match modrm.rm {
+            0 => register(EBX).wrapping_add(register(ESI)),
+            1 => register(EBX).wrapping_add(register(EDI)),
+            2 => register(EBP).wrapping_add(register(ESI)),
+            3 => register(EBP).wrapping_add(register(EDI)),
+            4 => register(ESI),
+            5 => register(EDI),
+            6 if modrm.md == 0 => 0,
+            6 => register(EBP),
+            _ => register(EBX),
+        };
+        base.wrapping_add(modrm.disp as u16) as u32
+    }
+
+    /// Reads a register used as a base or index, warning about poison.
+    fn address_register(&self, reg: u8) -> u32 {
+        let value = self.get_register32(reg);
+        if self.poison_init && value == POISON {
+            warn!(
+                "{} holds the poison value and is used as an address",
+                Self::register_name(reg as usize)
+            );
+        }
+        value
+    }
+    pub fn try_get_memory8(&self, address: u32) -> Result<u8, EmulatorError> {
+        let index = self.physical_address(address);
+        if let Some(value) = self.mmio_read(index) {
+            return Ok(value);
+        }
+        self.memory
+            .get(index)
+            .copied()
+            .ok_or(EmulatorError::OutOfBounds { address })
+    }
+    pub fn try_set_memory8(&mut self, address: u32, value: u8) -> Result<(), EmulatorError> {
+        let index = self.physical_address(address);
+        if self.mmio_write(index, value) {
+            return Ok(());
+        }
+        let byte = self
+            .memory
+            .get_mut(index)
+            .ok_or(EmulatorError::OutOfBounds { address })?;
+        *byte = value;
+        Ok(())
+    }
+    pub fn try_get_memory16(&self, address: u32) -> Result<u16, EmulatorError> {
+        Ok(u16::from_le_bytes([
+            self.try_get_memory8(address)?,
+            self.try_get_memory8(address.wrapping_add(1))?,
+        ]))
+    }
+    pub fn try_get_memory32(&self, address: u32) -> Result<u32, EmulatorError> {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.try_get_memory8(address.wrapping_add(i as u32))?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+    /// Writes nothing unless both bytes are in bounds.
+    pub fn try_set_memory16(&mut self, address: u32, value: u16) -> Result<(), EmulatorError> {
+        self.check_address(address)?;
+        self.check_address(address.wrapping_add(1))?;
+        let [low, high] = value.to_le_bytes();
+        self.try_set_memory8(address, low)?;
+        self.try_set_memory8(address.wrapping_add(1), high)
+    }
+    /// Writes nothing unless all four bytes are in bounds.
+    pub fn try_set_memory32(&mut self, address: u32, value: u32) -> Result<(), EmulatorError> {
+        for i in 0..4 {
+            self.check_address(address.wrapping_add(i))?;
+        }
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            self.try_set_memory8(address.wrapping_add(i as u32), byte)?;
+        }
+        Ok(())
+    }
+
+    /// Fails like an access to `address` would, without touching devices.
+    fn check_address(&self, address: u32) -> Result<(), EmulatorError> {
+        let index = self.physical_address(address);
+        if index < self.memory.len() || self.is_mapped(index) {
+            Ok(())
+        } else {
+            Err(EmulatorError::OutOfBounds { address })
+        }
+    }
+
+    pub fn try_get_memory64(&self, address: u32) -> Result<u64, EmulatorError> {
+        Ok(self.try_get_memory32(address)? as u64
+            | (self.try_get_memory32(address.wrapping_add(4))? as u64) << 32)
+    }
+    /// Writes nothing unless all eight bytes are in bounds.
+    pub fn try_set_memory64(&mut self, address: u32, value: u64) -> Result<(), EmulatorError> {
+        self.check_address(address.wrapping_add(4))?;
+        self.check_address(address.wrapping_add(7))?;
+        self.try_set_memory32(address, value as u32)?;
+        self.try_set_memory32(address.wrapping_add(4), (value >> 32) as u32)
+    }
+
+    /// Panics when `address` is out of bounds; see `try_get_memory8()`.
+    pub fn get_memory8(&self, address: u32) -> u8 {
+        self.try_get_memory8(address)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_get_memory16()`.
+    pub fn get_memory16(&self, address: u32) -> u16 {
+        self.try_get_memory16(address)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_get_memory32()`.
+    pub fn get_memory32(&self, address: u32) -> u32 {
+        self.try_get_memory32(address)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_get_memory64()`.
+    pub fn get_memory64(&self, address: u32) -> u64 {
+        self.try_get_memory64(address)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_set_memory8()`.
+    pub fn set_memory8(&mut self, address: u32, value: u8) {
+        self.try_set_memory8(address, value)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_set_memory16()`.
+    pub fn set_memory16(&mut self, address: u32, value: u16) {
+        self.try_set_memory16(address, value)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_set_memory32()`.
+    pub fn set_memory32(&mut self, address: u32, value: u32) {
+        self.try_set_memory32(address, value)
+            .expect("memory access out of bounds")
+    }
+    /// Panics when `address` is out of bounds; see `try_set_memory64()`.
+    pub fn set_memory64(&mut self, address: u32, value: u64) {
+        self.try_set_memory64(address, value)
+            .expect("memory access out of bounds")
+    }
+    /// Maps a linear address to an index into `memory`.
+    fn physical_address(&self, mut address: u32) -> usize {
+        if !self.a20_enabled {
+            address.set_bit(20, false);
+        }
+        match self.address_wrap {
+            Some(size) => address as usize % size,
+            None => address as usize,
+        }
+    }
+
+    fn get_r32(&self, modrm: &ModRM) -> u32 {
+        self.get_register32(modrm.op)
+    }
+    fn set_r32(&mut self, modrm: &ModRM, value: u32) {
+        self.set_register32(modrm.op, value);
+    }
+    fn get_r8(&self, modrm: &ModRM) -> u8 {
+        self.get_register8(modrm.op)
+    }
+    fn set_r8(&mut self, modrm: &ModRM, value: u8) {
+        self.set_register8(modrm.op, value);
+    }
+
+    fn push_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let reg = instruction.opcode_register();
+        self.push32(self.get_register32(reg))
+    }
+
+    fn push_imm32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push32(instruction.imm)
+    }
+
+    fn push_imm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push32(instruction.imm)
+    }
+
+    fn push32(&mut self, value: u32) -> Result<(), EmulatorError> {
+        let address = self.get_register32(ESP).wrapping_sub(4);
+        self.try_set_memory32(address, value)?;
+        self.set_register32(ESP, address);
+        Ok(())
+    }
+
+    fn pop_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let reg = instruction.opcode_register();
+        let value = self.pop32()?;
+        self.set_register32(reg, value);
+        Ok(())
+    }
+
+    fn pop32(&mut self) -> Result<u32, EmulatorError> {
+        let address = self.get_register32(ESP);
+        let value = self.try_get_memory32(address)?;
+        self.set_register32(ESP, address.wrapping_add(4));
+        Ok(value)
+    }
+
+    fn pushfd(&mut self) -> Result<(), EmulatorError> {
+        self.push32(self.eflags | EFLAGS_RESERVED)
+    }
+
+    fn popfd(&mut self) -> Result<(), EmulatorError> {
+        let value = self.pop32()?;
+        self.eflags = self.eflags & !POPFD_MASK | value & POPFD_MASK;
+        Ok(())
+    }
+
+    fn call_rel32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push32(self.eip.0)?;
+        self.eip += instruction.imm;
+        Ok(())
+    }
+
+    /// CALL ptr16:32 (0x9a): pushes CS (zero-extended) and the return
+    /// address, then loads CS:EIP. Segmentation is flat, so the CS base is
+    /// not changed.
+    fn call_far(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push32(self.segment(Segment::Cs) as u32)?;
+        self.push32(self.eip.0)?;
+        self.jmp_far(instruction);
+        Ok(())
+    }
+    /// JMP ptr16:32 (0xea), e.g. `jmp 0x08:start` after entering protected mode
+    fn jmp_far(&mut self, instruction: &Instruction) {
+        self.set_segment(Segment::Cs, instruction.selector);
+        self.eip = Wrapping(instruction.imm);
+    }
+
+    fn call_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        let target = self.get_rm32(modrm)?;
+        self.push32(self.eip.0)?;
+        self.eip = Wrapping(target);
+        Ok(())
+    }
+
+    fn jmp_rm32(&mut self, modrm: &ModRM) -> Result<(), EmulatorError> {
+        self.eip = Wrapping(self.get_rm32(modrm)?);
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), EmulatorError> {
+        let address = self.pop32()?;
+        self.eip = Wrapping(address);
+        Ok(())
+    }
+    /// Also releases `imm16` bytes of arguments (stdcall).
+    fn ret_imm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.ret()?;
+        let esp = self.get_register32(ESP).wrapping_add(instruction.imm);
+        self.set_register32(ESP, esp);
+        Ok(())
+    }
+
+    /// ENTER imm16, imm8: pushes EBP, copies `level - 1` frame pointers of
+    /// the enclosing frames plus the new one for nested procedures, points
+    /// EBP at the new frame and reserves `imm16` bytes of locals.
+    fn enter(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let size = instruction.imm & 0xffff;
+        let level = (instruction.imm >> 16) & 0x1f;
+        self.push32(self.get_register32(EBP))?;
+        let frame = self.get_register32(ESP);
+        if level > 0 {
+            let mut ebp = self.get_register32(EBP);
+            for _ in 1..level {
+                ebp = ebp.wrapping_sub(4);
+                self.push32(self.try_get_memory32(ebp)?)?;
+            }
+            self.push32(frame)?;
+        }
+        self.set_register32(EBP, frame);
+        let esp = self.get_register32(ESP).wrapping_sub(size);
+        self.set_register32(ESP, esp);
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<(), EmulatorError> {
+        let ebp = self.get_register32(EBP);
+        self.set_register32(ESP, ebp);
+        let value = self.pop32()?;
+        self.set_register32(EBP, value);
+        Ok(())
+    }
+
+    /// WAIT/FWAIT. The FPU model never raises exceptions, so there is nothing
+    /// to wait for; FPU exception checking is out of scope.
+    fn fwait(&mut self) {}
+
+    fn hlt(&mut self) {
+        self.halted = true;
+    }
+
+    fn cld(&mut self) {
+        self.set_direction(false);
+    }
+
+    fn std(&mut self) {
+        self.set_direction(true);
+    }
+
+    /// Source operand of a string instruction: DS:ESI unless a segment
+    /// override prefix selects another segment.
+    fn string_source_address(&self, instruction: &Instruction) -> u32 {
+        let segment = instruction.prefixes.segment.unwrap_or(Segment::Ds);
+        self.segment_base(segment)
+            .wrapping_add(self.get_index_register(instruction, ESI))
+    }
+
+    /// Destination operand of a string instruction: always ES:EDI.
+    fn string_destination_address(&self, instruction: &Instruction) -> u32 {
+        self.segment_base(Segment::Es)
+            .wrapping_add(self.get_index_register(instruction, EDI))
+    }
+
+    /// ESI, EDI or ECX as a string index or count; only the low word (SI,
+    /// DI or CX) with 16-bit addressing.
+    fn get_index_register(&self, instruction: &Instruction, reg: u8) -> u32 {
+        let value = self.get_register32(reg);
+        if instruction.prefixes.address_size {
+            value & 0xffff
+        } else {
+            value
+        }
+    }
+    fn set_index_register(&mut self, instruction: &Instruction, reg: u8, value: u32) {
+        if instruction.prefixes.address_size {
+            self.set_register16(reg, value as u16);
+        } else {
+            self.set_register32(reg, value);
+        }
+    }
+
+    /// Moves a string index register by `size` bytes along the direction flag.
+    fn advance_string_index(&mut self, instruction: &Instruction, reg: u8, size: u32) {
+        let value = self.get_index_register(instruction, reg);
+        if self.get_direction() {
+            self.set_index_register(instruction, reg, value.wrapping_sub(size));
+        } else {
+            self.set_index_register(instruction, reg, value.wrapping_add(size));
+        }
+    }
+
+    /// Runs a string instruction once, or, with a REP/REPE/REPNE prefix,
+    /// ECX times. CMPS and SCAS also stop early on ZF = 0 (REPE) or
+    /// ZF = 1 (REPNE). The whole repetition is a single step.
+    fn repeat(
+        &mut self,
+        instruction: &Instruction,
+        iteration: fn(&mut Self, &Instruction) -> Result<(), EmulatorError>,
+    ) -> Result<(), EmulatorError> {
+        let Some(repeat) = instruction.prefixes.repeat else {
+            return iteration(self, instruction);
+        };
+        let compares = matches!(instruction.opcode, 0xa6 | 0xa7 | 0xae | 0xaf);
+        while self.get_index_register(instruction, ECX) != 0 {
+            iteration(self, instruction)?;
+            let count = self.get_index_register(instruction, ECX) - 1;
+            self.set_index_register(instruction, ECX, count);
+            if compares && self.get_zero() != (repeat == Repeat::Rep) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn movs_m8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory8(self.string_source_address(instruction))?;
+        self.try_set_memory8(self.string_destination_address(instruction), value)?;
+        self.advance_string_index(instruction, ESI, 1);
+        self.advance_string_index(instruction, EDI, 1);
+        Ok(())
+    }
+
+    fn movs_m32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory32(self.string_source_address(instruction))?;
+        self.try_set_memory32(self.string_destination_address(instruction), value)?;
+        self.advance_string_index(instruction, ESI, 4);
+        self.advance_string_index(instruction, EDI, 4);
+        Ok(())
+    }
+
+    fn stos_m8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_destination_address(instruction);
+        self.try_set_memory8(address, self.get_register8(AL))?;
+        self.advance_string_index(instruction, EDI, 1);
+        Ok(())
+    }
+
+    fn stos_m32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_destination_address(instruction);
+        self.try_set_memory32(address, self.get_register32(EAX))?;
+        self.advance_string_index(instruction, EDI, 4);
+        Ok(())
+    }
+
+    fn lods_m8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_source_address(instruction);
+        let value = self.try_get_memory8(address)?;
+        self.set_register8(AL, value);
+        self.advance_string_index(instruction, ESI, 1);
+        Ok(())
+    }
+
+    fn lods_m32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_source_address(instruction);
+        let value = self.try_get_memory32(address)?;
+        self.set_register32(EAX, value);
+        self.advance_string_index(instruction, ESI, 4);
+        Ok(())
+    }
+
+    fn movzx_r32_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm8(modrm)? as u32;
+        self.set_r32(modrm, value);
+        Ok(())
+    }
+
+    fn movzx_r32_rm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm16(modrm)? as u32;
+        self.set_r32(modrm, value);
+        Ok(())
+    }
+
+    fn movsx_r32_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm8(modrm)? as i8 as u32;
+        self.set_r32(modrm, value);
+        Ok(())
+    }
+
+    fn movsx_r32_rm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm16(modrm)? as i16 as u32;
+        self.set_r32(modrm, value);
+        Ok(())
+    }
+
+    fn bswap(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let value = self.get_register32(reg);
+        self.set_register32(reg, value.swap_bytes());
+    }
+
+    fn popcnt(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm32 = self.get_rm32(modrm)?;
+        self.set_r32(modrm, rm32.count_ones());
+        self.update_eflags_popcnt(rm32 == 0);
+        Ok(())
+    }
+    /// POPCNT clears every status flag but ZF, which is set for a zero source.
+    fn update_eflags_popcnt(&mut self, is_zero: bool) {
+        self.eflags &= !(1 << CARRY_FLAG
+            | 1 << PARITY_FLAG
+            | 1 << AUXILIARY_FLAG
+            | 1 << SIGN_FLAG
+            | 1 << OVERFLOW_FLAG);
+        self.set_zero(is_zero);
+    }
+
+    /// BT, BTS, BTR and BTC r/m32, r32 (0x0f 0xa3, 0xab, 0xb3, 0xbb). With
+    /// a memory operand the register is a signed bit offset from the
+    /// operand's address, so it may select a bit outside that dword.
+    fn bit_rm32_r32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let op = 4 + (instruction.opcode >> 3 & 0b11) as u8;
+        let offset = self.get_r32(modrm);
+        if modrm.is_reg() {
+            let value = self.get_register32(modrm.rm);
+            if let Some(result) = self.bit_operation(op, value, offset % 32) {
+                self.set_register32(modrm.rm, result);
+            }
+        } else {
+            let address = self
+                .calc_memory_address(modrm)
+                .wrapping_add(((offset as i32 >> 5) * 4) as u32);
+            let value = self.try_get_memory32(address)?;
+            if let Some(result) = self.bit_operation(op, value, offset % 32) {
+                self.try_set_memory32(address, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 0x0f 0xba group: /4 BT, /5 BTS, /6 BTR, /7 BTC r/m32, imm8. The bit
+    /// index is taken modulo 32 for memory operands too.
+    fn code_0fba(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        if modrm.op < 4 {
+            return Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op: modrm.op,
+            });
+        }
+        let rm32 = self.get_rm32(modrm)?;
+        if let Some(result) = self.bit_operation(modrm.op, rm32, instruction.imm % 32) {
+            self.set_rm32(modrm, result)?;
+        }
+        Ok(())
+    }
+
+    /// Copies bit `bit` of `value` into CF and returns the value BTS (/5),
+    /// BTR (/6) or BTC (/7) writes back; `None` for BT (/4).
+    fn bit_operation(&mut self, op: u8, value: u32, bit: u32) -> Option<u32> {
+        self.set_carry(value.get_bit(bit as usize));
+        match op {
+            5 => Some(value | 1 << bit),
+            6 => Some(value & !(1 << bit)),
+            7 => Some(value ^ 1 << bit),
+            _ => None,
+        }
+    }
+
+    /// BSF (0x0f 0xbc) and BSR (0x0f 0xbd) r32, r/m32. A zero source sets ZF
+    /// and leaves the destination unchanged, which hardware does too although
+    /// the manuals call it undefined.
+    fn bit_scan(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm32 = self.get_rm32(modrm)?;
+        self.set_zero(rm32 == 0);
+        if rm32 != 0 {
+            let index = if instruction.opcode == 0x0fbc {
+                rm32.trailing_zeros()
+            } else {
+                31 - rm32.leading_zeros()
+            };
+            self.set_r32(modrm, index);
+        }
+        Ok(())
+    }
+
+    /// port number in DX
+    fn dx_port(&self) -> u16 {
+        (self.get_register32(EDX) & 0xffff) as u16
+    }
+
+    fn in_al_dx(&mut self) {
+        let value = self.io_in8(self.dx_port());
+        self.set_register8(AL, value);
+    }
+    fn in_eax_dx(&mut self) {
+        let value = self.io_in32(self.dx_port());
+        self.set_register32(EAX, value);
+    }
+    fn in_al_imm8(&mut self, instruction: &Instruction) {
+        let value = self.io_in8(instruction.imm as u16);
+        self.set_register8(AL, value);
+    }
+    fn in_eax_imm8(&mut self, instruction: &Instruction) {
+        let value = self.io_in32(instruction.imm as u16);
+        self.set_register32(EAX, value);
+    }
+
+    fn out_dx_al(&mut self) {
+        let value = self.get_register8(AL);
+        self.io_out8(self.dx_port(), value);
+    }
+    fn out_dx_eax(&mut self) {
+        let value = self.get_register32(EAX);
+        self.io_out32(self.dx_port(), value);
+    }
+    fn out_imm8_al(&mut self, instruction: &Instruction) {
+        let value = self.get_register8(AL);
+        self.io_out8(instruction.imm as u16, value);
+    }
+    fn out_imm8_eax(&mut self, instruction: &Instruction) {
+        let value = self.get_register32(EAX);
+        self.io_out32(instruction.imm as u16, value);
+    }
+
+    /// Sets CF, PF, ZF, SF and OF for `result = v1 + v2` or `v1 - v2` at
+    /// `width`. `result` must be computed wider than `width`, so a carry or
+    /// borrow shows up in the bits above it.
+    fn update_eflags(&mut self, v1: u32, v2: u32, result: u64, width: OperandWidth, op: FlagOp) {
+        let bits = width.bits();
+        let sign1 = v1.get_bit(bits - 1);
+        let sign2 = v2.get_bit(bits - 1);
+        let signr = result.get_bit(bits - 1);
+
+        self.set_carry(result >> bits > 0);
+        self.set_zero(result.get_bits(0..bits) == 0);
+        self.set_parity(Self::even_parity(result as u8));
+        self.set_sign(signr);
+        self.set_overflow(match op {
+            FlagOp::Add => sign1 == sign2 && sign1 != signr,
+            FlagOp::Sub => sign1 != sign2 && sign1 != signr,
+        });
+    }
+
+    /// Sets CF, PF, ZF, SF and OF for `result = v1 - v2`, where `v1` is the
+    /// minuend and `result` is computed in 64 bits so the borrow is visible.
+    fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64) {
+        self.update_eflags(v1, v2, result, OperandWidth::Dword, FlagOp::Sub);
+    }
+
+    /// Sets CF, PF, ZF, SF and OF for `result = v1 + v2`, where `result` is
+    /// computed in 64 bits so the carry out of bit 31 is visible.
+    fn update_eflags_add(&mut self, v1: u32, v2: u32, result: u64) {
+        self.update_eflags(v1, v2, result, OperandWidth::Dword, FlagOp::Add);
+    }
+
+    /// Flags after INC: like ADD with 1, but CF is left alone.
+    fn update_eflags_inc(&mut self, result: u32) {
+        self.set_zero(result == 0);
+        self.set_parity(Self::even_parity(result as u8));
+        self.set_sign(result.get_bit(31));
+        self.set_overflow(result == 0x80000000);
+    }
+
+    /// Flags after DEC: like SUB with 1, but CF is left alone.
+    fn update_eflags_dec(&mut self, result: u32) {
+        self.set_zero(result == 0);
+        self.set_parity(Self::even_parity(result as u8));
+        self.set_sign(result.get_bit(31));
+        self.set_overflow(result == 0x7fffffff);
+    }
+
+    /// Flags after AND, OR, XOR and TEST: CF and OF cleared,
+    /// PF, ZF and SF from `result`.
+    fn update_eflags_logic(&mut self, result: u32) {
+        self.set_carry(false);
+        self.set_overflow(false);
+        self.set_zero(result == 0);
+        self.set_parity(Self::even_parity(result as u8));
+        self.set_sign(result.get_bit(31));
+    }
+
+    /// 8-bit counterpart of `update_eflags_logic()`.
+    fn update_eflags_logic8(&mut self, result: u8) {
+        self.set_carry(false);
+        self.set_overflow(false);
+        self.set_zero(result == 0);
+        self.set_parity(Self::even_parity(result));
+        self.set_sign(result.get_bit(7));
+    }
+
+    /// 8-bit counterpart of `update_eflags_add()`.
+    fn update_eflags_add8(&mut self, v1: u8, v2: u8, result: u16) {
+        self.update_eflags(
+            v1 as u32,
+            v2 as u32,
+            result as u64,
+            OperandWidth::Byte,
+            FlagOp::Add,
+        );
+    }
+
+    /// 8-bit counterpart of `update_eflags_sub()`.
+    fn update_eflags_sub8(&mut self, v1: u8, v2: u8, result: u16) {
+        self.update_eflags(
+            v1 as u32,
+            v2 as u32,
+            result as u64,
+            OperandWidth::Byte,
+            FlagOp::Sub,
+        );
+    }
+
+    /// PF is set when the low byte has an even number of set bits.
+    fn even_parity(value: u8) -> bool {
+        value.count_ones().is_multiple_of(2)
+    }
+
+    /// Reads an eflags bit by its mnemonic ("CF", "ZF", ...).
+    /// Returns `None` for an unknown name.
+    pub fn flag(&self, name: &str) -> Option<bool> {
+        Self::flag_index(name).map(|flag| self.get_flag(flag))
+    }
+    /// Writes an eflags bit by its mnemonic ("CF", "ZF", ...).
+    /// Returns `None` and leaves eflags untouched for an unknown name.
+    pub fn set_flag(&mut self, name: &str, value: bool) -> Option<()> {
+        let flag = Self::flag_index(name)?;
+        self.eflags.set_bit(flag, value);
+        Some(())
+    }
+    fn flag_index(name: &str) -> Option<usize> {
+        match name {
+            "CF" => Some(CARRY_FLAG),
+            "PF" => Some(PARITY_FLAG),
+            "AF" => Some(AUXILIARY_FLAG),
+            "ZF" => Some(ZERO_FLAG),
+            "SF" => Some(SIGN_FLAG),
+            "TF" => Some(TRAP_FLAG),
+            "IF" => Some(INTERRUPT_FLAG),
+            "DF" => Some(DIRECTION_FLAG),
+            "OF" => Some(OVERFLOW_FLAG),
+            _ => None,
+        }
+    }
+    fn get_flag(&self, flag: usize) -> bool {
+        self.eflags.get_bit(flag)
+    }
+    fn get_carry(&self) -> bool {
+        self.eflags.get_bit(CARRY_FLAG)
+    }
+    fn get_parity(&self) -> bool {
+        self.eflags.get_bit(PARITY_FLAG)
+    }
+    fn get_zero(&self) -> bool {
+        self.eflags.get_bit(ZERO_FLAG)
+    }
+    fn get_sign(&self) -> bool {
+        self.eflags.get_bit(SIGN_FLAG)
+    }
+    fn get_direction(&self) -> bool {
+        self.eflags.get_bit(DIRECTION_FLAG)
+    }
+    fn get_overflow(&self) -> bool {
+        self.eflags.get_bit(OVERFLOW_FLAG)
+    }
+    fn set_carry(&mut self, is_carry: bool) {
+        self.eflags.set_bit(CARRY_FLAG, is_carry);
+    }
+    fn set_parity(&mut self, is_parity: bool) {
+        self.eflags.set_bit(PARITY_FLAG, is_parity);
+    }
+    fn set_zero(&mut self, is_zero: bool) {
+        self.eflags.set_bit(ZERO_FLAG, is_zero);
+    }
+    /// cleared: string instructions move to ascending addresses; set: descending
+    fn set_direction(&mut self, is_down: bool) {
+        self.eflags.set_bit(DIRECTION_FLAG, is_down);
+    }
+    fn set_sign(&mut self, is_sign: bool) {
+        self.eflags.set_bit(SIGN_FLAG, is_sign);
+    }
+    fn set_overflow(&mut self, is_overflow: bool) {
+        self.eflags.set_bit(OVERFLOW_FLAG, is_overflow);
+    }
+    fn io_in8(&mut self, address: u16) -> u8 {
+        if let Some(value) = self.port_in8(address) {
+            return value;
+        }
+        if let Some(value) = self.read_clock(address) {
+            return value;
+        }
+        match address {
+            SERIAL_PORT => self.serial.read(),
+            _ => 0,
+        }
+    }
+    /// Wider accesses are split into byte accesses to consecutive ports,
+    /// lowest byte first.
+    fn io_in16(&mut self, address: u16) -> u16 {
+        let low = self.io_in8(address);
+        let high = self.io_in8(address.wrapping_add(1));
+        u16::from_le_bytes([low, high])
+    }
+    fn io_in32(&mut self, address: u16) -> u32 {
+        let low = self.io_in16(address);
+        let high = self.io_in16(address.wrapping_add(2));
+        (high as u32) << 16 | low as u32
+    }
+    fn io_out16(&mut self, address: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.io_out8(address, low);
+        self.io_out8(address.wrapping_add(1), high);
+    }
+    fn io_out32(&mut self, address: u16, value: u32) {
+        self.io_out16(address, value as u16);
+        self.io_out16(address.wrapping_add(2), (value >> 16) as u16);
+    }
+    fn io_out8(&mut self, address: u16, value: u8) {
+        if self.port_out8(address, value) {
+            return;
+        }
+        if address == SERIAL_PORT {
+            self.serial.write(value);
+        }
+    }
+
+    fn mov_r8_imm8(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        self.set_register8(reg, instruction.imm as u8);
+    }
+    fn mov_rm8_r8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r8 = self.get_r8(modrm);
+        self.set_rm8(modrm, r8)
+    }
+
+    fn mov_r8_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm8 = self.get_rm8(modrm)?;
+        self.set_r8(modrm, rm8);
+        Ok(())
+    }
+
+    /// The moffs forms (0xa0..0xa3) carry the absolute address as their
+    /// immediate instead of a ModRM.
+    fn mov_al_moffs8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory8(instruction.imm)?;
+        self.set_register8(AL, value);
+        Ok(())
+    }
+    fn mov_eax_moffs32(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory32(instruction.imm)?;
+        self.set_register32(EAX, value);
+        Ok(())
+    }
+    fn mov_moffs8_al(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.try_set_memory8(instruction.imm, self.get_register8(AL))
+    }
+    fn mov_moffs32_eax(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.try_set_memory32(instruction.imm, self.get_register32(EAX))
+    }
+
+    fn cmp_al_imm8(&mut self, instruction: &Instruction) {
+        let value = instruction.imm as u8;
+        let al = self.get_register8(AL);
+        let result = (al as u16).wrapping_sub(value as u16);
+        self.update_eflags_sub8(al, value, result);
+    }
+
+    fn get_register8(&self, index: u8) -> u8 {
+        if index < 4 {
+            (self.get_register32(index) & 0xff) as u8
+        } else {
+            ((self.get_register32(index - 4) & 0xff00) >> 8) as u8
+        }
+    }
+    fn set_register8(&mut self, index: u8, value: u8) {
+        if index < 4 {
+            let r = self.get_register32(index) & 0xffffff00;
+            self.set_register32(index, r | value as u32);
+        } else {
+            let r = self.get_register32(index - 4) & 0xffff00ff;
+            self.set_register32(index - 4, r | ((value as u32) << 8));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn step(emu: &mut Emulator) {
+        emu.step().unwrap();
+    }
+
+    #[test]
+    fn run_until_terminated() {
+        // push 0; ret
+        let mut emu = emulator(&[0x6a, 0x00, 0xc3]);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+    }
+
+    #[test]
+    fn run_until_halted() {
+        // mov eax, 1; hlt; mov eax, 2
+        let mut emu = emulator(&[
+            0xb8, 0x01, 0x00, 0x00, 0x00, 0xf4, 0xb8, 0x02, 0x00, 0x00, 0x00,
+        ]);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert!(emu.is_halted());
+        assert_eq!(emu.eip(), 0x7c06);
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.registers[EAX as usize], 1);
+    }
+
+    #[test]
+    fn run_until_error() {
+        // mov eax, 1; div ecx
+        let mut emu = emulator(&[0xb8, 0x01, 0x00, 0x00, 0x00, 0xf7, 0xf1]);
+        assert_eq!(emu.run(), Err(EmulatorError::DivideByZero));
+        assert_eq!(emu.registers[EAX as usize], 1);
+        assert!(!emu.is_halted());
+    }
+
+    #[test]
+    fn run_until_breakpoint() {
+        // mov eax, 1; mov ebx, 2; push 0; ret
+        let mut emu = emulator(&[
+            0xb8, 0x01, 0x00, 0x00, 0x00, 0xbb, 0x02, 0x00, 0x00, 0x00, 0x6a, 0x00, 0xc3,
+        ]);
+        emu.add_breakpoint(0x7c05);
+        assert_eq!(emu.run(), Ok(RunExit::Breakpoint { address: 0x7c05 }));
+        assert_eq!(emu.eip(), 0x7c05);
+        assert_eq!(emu.registers[EAX as usize], 1);
+        assert_eq!(emu.registers[EBX as usize], 0);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(emu.registers[EBX as usize], 2);
+    }
+
+    #[test]
+    fn run_until_watchpoint() {
+        // mov eax, 0x1234; mov [0x100], eax; push 0; ret
+        let mut emu = emulator(&[
+            0xb8, 0x34, 0x12, 0x00, 0x00, 0x89, 0x05, 0x00, 0x01, 0x00, 0x00, 0x6a, 0x00, 0xc3,
+        ]);
+        emu.add_watchpoint(0x101).unwrap();
+        assert_eq!(
+            emu.run(),
+            Ok(RunExit::Watchpoint {
+                address: 0x101,
+                old: 0x00,
+                new: 0x12
+            })
+        );
+        assert_eq!(emu.eip.0, 0x7c0b);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+
+        assert_eq!(
+            emu.add_watchpoint(0x10000),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+    }
+
+    #[test]
+    fn watchpoint_above_the_address_wrap() {
+        // mov eax, 0x1234; mov [0x100], eax; push 0; ret
+        let mut emu = emulator(&[
+            0xb8, 0x34, 0x12, 0x00, 0x00, 0x89, 0x05, 0x00, 0x01, 0x00, 0x00, 0x6a, 0x00, 0xc3,
+        ]);
+        emu.set_address_wrap(Some(0x10000));
+        emu.add_watchpoint(0x10101).unwrap();
+        assert_eq!(
+            emu.run(),
+            Ok(RunExit::Watchpoint {
+                address: 0x10101,
+                old: 0x00,
+                new: 0x12
+            })
+        );
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+    }
+
+    #[test]
+    fn run_until_timeout() {
+        // jmp $
+        let mut emu = emulator(&[0xeb, 0xfe]);
+        assert_eq!(
+            emu.run_with_timeout(Duration::from_millis(10)),
+            Ok(RunExit::Timeout)
+        );
+        assert_eq!(emu.eip.0, 0x7c00);
+    }
+
+    #[test]
+    fn fwait_only_advances_eip() {
+        let mut emu = emulator(&[0x9b]);
+        let registers = emu.registers;
+        let eflags = emu.eflags;
+        step(&mut emu);
+        assert_eq!(emu.eip.0, 0x7c01);
+        assert_eq!(emu.registers, registers);
+        assert_eq!(emu.eflags, eflags);
+    }
+
+    #[test]
+    fn fpu_instruction_is_skipped_and_reported() {
+        // fldcw [eax]; frstor [ebx+8]
+        let mut emu = emulator(&[0xd9, 0x28, 0xdd, 0x63, 0x08]);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::UnsupportedFpu { opcode: 0xd9 })
+        );
+        assert_eq!(emu.eip.0, 0x7c02);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::UnsupportedFpu { opcode: 0xdd })
+        );
+        assert_eq!(emu.eip.0, 0x7c05);
+    }
+
+    #[test]
+    fn fpu_add_and_store() {
+        // fld dword [0x100]; fld dword [0x104]; faddp st1; fstp qword [0x108]
+        let mut emu = emulator(&[
+            0xd9, 0x05, 0x00, 0x01, 0x00, 0x00, 0xd9, 0x05, 0x04, 0x01, 0x00, 0x00, 0xde, 0xc1,
+            0xdd, 0x1d, 0x08, 0x01, 0x00, 0x00,
+        ]);
+        emu.set_memory32(0x100, 1.5f32.to_bits());
+        emu.set_memory32(0x104, 2.25f32.to_bits());
+        for _ in 0..4 {
+            step(&mut emu);
+        }
+        assert_eq!(emu.get_memory64(0x108), 3.75f64.to_bits());
+        assert_eq!(emu.eip.0, 0x7c14);
+    }
+
+    #[test]
+    fn fpu_integer_load_and_store() {
+        // fild dword [0x100]; fmul st0, st0; fistp dword [0x104]
+        let mut emu = emulator(&[
+            0xdb, 0x05, 0x00, 0x01, 0x00, 0x00, 0xd8, 0xc8, 0xdb, 0x1d, 0x04, 0x01, 0x00, 0x00,
+        ]);
+        emu.set_memory32(0x100, -7i32 as u32);
+        for _ in 0..3 {
+            step(&mut emu);
+        }
+        assert_eq!(emu.get_memory32(0x104), 49);
+    }
+
+    #[test]
+    fn address_wrap() {
+        let mut emu = emulator(&[]);
+        emu.set_address_wrap(Some(0x100000));
+        emu.memory[0] = 0xab;
+        assert_eq!(emu.get_memory8(0x100000), 0xab);
+        emu.set_memory8(0x100001, 0xcd);
+        assert_eq!(emu.memory[1], 0xcd);
+        emu.set_address_wrap(None);
+        assert_eq!(emu.get_memory8(0x1), 0xcd);
+    }
+
+    #[test]
+    fn a20_gate() {
+        let mut emu = Emulator::new(0x200000, 0, 0);
+        assert!(emu.is_a20_enabled());
+        emu.set_memory8(0x000000, 0x11);
+        emu.set_memory8(0x100000, 0x22);
+        assert_ne!(emu.get_memory8(0x100000), emu.get_memory8(0x000000));
+        emu.set_a20(false);
+        assert_eq!(emu.get_memory8(0x100000), 0x11);
+        emu.set_memory8(0x100001, 0x33);
+        assert_eq!(emu.memory[0x000001], 0x33);
+    }
+
+    #[test]
+    fn execute_decoded_instruction() {
+        // cmp eax, 5; jz +0x10
+        let mut emu = emulator(&[0x3d, 0x05, 0x00, 0x00, 0x00, 0x74, 0x10]);
+        emu.registers[EAX as usize] = 5;
+        let cmp = emu.decode(0x7c00).unwrap();
+        assert_eq!(cmp.length, 5);
+        emu.execute(&cmp).unwrap();
+        assert_eq!(emu.eip.0, 0x7c05);
+        let jz = emu.decode(emu.eip.0).unwrap();
+        emu.execute(&jz).unwrap();
+        assert_eq!(emu.eip.0, 0x7c17);
+    }
+
+    #[test]
+    fn push_imm8_is_sign_extended() {
+        // push -1
+        let mut emu = emulator(&[0x6a, 0xff]);
+        step(&mut emu);
+        assert_eq!(emu.get_memory32(0x7bfc), 0xffffffff);
+    }
+
+    #[test]
+    fn unknown_opcode_is_an_error() {
+        let mut emu = emulator(&[0x0f, 0x0b]);
+        assert_eq!(emu.step(), Err(EmulatorError::UnknownOpcode(0x0f0b)));
+        assert_eq!(emu.eip.0, 0x7c00);
+    }
+
+    #[test]
+    fn stack_guard() {
+        // push eax; add esp, -0x20
+        let mut emu = emulator(&[0x50, 0x83, 0xc4, 0xe0]);
+        emu.enable_stack_discipline_checks(0x7bf0);
+        step(&mut emu);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::StackGuard {
+                address: 0x7c01,
+                esp: 0x7bdc
+            })
+        );
+
+        // push eax with ESP = 0 wraps to the top of the address space
+        let mut emu = emulator(&[0x50]);
+        emu.set_address_wrap(Some(0x10000));
+        emu.registers[ESP as usize] = 0;
+        emu.enable_stack_discipline_checks(0);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::StackGuard {
+                address: 0x7c00,
+                esp: 0xfffffffc
+            })
+        );
+    }
+
+    #[test]
+    fn bswap() {
+        // bswap eax; bswap edi
+        let mut emu = emulator(&[0x0f, 0xc8, 0x0f, 0xcf]);
+        emu.registers[EAX as usize] = 0x12345678;
+        emu.registers[EDI as usize] = 0xaabbccdd;
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x78563412);
+        assert_eq!(emu.registers[EDI as usize], 0xddccbbaa);
+        assert_eq!(emu.eip.0, 0x7c04);
+    }
+
+    #[test]
+    fn unsupported_opcodes_are_reported() {
+        // daa; arpl ax, ax; daa; cpuid; push 0; ret
+        let mut emu = emulator(&[0x27, 0x63, 0xc0, 0x27, 0x0f, 0xa2, 0x6a, 0x00, 0xc3]);
+        assert_eq!(emu.run(), Err(EmulatorError::UnknownOpcode(0x27)));
+        emu.eip = Wrapping(0x7c00);
+        emu.set_skip_unsupported(true);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        let report = emu.unsupported_report();
+        let summary: Vec<_> = report
+            .iter()
+            .map(|u| (u.opcode, u.mnemonic, u.address, u.count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0x27, "daa", 0x7c00, 2),
+                (0x63, "arpl", 0x7c01, 1),
+                (0x0fa2, "cpuid", 0x7c04, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_immediate_sizes() {
+        // test byte [eax], 0x12; not byte [eax]; test dword [eax], 0x12345678;
+        // add ax, 0x1234; push 0; ret
+        let mut emu = emulator(&[
+            0xf6, 0x00, 0x12, 0xf6, 0x10, 0xf7, 0x00, 0x78, 0x56, 0x34, 0x12, 0x66, 0x05, 0x34,
+            0x12, 0x6a, 0x00, 0xc3,
+        ]);
+        emu.set_skip_unsupported(true);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        let summary: Vec<_> = emu
+            .unsupported_report()
+            .iter()
+            .map(|u| (u.opcode, u.address, u.count))
+            .collect();
+        assert_eq!(summary, vec![(0xf6, 0x7c00, 2), (0x05, 0x7c0b, 1)]);
+    }
+
+    #[test]
+    fn dump_memory_labels_regions() {
+        let mut emu = emulator(b"Hello, world!\x00\x01\x02xyz");
+        emu.define_region("code", 0x7c00..0x7e00);
+        emu.define_region("stack", 0x7000..0x7c00);
+        assert_eq!(
+            emu.dump_memory(0x7c00, 19),
+            "00007c00  48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 00 01 02  |Hello, world!...| (code)\n\
+             00007c10  78 79 7a                                         |xyz| (code)\n"
+        );
+        assert_eq!(emu.region_of(0x7bfc), Some("stack"));
+        assert_eq!(emu.region_of(0x8000), None);
+        assert!(emu.dump().contains("EIP = 00007c00 (code)"));
+        assert!(emu.dump().contains("ESP = 00007c00 (code)"));
+    }
+
+    #[test]
+    fn dump_memory_clamps_at_end() {
+        let mut emu = Emulator::new(0x20, 0, 0x20);
+        emu.load(b"tail", 0x1c).unwrap();
+        assert_eq!(
+            emu.dump_memory(0x1c, 0x100),
+            "0000001c  74 61 69 6c                                      |tail|\n"
+        );
+        assert_eq!(emu.dump_memory(0x40, 16), "");
+    }
+
+    #[test]
+    fn popcnt() {
+        // popcnt eax, ecx; popcnt edx, ebx
+        let mut emu = emulator(&[0xf3, 0x0f, 0xb8, 0xc1, 0xf3, 0x0f, 0xb8, 0xd3]);
+        emu.registers[ECX as usize] = 0xf0f0_0001;
+        emu.set_flag("CF", true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 9);
+        assert_eq!(emu.flag("ZF"), Some(false));
+        assert_eq!(emu.flag("CF"), Some(false));
+        emu.registers[EDX as usize] = 0x1234;
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0);
+        assert_eq!(emu.flag("ZF"), Some(true));
+        assert_eq!(emu.eip.0, 0x7c08);
+    }
+
+    #[test]
+    fn pre_execute_hook_sees_effective_address() {
+        // mov [ebx+4], eax
+        let mut emu = emulator(&[0x89, 0x43, 0x04]);
+        emu.registers[EBX as usize] = 0x100;
+        emu.registers[EAX as usize] = 0xdeadbeef;
+        let addresses = Rc::new(RefCell::new(Vec::new()));
+        let recorded = addresses.clone();
+        emu.set_pre_execute_hook(Box::new(move |emu, instruction| {
+            let address = emu.effective_address(instruction.modrm()).unwrap();
+            // the store has not happened yet
+            assert_eq!(emu.get_memory32(address), 0);
+            recorded.borrow_mut().push(address);
+        }));
+        step(&mut emu);
+        assert_eq!(*addresses.borrow(), vec![0x104]);
+        assert_eq!(emu.get_memory32(0x104), 0xdeadbeef);
+    }
+
+    #[test]
+    fn pre_execute_hook_cannot_make_lea_use_a_register() {
+        // lea eax, [ebx+4]
+        let mut emu = emulator(&[0x8d, 0x43, 0x04]);
+        emu.set_pre_execute_hook(Box::new(|_, instruction| {
+            // lea eax, ebx
+            instruction.modrm = Some(ModRM::from_code(0xc3));
+        }));
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::IllegalOperand { opcode: 0x8d })
+        );
+        assert_eq!(emu.eip.0, 0x7c00);
+    }
+
+    #[test]
+    fn trace_hook_counts_instructions() {
+        // mov eax, 1; inc eax; ret
+        let mut emu = emulator(&[0xb8, 0x01, 0x00, 0x00, 0x00, 0x40, 0xc3]);
+        emu.push32(0).unwrap();
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let recorded = trace.clone();
+        emu.set_trace_hook(Box::new(move |emu, eip, opcode| {
+            recorded
+                .borrow_mut()
+                .push((eip, opcode, emu.get_register32(EAX)));
+        }));
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(
+            *trace.borrow(),
+            vec![(0x7c00, 0xb8, 0), (0x7c05, 0x40, 1), (0x7c06, 0xc3, 2)]
+        );
+    }
+
+    #[test]
+    fn lods_segment_override() {
+        // lods byte cs:[esi]; lods byte [esi]; lods dword [esi]
+        let mut emu = emulator(&[0x2e, 0xac, 0xac, 0xad]);
+        emu.set_segment_base(Segment::Cs, 0x1000);
+        emu.set_segment_base(Segment::Ds, 0x2000);
+        emu.set_segment_base(Segment::Es, 0x3000);
+        emu.registers[ESI as usize] = 0x10;
+        emu.memory[0x1010] = 0xaa;
+        emu.memory[0x2011] = 0xbb;
+        emu.set_memory32(0x2012, 0x12345678);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 0xaa);
+        assert_eq!(emu.registers[ESI as usize], 0x11);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 0xbb);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x12345678);
+        assert_eq!(emu.registers[ESI as usize], 0x16);
+    }
+
+    #[test]
+    fn rep_lods() {
+        // rep lods byte [esi]
+        let mut emu = emulator(&[0xf3, 0xac]);
+        emu.load(&[1, 2, 3, 4], 0x100).unwrap();
+        emu.registers[ESI as usize] = 0x100;
+        emu.registers[ECX as usize] = 3;
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 3);
+        assert_eq!(emu.registers[ESI as usize], 0x103);
+        assert_eq!(emu.registers[ECX as usize], 0);
+        assert_eq!(emu.eip(), 0x7c02);
+
+        // ECX = 0 runs no iteration
+        emu.set_eip(0x7c00);
+        step(&mut emu);
+        assert_eq!(emu.registers[ESI as usize], 0x103);
+    }
+
+    #[test]
+    fn rep_stos() {
+        // rep stos byte [edi]; stos dword [edi]
+        let mut emu = emulator(&[0xf3, 0xaa, 0xab]);
+        emu.memory[0x100..0x120].fill(0xff);
+        emu.set_segment_base(Segment::Es, 0x80);
+        emu.registers[EDI as usize] = 0x80;
+        emu.registers[ECX as usize] = 16;
+        step(&mut emu);
+        assert_eq!(emu.memory[0x100..0x110], [0; 16]);
+        assert_eq!(emu.memory[0x110], 0xff);
+        assert_eq!(emu.registers[EDI as usize], 0x90);
+
+        emu.registers[EAX as usize] = 0x12345678;
+        emu.set_direction(true);
+        step(&mut emu);
+        assert_eq!(emu.get_memory32(0x110), 0x12345678);
+        assert_eq!(emu.registers[EDI as usize], 0x8c);
+    }
+
+    #[test]
+    fn rep_movs() {
+        // rep movs byte [edi], [esi]; rep movs dword [edi], [esi]
+        let mut emu = emulator(&[0xf3, 0xa4, 0xf3, 0xa5]);
+        emu.load(b"hello, world", 0x100).unwrap();
+        emu.registers[ESI as usize] = 0x100;
+        emu.registers[EDI as usize] = 0x200;
+        emu.registers[ECX as usize] = 5;
+        step(&mut emu);
+        assert_eq!(emu.memory[0x200..0x206], *b"hello\0");
+        assert_eq!(emu.registers[ESI as usize], 0x105);
+        assert_eq!(emu.registers[EDI as usize], 0x205);
+
+        emu.registers[ESI as usize] = 0x100;
+        emu.registers[EDI as usize] = 0x300;
+        emu.registers[ECX as usize] = 3;
+        step(&mut emu);
+        assert_eq!(emu.memory[0x300..0x30c], emu.memory[0x100..0x10c]);
+        assert_eq!(emu.registers[EDI as usize], 0x30c);
+    }
+
+    #[test]
+    fn lods_backwards() {
+        // lods byte [esi]
+        let mut emu = emulator(&[0xac]);
+        emu.set_flag("DF", true);
+        emu.registers[ESI as usize] = 0x10;
+        step(&mut emu);
+        assert_eq!(emu.registers[ESI as usize], 0x0f);
+    }
+
+    /// Runs a single CMP with EAX and ECX preset and returns (CF, ZF, SF, OF).
+    fn cmp_flags(code: &[u8], eax: u32, ecx: u32) -> (bool, bool, bool, bool) {
+        let mut emu = emulator(code);
+        emu.registers[EAX as usize] = eax;
+        emu.registers[ECX as usize] = ecx;
+        step(&mut emu);
+        (
+            emu.get_carry(),
+            emu.get_zero(),
+            emu.get_sign(),
+            emu.get_overflow(),
+        )
+    }
+
+    #[test]
+    fn cmp_overflow() {
+        // cmp eax, ecx
+        let code = [0x3b, 0xc1];
+        assert_eq!(cmp_flags(&code, 0x80000000, 1), (false, false, false, true));
+        assert_eq!(cmp_flags(&code, 1, 0x80000000), (true, false, true, true));
+        assert_eq!(cmp_flags(&code, 0xffffffff, 1), (false, false, true, false));
+        assert_eq!(cmp_flags(&code, 5, 5), (false, true, false, false));
+
+        // cmp eax, 1
+        let code = [0x3d, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(cmp_flags(&code, 0x80000000, 0), (false, false, false, true));
+        assert_eq!(cmp_flags(&code, 0, 0), (true, false, true, false));
+        // cmp eax, 0x80000000
+        let code = [0x3d, 0x00, 0x00, 0x00, 0x80];
+        assert_eq!(cmp_flags(&code, 1, 0), (true, false, true, true));
+
+        // cmp eax, 1 (83 /7)
+        let code = [0x83, 0xf8, 0x01];
+        assert_eq!(cmp_flags(&code, 0x80000000, 0), (false, false, false, true));
+        // cmp eax, -1 (83 /7)
+        let code = [0x83, 0xf8, 0xff];
+        assert_eq!(cmp_flags(&code, 0x7fffffff, 0), (true, false, true, true));
+
+        // cmp al, 1
+        let code = [0x3c, 0x01];
+        assert_eq!(cmp_flags(&code, 0x80, 0), (false, false, false, true));
+        assert_eq!(cmp_flags(&code, 0x00, 0), (true, false, true, false));
+        // cmp al, 0x80
+        let code = [0x3c, 0x80];
+        assert_eq!(cmp_flags(&code, 0x01, 0), (true, false, true, true));
+    }
+
+    #[test]
+    fn eip_accessors() {
+        // jmp $+0x12
+        let mut emu = emulator(&[0xeb, 0x10]);
+        emu.set_eip(0x1234);
+        assert_eq!(emu.eip(), 0x1234);
+        emu.set_eip(0x7c00);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c12);
+    }
+
+    #[test]
+    fn negative_displacement() {
+        // mov eax, [ebx-4]
+        let mut emu = emulator(&[0x8b, 0x43, 0xfc]);
+        emu.registers[EBX as usize] = 0x104;
+        emu.set_memory32(0x100, 0xdeadbeef);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(emu.effective_address(instruction.modrm()), Some(0x100));
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xdeadbeef);
+
+        // wraps below zero
+        emu.registers[EBX as usize] = 2;
+        assert_eq!(emu.effective_address(instruction.modrm()), Some(0xfffffffe));
+
+        // mov eax, [ebx-0x100]
+        let emu = emulator(&[0x8b, 0x83, 0x00, 0xff, 0xff, 0xff]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(emu.effective_address(instruction.modrm()), Some(0xffffff00));
+
+        // mov eax, [0xfffffffc]
+        let emu = emulator(&[0x8b, 0x05, 0xfc, 0xff, 0xff, 0xff]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(emu.effective_address(instruction.modrm()), Some(0xfffffffc));
+    }
+
+    #[test]
+    fn poison_init() {
+        // mov eax, ebx
+        let mut emu = emulator(&[]);
+        emu.set_poison_init(true);
+        assert_eq!(emu.registers[EBX as usize], 0);
+        emu.reset(0x7c00, 0x7c00);
+        emu.clear_memory();
+        emu.load(&[0x8b, 0xc3], 0x7c00).unwrap();
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], POISON);
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+        assert_eq!(emu.get_memory8(0x100), 0xcc);
+        emu.grow_memory(0x20000).unwrap();
+        assert_eq!(emu.get_memory8(0x1ffff), 0xcc);
+
+        // disabling it keeps what is already there
+        emu.set_poison_init(false);
+        assert_eq!(emu.registers[EBX as usize], POISON);
+        assert_eq!(emu.get_memory8(0x100), 0xcc);
+        emu.reset(0x7c00, 0x7c00);
+        emu.clear_memory();
+        assert_eq!(emu.registers[EBX as usize], 0);
+        assert_eq!(emu.get_memory8(0x100), 0);
+    }
+
+    #[test]
+    fn sub_r32() {
+        // sub eax, ebx (29 /r); sub ecx, [0x100] (2b /r)
+        let mut emu = emulator(&[0x29, 0xd8, 0x2b, 0x0d, 0x00, 0x01, 0x00, 0x00]);
+        emu.registers[EAX as usize] = 7;
+        emu.registers[EBX as usize] = 7;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_zero());
+        assert!(!emu.get_carry());
+
+        emu.registers[ECX as usize] = 1;
+        emu.set_memory32(0x100, 2);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xffffffff);
+        assert!(!emu.get_zero());
+        assert!(emu.get_carry());
+        assert!(emu.get_sign());
+    }
+
+    #[test]
+    fn add_flags() {
+        // add eax, ebx; add ecx, 1
+        let mut emu = emulator(&[0x01, 0xd8, 0x83, 0xc1, 0x01]);
+        emu.registers[EAX as usize] = 0xffffffff;
+        emu.registers[EBX as usize] = 1;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_carry());
+        assert!(emu.get_zero());
+        assert!(!emu.get_overflow());
+
+        emu.registers[ECX as usize] = 0x7fffffff;
+        step(&mut emu);
+        assert!(!emu.get_carry());
+        assert!(emu.get_sign());
+        assert!(emu.get_overflow());
+    }
+
+    #[test]
+    fn sib_addressing() {
+        let mut emu = emulator(&[]);
+        emu.registers[EAX as usize] = 0x1000;
+        emu.registers[ECX as usize] = 0x10;
+        emu.registers[ESI as usize] = 0x4;
+        emu.registers[EBP as usize] = 0x2000;
+        let cases: &[(&[u8], u32)] = &[
+            (&[0x8b, 0x04, 0x24], 0x7c00),             // mov eax, [esp]
+            (&[0x8b, 0x04, 0x48], 0x1020),             // mov eax, [eax+ecx*2]
+            (&[0x8b, 0x44, 0xf5, 0xfc], 0x201c),       // mov eax, [ebp+esi*8-4]
+            (&[0x8b, 0x04, 0x8d, 0, 1, 0, 0], 0x140),  // mov eax, [ecx*4+0x100]
+            (&[0x8b, 0x84, 0x20, 0, 1, 0, 0], 0x1100), // mov eax, [eax+0x100] (no index)
+        ];
+        for &(code, address) in cases {
+            emu.memory[0x7c00..0x7c00 + code.len()].copy_from_slice(code);
+            let instruction = emu.decode(0x7c00).unwrap();
+            assert_eq!(instruction.length as usize, code.len(), "{:02x?}", code);
+            assert_eq!(
+                emu.effective_address(instruction.modrm()),
+                Some(address),
+                "{:02x?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn code_81() {
+        // add eax, 0x12345678
+        let mut emu = emulator(&[0x81, 0xc0, 0x78, 0x56, 0x34, 0x12]);
+        emu.registers[EAX as usize] = 0x11111111;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x23456789);
+        assert!(!emu.get_carry());
+
+        let cases: &[(u8, u32, bool, u32)] = &[
+            // (op, ecx, CF before, ecx after) with imm32 = 0x0000ff00
+            (1, 0x12345678, false, 0x1234ff78),
+            (2, 0xffff0100, true, 0x00000001),
+            (3, 0x0000ff00, true, 0xffffffff),
+            (4, 0x12345678, false, 0x00005600),
+            (5, 0x0000ff01, false, 0x00000001),
+            (6, 0x12345678, false, 0x1234a978),
+            (7, 0x0000ff00, false, 0x0000ff00),
+        ];
+        for &(op, before, carry, after) in cases {
+            let mut emu = emulator(&[0x81, 0xc1 | op << 3, 0x00, 0xff, 0x00, 0x00]);
+            emu.registers[ECX as usize] = before;
+            emu.set_carry(carry);
+            step(&mut emu);
+            assert_eq!(emu.registers[ECX as usize], after, "/{}", op);
+            assert_eq!(emu.get_zero(), op == 7, "/{}", op);
+        }
+    }
+
+    #[test]
+    fn logic_r32() {
+        // xor eax, eax
+        let mut emu = emulator(&[0x31, 0xc0]);
+        emu.registers[EAX as usize] = 0x12345678;
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_zero());
+        assert!(!emu.get_carry());
+
+        // and ecx, edx; or ecx, edx; xor edx, ecx (33 /r)
+        let mut emu = emulator(&[0x21, 0xd1, 0x0b, 0xca, 0x33, 0xd1]);
+        emu.registers[ECX as usize] = 0xf0f0f0f0;
+        emu.registers[EDX as usize] = 0xff00ff00;
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xf000f000);
+        assert!(emu.get_sign());
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xff00ff00);
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0);
+        assert!(emu.get_zero());
+    }
+
+    #[test]
+    fn logic_flags_drive_js() {
+        // and eax, 0x80000000; js +5; mov eax, 1; and eax, 0x7fffffff; jns +2
+        let mut emu = emulator(&[
+            0x81, 0xe0, 0x00, 0x00, 0x00, 0x80, 0x78, 0x05, 0xb8, 0x01, 0x00, 0x00, 0x00, 0x81,
+            0xe0, 0xff, 0xff, 0xff, 0x7f, 0x79, 0x02,
+        ]);
+        emu.registers[EAX as usize] = 0xc0000001;
+        emu.set_overflow(true);
+        emu.set_carry(true);
+        step(&mut emu);
+        assert!(emu.get_sign());
+        assert!(!emu.get_carry());
+        assert!(!emu.get_overflow());
+        step(&mut emu);
+        assert_eq!(emu.eip.0, 0x7c0d);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_zero());
+        assert!(emu.get_parity());
+        step(&mut emu);
+        assert_eq!(emu.eip.0, 0x7c17);
+    }
+
+    #[test]
+    fn test_instructions() {
+        // test al, al; test eax, ecx; test al, 0x80; test eax, 0x100
+        let mut emu = emulator(&[
+            0x84, 0xc0, 0x85, 0xc8, 0xa8, 0x80, 0xa9, 0x00, 0x01, 0x00, 0x00,
+        ]);
+        emu.registers[EAX as usize] = 0x1200;
+        emu.registers[ECX as usize] = 0x0200;
+        emu.set_carry(true);
+        step(&mut emu);
+        assert!(emu.get_zero());
+        assert!(!emu.get_carry());
+        step(&mut emu);
+        assert!(!emu.get_zero());
+        assert_eq!(emu.registers[EAX as usize], 0x1200);
+        emu.registers[EAX as usize] = 0x80;
+        step(&mut emu);
+        assert!(!emu.get_zero());
+        assert!(emu.get_sign());
+        step(&mut emu);
+        assert!(emu.get_zero());
+        assert!(!emu.get_sign());
+    }
+
+    #[test]
+    fn high_byte_registers() {
+        let mut emu = emulator(&[]);
+        emu.registers[EAX as usize] = 0x1234;
+        assert_eq!(emu.get_register8(AH), 0x12);
+        assert_eq!(emu.get_register8(AL), 0x34);
+
+        emu.registers[ESP as usize] = 0x7c00;
+        emu.set_register8(BH, 0xab);
+        assert_eq!(emu.registers[EBX as usize], 0xab00);
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+    }
+
+    #[test]
+    fn parity() {
+        // sub eax, 2; jp +2; jnp +2
+        let mut emu = emulator(&[0x83, 0xe8, 0x02, 0x7a, 0x02, 0x00, 0x00, 0x7b, 0x02]);
+        emu.registers[EAX as usize] = 0x105;
+        step(&mut emu);
+        // 0x103: low byte 0b0000_0011
+        assert!(emu.get_parity());
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c07);
+
+        // 0x102 - 2 = 0x100: low byte 0, even
+        emu.registers[EAX as usize] = 0x102;
+        emu.set_eip(0x7c00);
+        step(&mut emu);
+        assert!(emu.get_parity());
+
+        // 0x103 - 2 = 0x101: odd
+        emu.registers[EAX as usize] = 0x103;
+        emu.set_eip(0x7c00);
+        step(&mut emu);
+        assert!(!emu.get_parity());
+        emu.set_eip(0x7c07);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c0b);
+    }
+
+    #[test]
+    fn jcc_rel32() {
+        // cmp eax, 0; jz +0x200; jnz -0x100
+        let mut emu = emulator(&[
+            0x83, 0xf8, 0x00, 0x0f, 0x84, 0x00, 0x02, 0x00, 0x00, 0x0f, 0x85, 0x00, 0xff, 0xff,
+            0xff,
+        ]);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c09 + 0x200);
+
+        emu.registers[EAX as usize] = 1;
+        emu.set_eip(0x7c00);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c09);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c0f - 0x100);
+    }
+
+    #[test]
+    fn step_errors() {
+        // push dword [eax] (0xff /6)
+        let mut emu = emulator(&[0xff, 0x30]);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::UnimplementedModRM {
+                opcode: 0xff,
+                op: 6
+            })
+        );
+
+        emu.set_eip(0x10000);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+
+        // mov eax, imm32 cut off by the end of memory
+        emu.memory[0xfffe..].copy_from_slice(&[0xb8, 0x01]);
+        emu.set_eip(0xfffe);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+
+        // mov eax, [0xfffffff0]
+        let mut emu = emulator(&[0x8b, 0x05, 0xf0, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::OutOfBounds {
+                address: 0xfffffff0
+            })
+        );
+
+        // push 0 with ESP past the end of memory
+        let mut emu = emulator(&[0x6a, 0x00]);
+        emu.registers[ESP as usize] = 0x10004;
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+        assert_eq!(emu.registers[ESP as usize], 0x10004);
+    }
+
+    #[test]
+    fn memory_out_of_bounds() {
+        let mut emu = Emulator::new(0x10, 0, 0);
+        assert_eq!(emu.try_get_memory8(0x0f), Ok(0));
+        assert_eq!(
+            emu.try_get_memory8(0x10),
+            Err(EmulatorError::OutOfBounds { address: 0x10 })
+        );
+        assert_eq!(
+            emu.try_get_memory32(0x0d),
+            Err(EmulatorError::OutOfBounds { address: 0x10 })
+        );
+        assert_eq!(
+            emu.try_set_memory32(0x0e, 0xffffffff),
+            Err(EmulatorError::OutOfBounds { address: 0x10 })
+        );
+        assert_eq!(emu.memory[0x0e..], [0, 0]);
+        assert_eq!(emu.try_set_memory32(0x0c, 0x12345678), Ok(()));
+        assert_eq!(emu.try_get_memory32(0x0c), Ok(0x12345678));
+    }
+
+    #[test]
+    fn shifts() {
+        // shl eax, 1; shr eax, 4; sar ecx, cl; sar ecx, 0
+        let mut emu = emulator(&[0xd1, 0xe0, 0xc1, 0xe8, 0x04, 0xd3, 0xf9, 0xc1, 0xf9, 0x00]);
+        emu.registers[EAX as usize] = 0x80000001;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 2);
+        assert!(emu.get_carry());
+        assert!(emu.get_overflow());
+
+        emu.registers[EAX as usize] = 0x18;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 1);
+        assert!(emu.get_carry());
+        assert!(!emu.get_zero());
+
+        emu.registers[ECX as usize] = 0x80000024;
+        step(&mut emu);
+        // count = 0x24 & 0x1f = 4
+        assert_eq!(emu.registers[ECX as usize], 0xf8000002);
+        assert!(!emu.get_carry());
+        assert!(emu.get_sign());
+
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xf8000002);
+        assert!(emu.get_carry());
+    }
+
+    #[test]
+    fn mul_imul() {
+        // mul ecx; imul ecx
+        let mut emu = emulator(&[0xf7, 0xe1, 0xf7, 0xe9]);
+        emu.registers[EAX as usize] = 0x10000;
+        emu.registers[ECX as usize] = 0x12345;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x23450000);
+        assert_eq!(emu.registers[EDX as usize], 0x1);
+        assert!(emu.get_overflow());
+        assert!(emu.get_carry());
+
+        emu.registers[EAX as usize] = 0xfffffffe;
+        emu.registers[ECX as usize] = 3;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xfffffffa);
+        assert_eq!(emu.registers[EDX as usize], 0xffffffff);
+        assert!(!emu.get_overflow());
+        assert!(!emu.get_carry());
+
+        // test ecx, 0x100
+        let mut emu = emulator(&[0xf7, 0xc1, 0x00, 0x01, 0x00, 0x00]);
+        emu.registers[ECX as usize] = 0x0ff;
+        step(&mut emu);
+        assert!(emu.get_zero());
+        assert_eq!(emu.eip(), 0x7c06);
+    }
+
+    #[test]
+    fn div_idiv() {
+        // div ecx; idiv ecx
+        let mut emu = emulator(&[0xf7, 0xf1, 0xf7, 0xf9]);
+        emu.registers[EDX as usize] = 1;
+        emu.registers[EAX as usize] = 5;
+        emu.registers[ECX as usize] = 0x10;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x10000000);
+        assert_eq!(emu.registers[EDX as usize], 5);
+
+        // -100 / 7 = -14 remainder -2
+        emu.registers[EDX as usize] = 0xffffffff;
+        emu.registers[EAX as usize] = -100i32 as u32;
+        emu.registers[ECX as usize] = 7;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], -14i32 as u32);
+        assert_eq!(emu.registers[EDX as usize], -2i32 as u32);
+
+        // 42 / 6 = 7 remainder 0
+        emu.set_eip(0x7c00);
+        emu.registers[EDX as usize] = 0;
+        emu.registers[EAX as usize] = 42;
+        emu.registers[ECX as usize] = 6;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 7);
+        assert_eq!(emu.registers[EDX as usize], 0);
+    }
+
+    #[test]
+    fn divide_errors() {
+        // div ecx
+        let mut emu = emulator(&[0xf7, 0xf1]);
+        emu.registers[EAX as usize] = 42;
+        assert_eq!(emu.step(), Err(EmulatorError::DivideByZero));
+        assert_eq!(emu.registers[EAX as usize], 42);
+
+        emu.set_eip(0x7c00);
+        emu.registers[EDX as usize] = 1;
+        emu.registers[ECX as usize] = 1;
+        assert_eq!(emu.step(), Err(EmulatorError::DivideOverflow));
+    }
+
+    #[test]
+    fn neg_not() {
+        // neg eax; neg eax; not eax
+        let mut emu = emulator(&[0xf7, 0xd8, 0xf7, 0xd8, 0xf7, 0xd0]);
+        emu.registers[EAX as usize] = 5;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], -5i32 as u32);
+        assert!(emu.get_sign());
+        assert!(emu.get_carry());
+
+        emu.registers[EAX as usize] = 0;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_zero());
+        assert!(!emu.get_carry());
+
+        emu.registers[EAX as usize] = 0x0f0f0f0f;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xf0f0f0f0);
+        assert!(emu.get_zero());
+    }
+
+    #[test]
+    fn cmp_r8_rm8() {
+        // cmp al, [0x100]; cmp al, bl; cmp bl, al
+        let mut emu = emulator(&[0x3a, 0x05, 0x00, 0x01, 0x00, 0x00, 0x3a, 0xc3, 0x3a, 0xd8]);
+        emu.memory[0x100] = b'a';
+        emu.registers[EAX as usize] = b'a' as u32;
+        emu.registers[EBX as usize] = b'z' as u32;
+        step(&mut emu);
+        assert!(emu.get_zero());
+        assert!(!emu.get_carry());
+        // 'a' - 'z' borrows and is negative
+        step(&mut emu);
+        assert!(!emu.get_zero());
+        assert!(emu.get_carry());
+        assert!(emu.get_sign());
+        step(&mut emu);
+        assert!(!emu.get_carry());
+        assert!(!emu.get_sign());
+        assert_eq!(emu.registers[EBX as usize], b'z' as u32);
+    }
+
+    #[test]
+    fn arithmetic_r8() {
+        // add al, bl; add ah, [0x100]; sub cl, dl; cmp cl, dl
+        let mut emu = emulator(&[
+            0x00, 0xd8, 0x02, 0x25, 0x00, 0x01, 0x00, 0x00, 0x28, 0xd1, 0x38, 0xd1,
+        ]);
+        emu.registers[EAX as usize] = 0x12ff;
+        emu.registers[EBX as usize] = 0x02;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x1201);
+        assert!(emu.get_carry());
+        assert!(!emu.get_overflow());
+
+        emu.memory[0x100] = 0x70;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x8201);
+        assert!(!emu.get_carry());
+        assert!(emu.get_overflow());
+        assert!(emu.get_sign());
+
+        emu.registers[ECX as usize] = 0x01;
+        emu.registers[EDX as usize] = 0x02;
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xff);
+        assert!(emu.get_carry());
+        assert!(emu.get_sign());
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xff);
+        assert!(!emu.get_carry());
+        assert!(!emu.get_zero());
+    }
+
+    #[test]
+    fn code_80() {
+        // add byte [0x100], 0x81; sub byte [0x100], 1; cmp byte [0x100], 0x80
+        let mut emu = emulator(&[
+            0x80, 0x05, 0x00, 0x01, 0x00, 0x00, 0x81, 0x80, 0x2d, 0x00, 0x01, 0x00, 0x00, 0x01,
+            0x80, 0x3d, 0x00, 0x01, 0x00, 0x00, 0x80,
+        ]);
+        emu.memory[0x100] = 0x80;
+        step(&mut emu);
+        assert_eq!(emu.memory[0x100], 0x01);
+        assert!(emu.get_carry());
+        assert!(emu.get_overflow());
+        step(&mut emu);
+        assert_eq!(emu.memory[0x100], 0x00);
+        assert!(emu.get_zero());
+        step(&mut emu);
+        assert!(emu.get_carry());
+        assert!(emu.get_sign());
+        assert!(emu.get_overflow());
+    }
+
+    #[test]
+    fn code_80_logic_and_carry() {
+        // or al, 0x0f; and al, 0x3c; xor al, 0xff; add cl, 1; adc bl, 0; sbb bl, 0
+        let mut emu = emulator(&[
+            0x80, 0xc8, 0x0f, 0x80, 0xe0, 0x3c, 0x80, 0xf0, 0xff, 0x80, 0xc1, 0x01, 0x80, 0xd3,
+            0x00, 0x80, 0xdb, 0x00,
+        ]);
+        emu.set_register8(AL, 0x30);
+        emu.set_register8(CL, 0xff);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 0x3f);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 0x3c);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(AL), 0xc3);
+        assert!(emu.get_sign());
+        step(&mut emu);
+        assert!(emu.get_carry());
+        step(&mut emu);
+        assert_eq!(emu.get_register8(BL), 1);
+        assert!(!emu.get_carry());
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.get_register8(BL), 0);
+    }
+
+    #[test]
+    fn cmp_byte_then_jz() {
+        // cmp byte [0x100], 'A'; jz +2; mov al, 1; hlt
+        let mut emu = emulator(&[
+            0x80, 0x3d, 0x00, 0x01, 0x00, 0x00, 0x41, 0x74, 0x02, 0xb0, 0x01, 0xf4,
+        ]);
+        emu.memory[0x100] = b'A';
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.get_register8(AL), 0);
+        assert_eq!(emu.memory[0x100], b'A');
+    }
+
+    #[test]
+    fn lea() {
+        // lea eax, [ebx+8]; lea ecx, [eax+edx*4-1]
+        let mut emu = emulator(&[0x8d, 0x43, 0x08, 0x8d, 0x4c, 0x90, 0xff]);
+        emu.registers[EBX as usize] = 0xfff0;
+        emu.registers[EDX as usize] = 2;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xfff8);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0x10000 - 1);
+    }
+
+    #[test]
+    fn pushfd_popfd() {
+        // pushfd; popfd
+        let mut emu = emulator(&[0x9c, 0x9d]);
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.get_memory32(0x7bfc), 1 << CARRY_FLAG | EFLAGS_RESERVED);
+        emu.set_carry(false);
+        step(&mut emu);
+        assert!(emu.get_carry());
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+
+        // reserved and system bits are not popped
+        emu.set_eip(0x7c01);
+        emu.push32(0xffffffff).unwrap();
+        step(&mut emu);
+        assert_eq!(emu.eflags, POPFD_MASK);
+    }
+
+    #[test]
+    fn inc_dec_r8() {
+        // inc al; dec byte [0x100]
+        let mut emu = emulator(&[0xfe, 0xc0, 0xfe, 0x0d, 0x00, 0x01, 0x00, 0x00]);
+        emu.registers[EAX as usize] = 0x7f;
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x80);
+        assert!(emu.get_overflow());
+        assert!(emu.get_sign());
+        assert!(emu.get_carry());
+
+        emu.set_carry(false);
+        step(&mut emu);
+        assert_eq!(emu.memory[0x100], 0xff);
+        assert!(emu.get_sign());
+        assert!(!emu.get_overflow());
+        assert!(!emu.get_carry());
+    }
+
+    #[test]
+    fn inc_dec_r32() {
+        // dec ecx; inc eax; dec dword [0x100]
+        let mut emu = emulator(&[0x49, 0x40, 0xff, 0x0d, 0x00, 0x01, 0x00, 0x00]);
+        emu.registers[ECX as usize] = 1;
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0);
+        assert!(emu.get_zero());
+        assert!(emu.get_carry());
+
+        emu.registers[EAX as usize] = 0x7fffffff;
+        step(&mut emu);
+        assert!(emu.get_overflow());
+        assert!(emu.get_sign());
+        assert!(!emu.get_zero());
+
+        emu.set_memory32(0x100, 0x80000000);
+        step(&mut emu);
+        assert_eq!(emu.get_memory32(0x100), 0x7fffffff);
+        assert!(emu.get_overflow());
+        assert!(!emu.get_sign());
+        assert!(emu.get_carry());
+    }
+
+    #[test]
+    fn load() {
+        let mut emu = Emulator::new(0x100, 0, 0);
+        assert_eq!(emu.load(&[1, 2, 3], 0xf0), Ok(()));
+        assert_eq!(emu.memory[0xef..0xf4], [0, 1, 2, 3, 0]);
+        assert_eq!(
+            emu.load(&[4; 0x20], 0xf0),
+            Err(EmulatorError::OutOfBounds { address: 0x100 })
+        );
+        assert_eq!(emu.memory[0xf3], 0);
+    }
+
+    #[test]
+    fn cld_std() {
+        // std; cld
+        let mut emu = emulator(&[0xfd, 0xfc]);
+        emu.set_carry(true);
+        step(&mut emu);
+        assert!(emu.get_direction());
+        assert!(emu.dump().contains("EFLAGS = 00000401 [CF DF]\n"));
+        step(&mut emu);
+        assert!(!emu.get_direction());
+        assert!(emu.dump().contains("EFLAGS = 00000001 [CF]\n"));
+    }
+
+    #[test]
+    fn indirect_call_jmp() {
+        // mov eax, [ebx*4+0x100]; jmp eax
+        let mut emu = emulator(&[0x8b, 0x04, 0x9d, 0x00, 0x01, 0x00, 0x00, 0xff, 0xe0]);
+        emu.set_memory32(0x100, 0x7d00);
+        emu.set_memory32(0x104, 0x7e00);
+        emu.registers[EBX as usize] = 1;
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7e00);
+
+        // call [0x104]
+        emu.load(&[0xff, 0x15, 0x04, 0x01, 0x00, 0x00], 0x7e00)
+            .unwrap();
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7e00);
+        assert_eq!(emu.registers[ESP as usize], 0x7bfc);
+        assert_eq!(emu.get_memory32(0x7bfc), 0x7e06);
+    }
+
+    #[test]
+    fn movzx_movsx() {
+        // movzx eax, byte [0x100]; movsx ecx, byte [0x100];
+        // movzx edx, word [0x100]; movsx ebx, dx
+        let mut emu = emulator(&[
+            0x0f, 0xb6, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0f, 0xbe, 0x0d, 0x00, 0x01, 0x00, 0x00,
+            0x0f, 0xb7, 0x15, 0x00, 0x01, 0x00, 0x00, 0x0f, 0xbf, 0xda,
+        ]);
+        emu.load(&[0x80, 0x90], 0x100).unwrap();
+        emu.registers[EAX as usize] = 0xffffffff;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x00000080);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xffffff80);
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0x00009080);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBX as usize], 0xffff9080);
+    }
+
+    #[test]
+    fn cmovz() {
+        // cmovz eax, [0x100]
+        for zero in [true, false] {
+            let mut emu = emulator(&[0x0f, 0x44, 0x05, 0x00, 0x01, 0x00, 0x00]);
+            emu.set_memory32(0x100, 0x1234);
+            emu.registers[EAX as usize] = 0xffff;
+            emu.set_zero(zero);
+            step(&mut emu);
+            let expected = if zero { 0x1234 } else { 0xffff };
+            assert_eq!(emu.registers[EAX as usize], expected);
+            assert_eq!(emu.eip(), 0x7c07);
+        }
+    }
+
+    #[test]
+    fn setcc() {
+        // cmp eax, ecx; setz bl; setl byte [0x100]; setnle bh
+        let mut emu = emulator(&[
+            0x3b, 0xc1, 0x0f, 0x94, 0xc3, 0x0f, 0x9c, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0f, 0x9f,
+            0xc7,
+        ]);
+        emu.registers[EAX as usize] = 3;
+        emu.registers[ECX as usize] = 3;
+        emu.registers[EBX as usize] = 0xffffffff;
+        emu.memory[0x100] = 0xff;
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBX as usize], 0xffffff01);
+        step(&mut emu);
+        assert_eq!(emu.memory[0x100], 0);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBX as usize], 0xffff0001);
+    }
+
+    #[test]
+    fn xchg() {
+        // xchg eax, ebx; xchg [0x100], ecx; xchg al, ah
+        let mut emu = emulator(&[0x93, 0x87, 0x0d, 0x00, 0x01, 0x00, 0x00, 0x86, 0xe0]);
+        emu.registers[EAX as usize] = 0x1234;
+        emu.registers[EBX as usize] = 0x5678;
+        emu.registers[ECX as usize] = 0xdeadbeef;
+        emu.set_memory32(0x100, 0xcafe);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x5678);
+        assert_eq!(emu.registers[EBX as usize], 0x1234);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 0xcafe);
+        assert_eq!(emu.get_memory32(0x100), 0xdeadbeef);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x7856);
+    }
+
+    #[test]
+    fn nop() {
+        let mut emu = emulator(&[0x90]);
+        emu.registers = [1, 2, 3, 4, 5, 6, 7, 8];
+        let eflags = emu.eflags;
+        step(&mut emu);
+        assert_eq!(emu.registers, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(emu.eflags, eflags);
+        assert_eq!(emu.eip(), 0x7c01);
+    }
+
+    #[test]
+    fn loop_family() {
+        // xor eax, eax; mov ecx, 5; l: add eax, 3; loop l; jecxz +2; inc eax; inc eax
+        let mut emu = emulator(&[
+            0x31, 0xc0, 0xb9, 0x05, 0x00, 0x00, 0x00, 0x83, 0xc0, 0x03, 0xe2, 0xfb, 0xe3, 0x01,
+            0x40, 0x40,
+        ]);
+        step(&mut emu);
+        let eflags = emu.eflags;
+        step(&mut emu);
+        for _ in 0..5 {
+            step(&mut emu);
+            emu.eflags = eflags;
+            step(&mut emu);
+            assert_eq!(emu.eflags, eflags);
+        }
+        assert_eq!(emu.registers[EAX as usize], 15);
+        assert_eq!(emu.registers[ECX as usize], 0);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 16);
+    }
+
+    #[test]
+    fn loope_loopne() {
+        // mov ecx, 3; cmp eax, eax; loope +0; loopne +0
+        let mut emu = emulator(&[
+            0xb9, 0x03, 0x00, 0x00, 0x00, 0x3b, 0xc0, 0xe1, 0x00, 0xe0, 0x00,
+        ]);
+        step(&mut emu);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 2);
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 1);
+        assert_eq!(emu.eip(), 0x7c0b);
+    }
+
+    #[test]
+    fn cwde_cdq() {
+        // cdq; cwde; cdq
+        let mut emu = emulator(&[0x99, 0x98, 0x99]);
+        emu.registers[EAX as usize] = 0x80000000;
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0xffffffff);
+        emu.registers[EAX as usize] = 0x12348001;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xffff8001);
+        emu.registers[EAX as usize] = 1;
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0);
+    }
+
+    #[test]
+    fn adc_sbb() {
+        // adc eax, ebx; adc ecx, edx; sbb eax, ebx; sbb esi, edi
+        let mut emu = emulator(&[0x13, 0xc3, 0x11, 0xd1, 0x1b, 0xc3, 0x19, 0xfe]);
+        emu.registers[EAX as usize] = 0xffffffff;
+        emu.registers[ECX as usize] = 1;
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_carry());
+        assert!(emu.get_zero());
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], 2);
+        assert!(!emu.get_carry());
+
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0xffffffff);
+        assert!(emu.get_carry());
+        emu.registers[ESI as usize] = 5;
+        emu.registers[EDI as usize] = 0xffffffff;
+        step(&mut emu);
+        assert_eq!(emu.registers[ESI as usize], 5);
+        assert!(emu.get_carry());
+    }
+
+    #[test]
+    fn mov_moffs() {
+        // mov [0x100], eax; mov al, [0x101]; mov [0x200], al; mov eax, [0x200]
+        let mut emu = emulator(&[
+            0xa3, 0x00, 0x01, 0x00, 0x00, 0xa0, 0x01, 0x01, 0x00, 0x00, 0xa2, 0x00, 0x02, 0x00,
+            0x00, 0xa1, 0x00, 0x02, 0x00, 0x00,
+        ]);
+        emu.registers[EAX as usize] = 0x12345678;
+        step(&mut emu);
+        assert_eq!(emu.get_memory32(0x100), 0x12345678);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x12345656);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x56);
+    }
+
+    #[test]
+    fn reset() {
+        // mov eax, 0xffffffff; inc eax; hlt
+        let mut emu = emulator(&[0xb8, 0xff, 0xff, 0xff, 0xff, 0x40, 0xf4]);
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert!(emu.get_zero());
+
+        emu.reset(0x7c00, 0x7c00);
+        assert_eq!(emu.registers, [0, 0, 0, 0, 0x7c00, 0, 0, 0]);
+        assert_eq!(emu.eflags, 0);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.instruction_count(), 0);
+
+        // mov ecx, 2; hlt
+        emu.load(&[0xb9, 0x02, 0x00, 0x00, 0x00, 0xf4], 0x7c00)
+            .unwrap();
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.registers[ECX as usize], 2);
+        assert_eq!(emu.registers[EAX as usize], 0);
+
+        emu.clear_memory();
+        assert!(emu.memory.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn ret_imm16() {
+        // push 2; push 1; call f; hlt; f: ret 8
+        let mut emu = emulator(&[
+            0x6a, 0x02, 0x6a, 0x01, 0xe8, 0x01, 0x00, 0x00, 0x00, 0xf4, 0xc2, 0x08, 0x00,
+        ]);
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+        assert_eq!(emu.eip(), 0x7c0a);
+    }
+
+    #[test]
+    fn enter_leave() {
+        // enter 16, 0; leave; enter 8, 2
+        let mut emu = emulator(&[0xc8, 0x10, 0x00, 0x00, 0xc9, 0xc8, 0x08, 0x00, 0x02]);
+        emu.registers[EBP as usize] = 0x7f00;
+        emu.set_memory32(0x7efc, 0x7e00);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBP as usize], 0x7bfc);
+        assert_eq!(emu.registers[ESP as usize], 0x7bec);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBP as usize], 0x7f00);
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+
+        step(&mut emu);
+        assert_eq!(emu.registers[EBP as usize], 0x7bfc);
+        assert_eq!(emu.get_memory32(0x7bf8), 0x7e00);
+        assert_eq!(emu.get_memory32(0x7bf4), 0x7bfc);
+        assert_eq!(emu.registers[ESP as usize], 0x7bec);
+    }
+
+    #[test]
+    fn run_with_limit() {
+        // jmp $
+        let mut emu = emulator(&[0xeb, 0xfe]);
+        assert_eq!(emu.run_with_limit(100), Ok(RunExit::StepLimit));
+        assert_eq!(emu.instruction_count(), 100);
+        assert_eq!(emu.eip(), 0x7c00);
+    }
+
+    #[test]
+    fn call_rel32_backward() {
+        // f: ret; call f
+        let mut emu = emulator(&[0xc3, 0xe8, 0xfa, 0xff, 0xff, 0xff]);
+        emu.set_eip(0x7c01);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c00);
+        assert_eq!(emu.get_memory32(0x7bfc), 0x7c06);
+
+        // call -0x100 from the bottom of the address space wraps around
+        emu.load(&[0xe8, 0x00, 0xff, 0xff, 0xff], 0).unwrap();
+        emu.set_eip(0);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0xffffff05);
+        assert_eq!(emu.get_memory32(0x7bf8), 5);
+    }
+
+    #[test]
+    fn rotates() {
+        // rol eax, 1; ror eax, 4; rcl ebx, 1; rcr ebx, cl
+        let mut emu = emulator(&[0xd1, 0xc0, 0xc1, 0xc8, 0x04, 0xd1, 0xd3, 0xd3, 0xdb]);
+        emu.registers[EAX as usize] = 0x80000001;
+        emu.registers[EBX as usize] = 0x40000000;
+        emu.registers[ECX as usize] = 2;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x00000003);
+        assert!(emu.get_carry());
+        assert!(emu.get_overflow());
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0x30000000);
+        assert!(!emu.get_carry());
+
+        emu.set_carry(true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EBX as usize], 0x80000001);
+        assert!(!emu.get_carry());
+        assert!(emu.get_overflow());
+        step(&mut emu);
+        // CF:EBX = 0:80000001 rotated right twice through carry
+        assert_eq!(emu.registers[EBX as usize], 0xa0000000);
+        assert!(!emu.get_carry());
+    }
+
+    #[test]
+    fn grow_memory() {
+        let mut emu = Emulator::new(0x1000, 0, 0x1000);
+        let program = [0x90; 0x20];
+        assert_eq!(
+            emu.load(&program, 0x1ff0),
+            Err(EmulatorError::OutOfBounds { address: 0x1ff0 })
+        );
+        emu.grow_memory(0x2010).unwrap();
+        assert_eq!(emu.memory_size(), 0x2010);
+        emu.load(&program, 0x1ff0).unwrap();
+        assert_eq!(
+            emu.grow_memory(0x1000),
+            Err(EmulatorError::MemoryShrink {
+                size: 0x2010,
+                requested: 0x1000
+            })
+        );
+    }
+
+    #[test]
+    fn imul_two_and_three_operands() {
+        // imul eax, ebx; imul ecx, ebx, -3; imul edx, [0x100], 0x10000
+        let mut emu = emulator(&[
+            0x0f, 0xaf, 0xc3, 0x6b, 0xcb, 0xfd, 0x69, 0x15, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x01, 0x00,
+        ]);
+        emu.registers[EAX as usize] = 0x10000;
+        emu.registers[EBX as usize] = 0x30000;
+        emu.set_memory32(0x100, 0x7fff);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 0);
+        assert!(emu.get_carry());
+        assert!(emu.get_overflow());
+        step(&mut emu);
+        assert_eq!(emu.registers[ECX as usize], -0x90000i32 as u32);
+        assert!(!emu.get_overflow());
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 0x7fff0000);
+        assert!(!emu.get_carry());
+    }
+
+    #[test]
+    fn multi_byte_nops() {
+        // nop; nop dword [eax]; nop dword [eax+eax+0]; nop word [eax+eax+0]
+        let code = [
+            0x90, 0x0f, 0x1f, 0x00, 0x0f, 0x1f, 0x44, 0x00, 0x00, 0x66, 0x0f, 0x1f, 0x44, 0x00,
+            0x00,
+        ];
+        let mut emu = emulator(&code);
+        emu.registers = [1, 2, 3, 4, 5, 6, 7, 8];
+        let eflags = emu.eflags;
+        let memory = emu.memory_hash();
+        for eip in [0x7c01, 0x7c04, 0x7c09, 0x7c0f] {
+            step(&mut emu);
+            assert_eq!(emu.eip(), eip);
+        }
+        assert_eq!(emu.registers, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(emu.eflags, eflags);
+        assert_eq!(emu.memory_hash(), memory);
+    }
+
+    #[test]
+    fn far_jmp_and_call() {
+        // jmp 0x08:0x7c10; ...; 0x7c10: call 0x10:0x7c00
+        let mut emu = emulator(&[0xea, 0x10, 0x7c, 0x00, 0x00, 0x08, 0x00]);
+        emu.load(&[0x9a, 0x00, 0x7c, 0x00, 0x00, 0x10, 0x00], 0x7c10)
+            .unwrap();
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c10);
+        assert_eq!(emu.segment(Segment::Cs), 0x08);
+        step(&mut emu);
+        assert_eq!(emu.eip(), 0x7c00);
+        assert_eq!(emu.segment(Segment::Cs), 0x10);
+        assert_eq!(emu.get_memory32(0x7bfc), 0x08);
+        assert_eq!(emu.get_memory32(0x7bf8), 0x7c17);
+    }
+
+    #[test]
+    fn bit_test() {
+        // bt eax, 5; bts eax, ecx; btr eax, 37; btc [0x100], ecx
+        let mut emu = emulator(&[
+            0x0f, 0xba, 0xe0, 0x05, 0x0f, 0xab, 0xc8, 0x0f, 0xba, 0xf0, 0x25, 0x0f, 0xbb, 0x0d,
+            0x00, 0x01, 0x00, 0x00,
+        ]);
+        emu.registers[EAX as usize] = 0x20;
+        emu.registers[ECX as usize] = 33;
+        step(&mut emu);
+        assert!(emu.get_carry());
+        assert_eq!(emu.registers[EAX as usize], 0x20);
+        // register operands take the index modulo 32
+        step(&mut emu);
+        assert!(!emu.get_carry());
+        assert_eq!(emu.registers[EAX as usize], 0x22);
+        step(&mut emu);
+        assert!(emu.get_carry());
+        assert_eq!(emu.registers[EAX as usize], 0x02);
+        // with memory, bit 33 lives in the next dword
+        step(&mut emu);
+        assert!(!emu.get_carry());
+        assert_eq!(emu.get_memory32(0x100), 0);
+        assert_eq!(emu.get_memory32(0x104), 0x02);
+    }
+
+    #[test]
+    fn bit_scan() {
+        // bsf eax, ecx; bsr edx, ecx; bsf eax, ebx
+        let mut emu = emulator(&[0x0f, 0xbc, 0xc1, 0x0f, 0xbd, 0xd1, 0x0f, 0xbc, 0xc3]);
+        emu.registers[ECX as usize] = 0x0000_0100;
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 8);
+        assert!(!emu.get_zero());
+        emu.registers[ECX as usize] = 0x0001_0100;
+        step(&mut emu);
+        assert_eq!(emu.registers[EDX as usize], 16);
+        step(&mut emu);
+        assert!(emu.get_zero());
+        assert_eq!(emu.registers[EAX as usize], 8);
+    }
+
+    #[test]
+    fn update_eflags_widths() {
+        let mut emu = emulator(&[]);
+        let cases: &[(OperandWidth, FlagOp, u32, u32, bool, bool, bool)] = &[
+            // (width, op, v1, v2, CF, SF, OF)
+            (
+                OperandWidth::Byte,
+                FlagOp::Add,
+                0x7f,
+                0x01,
+                false,
+                true,
+                true,
+            ),
+            (
+                OperandWidth::Byte,
+                FlagOp::Add,
+                0xff,
+                0x01,
+                true,
+                false,
+                false,
+            ),
+            (
+                OperandWidth::Byte,
+                FlagOp::Sub,
+                0x00,
+                0x01,
+                true,
+                true,
+                false,
+            ),
+            (
+                OperandWidth::Word,
+                FlagOp::Add,
+                0x7fff,
+                0x01,
+                false,
+                true,
+                true,
+            ),
+            (
+                OperandWidth::Word,
+                FlagOp::Add,
+                0xff,
+                0x01,
+                false,
+                false,
+                false,
+            ),
+            (
+                OperandWidth::Word,
+                FlagOp::Sub,
+                0x8000,
+                0x01,
+                false,
+                false,
+                true,
+            ),
+            (
+                OperandWidth::Dword,
+                FlagOp::Add,
+                0xffff,
+                0x01,
+                false,
+                false,
+                false,
+            ),
+            (
+                OperandWidth::Dword,
+                FlagOp::Add,
+                0xffffffff,
+                0x01,
+                true,
+                false,
+                false,
+            ),
+            (
+                OperandWidth::Dword,
+                FlagOp::Sub,
+                0x00,
+                0x01,
+                true,
+                true,
+                false,
+            ),
+        ];
+        for &(width, op, v1, v2, carry, sign, overflow) in cases {
+            let result = match op {
+                FlagOp::Add => (v1 as u64).wrapping_add(v2 as u64),
+                FlagOp::Sub => (v1 as u64).wrapping_sub(v2 as u64),
+            };
+            emu.update_eflags(v1, v2, result, width, op);
+            let flags = (emu.get_carry(), emu.get_sign(), emu.get_overflow());
+            assert_eq!(
+                flags,
+                (carry, sign, overflow),
+                "{:?} {:?} {:x}, {:x}",
+                width,
+                op,
+                v1,
+                v2
+            );
+            assert_eq!(emu.get_zero(), result.get_bits(0..width.bits()) == 0);
+        }
+    }
+
+    #[test]
+    fn flag_by_name() {
+        let mut emu = emulator(&[]);
+        assert_eq!(emu.flag("CF"), Some(false));
+        assert_eq!(emu.set_flag("CF", true), Some(()));
+        assert_eq!(emu.flag("CF"), Some(true));
+        assert_eq!(emu.eflags, 1);
+        assert_eq!(emu.set_flag("XX", true), None);
+        assert_eq!(emu.flag("XX"), None);
+    }
+
+    #[test]
+    fn flag_set_by_name_is_seen_by_jc() {
+        // jc +0x10
+        let mut emu = emulator(&[0x72, 0x10]);
+        emu.set_flag("CF", true);
+        step(&mut emu);
+        assert_eq!(emu.eip.0, 0x7c12);
+    }
+
+    #[test]
+    fn flag_set_by_name_is_seen_by_adc() {
+        // adc eax, ebx
+        let mut emu = emulator(&[0x11, 0xd8]);
+        emu.registers[EAX as usize] = 1;
+        emu.registers[EBX as usize] = 2;
+        emu.set_flag("CF", true);
+        step(&mut emu);
+        assert_eq!(emu.registers[EAX as usize], 4);
+        assert_eq!(emu.flag("CF"), Some(false));
     }
 }
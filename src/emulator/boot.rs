@@ -0,0 +1,63 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::Emulator;
+
+/// size of a boot sector
+pub const BOOT_SECTOR_SIZE: usize = 512;
+/// address the BIOS loads the boot sector at
+pub const BOOT_ADDRESS: u32 = 0x7c00;
+
+/// Whether `bytes` ends its first sector with the 0x55 0xaa boot signature.
+pub fn is_valid_boot_sector(bytes: &[u8]) -> bool {
+    bytes.len() >= BOOT_SECTOR_SIZE && bytes[510] == 0x55 && bytes[511] == 0xaa
+}
+
+impl Emulator {
+    /// Loads `sector` at 0x7c00 and points EIP at it, as the BIOS does.
+    /// With `verify`, a sector without the boot signature is rejected.
+    pub fn load_boot_sector(&mut self, sector: &[u8], verify: bool) -> Result<(), EmulatorError> {
+        if verify && !is_valid_boot_sector(sector) {
+            return Err(EmulatorError::InvalidBootSignature);
+        }
+        self.load(sector, BOOT_ADDRESS)?;
+        self.set_eip(BOOT_ADDRESS);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sector(signature: [u8; 2]) -> Vec<u8> {
+        let mut sector = vec![0x90; BOOT_SECTOR_SIZE];
+        sector[510..].copy_from_slice(&signature);
+        sector
+    }
+
+    #[test]
+    fn boot_signature() {
+        assert!(is_valid_boot_sector(&sector([0x55, 0xaa])));
+        assert!(!is_valid_boot_sector(&sector([0xaa, 0x55])));
+        assert!(!is_valid_boot_sector(&[0x55, 0xaa]));
+    }
+
+    #[test]
+    fn load_boot_sector() {
+        let mut emu = Emulator::new(0x10000, 0, 0x7c00);
+        assert_eq!(
+            emu.load_boot_sector(&sector([0, 0]), true),
+            Err(EmulatorError::InvalidBootSignature)
+        );
+        assert_eq!(emu.eip(), 0);
+        emu.load_boot_sector(&sector([0x55, 0xaa]), true).unwrap();
+        assert_eq!(emu.eip(), BOOT_ADDRESS);
+        assert_eq!(emu.memory[0x7dfe..0x7e00], [0x55, 0xaa]);
+
+        let mut emu = Emulator::new(0x7d00, 0, 0x7c00);
+        assert_eq!(
+            emu.load_boot_sector(&sector([0x55, 0xaa]), true),
+            Err(EmulatorError::OutOfBounds { address: 0x7d00 })
+        );
+        assert_eq!(emu.eip(), 0);
+    }
+}
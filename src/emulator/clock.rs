@@ -0,0 +1,64 @@
+use crate::emulator::Emulator;
+
+/// I/O port exposing the instruction counter as a deterministic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ClockPort {
+    /// port number
+    port: u16,
+    /// counter value latched by the read of byte 0
+    latch: u64,
+    /// byte returned by the next read, 0 (least significant) to 7
+    next: u8,
+}
+
+impl Emulator {
+    /// Number of instructions executed so far.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Exposes the instruction counter at I/O port `port`, or removes it with
+    /// `None`. Reading the port returns the counter one byte at a time, least
+    /// significant first; the value is latched when byte 0 is read, so eight
+    /// reads give a consistent 64-bit value.
+    pub fn set_clock_port(&mut self, port: Option<u16>) {
+        self.clock = port.map(|port| ClockPort {
+            port,
+            latch: 0,
+            next: 0,
+        });
+    }
+
+    /// Reads the next clock byte if `port` is the clock port.
+    pub(super) fn read_clock(&mut self, port: u16) -> Option<u8> {
+        let count = self.instruction_count;
+        let clock = self.clock.as_mut().filter(|clock| clock.port == port)?;
+        if clock.next == 0 {
+            clock.latch = count;
+        }
+        let value = (clock.latch >> (clock.next * 8)) as u8;
+        clock.next = (clock.next + 1) % 8;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::{AL, BL};
+
+    #[test]
+    fn guest_reads_clock_twice() {
+        // mov edx, 0x40; in al, dx; mov bl, al; in al, dx (x7); in al, dx
+        let mut code = vec![0xba, 0x40, 0x00, 0x00, 0x00, 0xec, 0x8a, 0xd8];
+        code.extend([0xec; 8]);
+        let mut emu = emulator(&code);
+        emu.set_clock_port(Some(0x40));
+        for _ in 0..11 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.instruction_count(), 11);
+        assert_eq!(emu.get_register8(BL), 1);
+        assert_eq!(emu.get_register8(AL), 10);
+    }
+}
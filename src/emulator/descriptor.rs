@@ -0,0 +1,140 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::Instruction;
+use crate::emulator::Emulator;
+use bit_field::BitField;
+
+/// GDTR: where the global descriptor table is and its size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DescriptorTable {
+    /// linear address of the table
+    pub base: u32,
+    /// offset of the last valid byte, i.e. size - 1
+    pub limit: u16,
+}
+
+/// A segment descriptor as stored in the GDT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentDescriptor {
+    pub base: u32,
+    /// offset of the last valid byte, already scaled by the granularity bit
+    pub limit: u32,
+    /// access byte: present, DPL, type
+    pub access: u8,
+    /// upper nibble of byte 6: granularity, default size, long mode
+    pub flags: u8,
+}
+
+impl SegmentDescriptor {
+    pub fn from_bits(bits: u64) -> SegmentDescriptor {
+        let base = bits.get_bits(16..40) | bits.get_bits(56..64) << 24;
+        let limit = bits.get_bits(0..16) | bits.get_bits(48..52) << 16;
+        let flags = bits.get_bits(52..56) as u8;
+        let limit = if flags.get_bit(3) {
+            (limit << 12 | 0xfff) as u32
+        } else {
+            limit as u32
+        };
+        SegmentDescriptor {
+            base: base as u32,
+            limit,
+            access: bits.get_bits(40..48) as u8,
+            flags,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.access.get_bit(7)
+    }
+}
+
+impl Emulator {
+    pub fn gdtr(&self) -> DescriptorTable {
+        self.gdtr
+    }
+
+    /// Reads the descriptor `selector` refers to from the GDT, or `None` if it
+    /// lies past the limit. Bits 0-2 of the selector (RPL, TI) are ignored.
+    pub fn gdt_descriptor(&self, selector: u16) -> Option<SegmentDescriptor> {
+        let offset = (selector & !0b111) as u32;
+        if offset + 7 > self.gdtr.limit as u32 {
+            return None;
+        }
+        let address = self.gdtr.base.wrapping_add(offset);
+        Some(SegmentDescriptor::from_bits(
+            self.try_get_memory64(address).ok()?,
+        ))
+    }
+
+    /// 0x0f 0x01 group: /0 SGDT m, /2 LGDT m. The memory operand is a 16-bit
+    /// limit followed by a 32-bit base, of which LGDT with 16-bit operands
+    /// only loads the low 24 bits.
+    pub(super) fn code_0f01(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        match modrm.op {
+            0 if !modrm.is_reg() => {
+                let address = self.calc_memory_address(modrm);
+                self.try_set_memory16(address, self.gdtr.limit)?;
+                self.try_set_memory32(address.wrapping_add(2), self.gdtr.base)?;
+            }
+            2 if !modrm.is_reg() => {
+                let address = self.calc_memory_address(modrm);
+                let mut base = self.try_get_memory32(address.wrapping_add(2))?;
+                if instruction.prefixes.operand_size {
+                    base &= 0x00ffffff;
+                }
+                self.gdtr = DescriptorTable {
+                    limit: self.try_get_memory16(address)?,
+                    base,
+                };
+            }
+            op => {
+                return Err(EmulatorError::UnimplementedModRM {
+                    opcode: instruction.opcode,
+                    op,
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+
+    #[test]
+    fn lgdt_sgdt() {
+        // lgdt [0x100]; sgdt [0x200]
+        let code = [
+            0x0f, 0x01, 0x15, 0x00, 0x01, 0x00, 0x00, 0x0f, 0x01, 0x05, 0x00, 0x02, 0x00, 0x00,
+        ];
+        let mut emu = emulator(&code);
+        // limit 0x17 (three descriptors), base 0x1000
+        emu.load(&[0x17, 0x00, 0x00, 0x10, 0x00, 0x00], 0x100)
+            .unwrap();
+        // null descriptor, then a flat 4 GiB code segment
+        emu.load(
+            &[
+                0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0x00, 0x00, 0x00, 0x9a, 0xcf, 0x00,
+            ],
+            0x1000,
+        )
+        .unwrap();
+        emu.step().unwrap();
+        assert_eq!(
+            emu.gdtr(),
+            DescriptorTable {
+                base: 0x1000,
+                limit: 0x17
+            }
+        );
+        emu.step().unwrap();
+        assert_eq!(emu.memory[0x200..0x206], emu.memory[0x100..0x106]);
+
+        let code = emu.gdt_descriptor(0x08).unwrap();
+        assert_eq!((code.base, code.limit, code.access), (0, 0xffffffff, 0x9a));
+        assert!(code.is_present());
+        assert_eq!(emu.gdt_descriptor(0x18), None);
+    }
+}
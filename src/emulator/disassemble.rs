@@ -0,0 +1,521 @@
+use crate::emulator::instruction::{Instruction, Repeat};
+use crate::emulator::modrm::ModRM;
+use crate::emulator::Emulator;
+use std::fmt;
+
+const REGISTERS32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+const REGISTERS16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const REGISTERS8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+/// bases of the 16-bit addressing forms, indexed by ModRM rm
+const ADDRESSES16: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+const SEGMENTS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const GROUP_F7: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const GROUP_FF: [&str; 8] = [
+    "inc", "dec", "call", "call far", "jmp", "jmp far", "push", "?",
+];
+const X87_ARITH: [&str; 8] = [
+    "fadd", "fmul", "fcom", "fcomp", "fsub", "fsubr", "fdiv", "fdivr",
+];
+const BIT_TEST: [&str; 4] = ["bt", "bts", "btr", "btc"];
+/// condition code suffixes, indexed by the low 4 bits of Jcc/SETcc
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "nb", "z", "nz", "be", "nbe", "s", "ns", "p", "np", "l", "nl", "le", "nle",
+];
+
+/// Operand size in bits.
+#[derive(Clone, Copy)]
+enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    fn register(self, reg: u8) -> &'static str {
+        match self {
+            Width::Byte => REGISTERS8[reg as usize],
+            Width::Word => REGISTERS16[reg as usize],
+            _ => REGISTERS32[reg as usize],
+        }
+    }
+
+    fn keyword(self) -> &'static str {
+        match self {
+            Width::Byte => "byte",
+            Width::Word => "word",
+            Width::Dword => "dword",
+            Width::Qword => "qword",
+        }
+    }
+}
+
+/// Small numbers in decimal, anything else in hex.
+fn number(value: u32) -> String {
+    if value < 10 {
+        value.to_string()
+    } else {
+        format!("0x{:x}", value)
+    }
+}
+
+/// `number()`, with negative values as `-n`
+fn signed(value: u32) -> String {
+    if (value as i32) < 0 {
+        format!("-{}", number(value.wrapping_neg()))
+    } else {
+        number(value)
+    }
+}
+
+impl Instruction {
+    /// operand size of the non-byte forms
+    fn width(&self) -> Width {
+        if self.prefixes.operand_size {
+            Width::Word
+        } else {
+            Width::Dword
+        }
+    }
+
+    /// target of a relative jump or call; rel16 wraps within 64 KiB
+    fn target(&self) -> String {
+        if self.prefixes.operand_size && matches!(self.opcode, 0xe8 | 0xe9 | 0x0f80..=0x0f8f) {
+            let ip = (self.next() as u16).wrapping_add(self.imm as u16);
+            return format!("0x{:x}", ip);
+        }
+        format!("0x{:x}", self.next().wrapping_add(self.imm))
+    }
+
+    fn reg(&self, width: Width) -> &'static str {
+        width.register(self.modrm().op)
+    }
+
+    /// The r/m operand. Memory operands get a size keyword when `sized`,
+    /// i.e. when no register operand implies it.
+    fn rm(&self, width: Width, sized: bool) -> String {
+        let modrm = self.modrm();
+        if modrm.is_reg() {
+            return width.register(modrm.rm).to_string();
+        }
+        let segment = match self.prefixes.segment {
+            Some(segment) => format!("{}:", SEGMENTS[segment as usize]),
+            None => String::new(),
+        };
+        let memory = format!("{}[{}]", segment, Self::address(modrm));
+        if sized {
+            format!("{} {}", width.keyword(), memory)
+        } else {
+            memory
+        }
+    }
+
+    /// The effective address expression, e.g. `ebx+esi*4+8` or `bx+si+8`.
+    fn address(modrm: &ModRM) -> String {
+        let mut terms = Vec::new();
+        if modrm.address16 {
+            if !(modrm.md == 0 && modrm.rm == 6) {
+                terms.push(ADDRESSES16[modrm.rm as usize].to_string());
+            }
+        } else if modrm.has_sib() {
+            if !(modrm.md == 0 && modrm.base() == 5) {
+                terms.push(REGISTERS32[modrm.base() as usize].to_string());
+            }
+            if modrm.index() != 4 {
+                let index = REGISTERS32[modrm.index() as usize];
+                terms.push(match modrm.scale() {
+                    0 => index.to_string(),
+                    scale => format!("{}*{}", index, 1 << scale),
+                });
+            }
+        } else if !(modrm.md == 0 && modrm.rm == 5) {
+            terms.push(REGISTERS32[modrm.rm as usize].to_string());
+        }
+
+        let mut text = terms.join("+");
+        if terms.is_empty() && modrm.address16 {
+            text = format!("0x{:x}", modrm.disp as u16);
+        } else if terms.is_empty() {
+            text = format!("0x{:x}", modrm.disp as u32);
+        } else if modrm.disp < 0 {
+            text += &format!("-{}", number(modrm.disp.unsigned_abs()));
+        } else if modrm.disp > 0 {
+            text += &format!("+{}", number(modrm.disp as u32));
+        }
+        text
+    }
+
+    /// string instruction with its REP/REPE/REPNE and segment override
+    /// prefixes and size suffix, e.g. `rep cs movsb`
+    fn string(&self, name: &str, width: Width) -> String {
+        let prefix = match (self.prefixes.repeat, self.opcode) {
+            (None, _) => "",
+            (Some(Repeat::Rep), 0xa6 | 0xa7 | 0xae | 0xaf) => "repe ",
+            (Some(Repeat::Rep), _) => "rep ",
+            (Some(Repeat::Repne), _) => "repne ",
+        };
+        let suffix = match width {
+            Width::Byte => "b",
+            Width::Word => "w",
+            _ => "d",
+        };
+        let segment = match self.prefixes.segment {
+            Some(segment) => format!("{} ", SEGMENTS[segment as usize]),
+            None => String::new(),
+        };
+        format!("{}{}{}{}", prefix, segment, name, suffix)
+    }
+
+    fn x87(&self) -> String {
+        let modrm = self.modrm();
+        let opcode = self.opcode as u8;
+        let op = modrm.op as usize;
+        if modrm.is_reg() {
+            let i = modrm.rm;
+            // the reversed forms swap SUB/SUBR and DIV/DIVR encodings
+            let reversed = ["fadd", "fmul", "?", "?", "fsubr", "fsub", "fdivr", "fdiv"];
+            return match (opcode, op) {
+                (0xd8, _) => format!("{} st0, st{}", X87_ARITH[op], i),
+                (0xdc, _) => format!("{} st{}, st0", reversed[op], i),
+                (0xde, _) => format!("{}p st{}, st0", reversed[op], i),
+                (0xd9, 0) => format!("fld st{}", i),
+                (0xdd, 2) => format!("fst st{}", i),
+                (0xdd, 3) => format!("fstp st{}", i),
+                _ => format!("(bad x87 {:02x} /{})", opcode, op),
+            };
+        }
+        let (name, width) = match (opcode, op) {
+            (0xd8, _) => (X87_ARITH[op], Width::Dword),
+            (0xdc, _) => (X87_ARITH[op], Width::Qword),
+            (0xd9, 0) => ("fld", Width::Dword),
+            (0xd9, 2) => ("fst", Width::Dword),
+            (0xd9, 3) => ("fstp", Width::Dword),
+            (0xdd, 0) => ("fld", Width::Qword),
+            (0xdd, 2) => ("fst", Width::Qword),
+            (0xdd, 3) => ("fstp", Width::Qword),
+            (0xdb, 0) => ("fild", Width::Dword),
+            (0xdb, 2) => ("fist", Width::Dword),
+            (0xdb, 3) => ("fistp", Width::Dword),
+            (0xdf, 5) => ("fild", Width::Qword),
+            (0xdf, 7) => ("fistp", Width::Qword),
+            _ => return format!("(bad x87 {:02x} /{})", opcode, op),
+        };
+        format!("{} {}", name, self.rm(width, true))
+    }
+}
+
+/// Intel syntax, e.g. `add [ebx+8], ecx`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let w = self.width();
+        // sign-extended imm8s read better as negative numbers
+        let imm = match self.opcode {
+            0x6a | 0x6b | 0x83 => signed(self.imm),
+            _ => number(self.imm),
+        };
+        let text = match self.opcode {
+            0x00..=0x3f if self.opcode & 0b111 < 6 => {
+                let name = ALU[(self.opcode >> 3) as usize];
+                match self.opcode & 0b111 {
+                    0 => format!(
+                        "{} {}, {}",
+                        name,
+                        self.rm(Width::Byte, false),
+                        self.reg(Width::Byte)
+                    ),
+                    1 => format!("{} {}, {}", name, self.rm(w, false), self.reg(w)),
+                    2 => format!(
+                        "{} {}, {}",
+                        name,
+                        self.reg(Width::Byte),
+                        self.rm(Width::Byte, false)
+                    ),
+                    3 => format!("{} {}, {}", name, self.reg(w), self.rm(w, false)),
+                    4 => format!("{} al, {}", name, imm),
+                    _ => format!("{} {}, {}", name, w.register(0), imm),
+                }
+            }
+            0x40..=0x47 => format!("inc {}", w.register(self.opcode_register())),
+            0x48..=0x4f => format!("dec {}", w.register(self.opcode_register())),
+            0x50..=0x57 => format!("push {}", w.register(self.opcode_register())),
+            0x58..=0x5f => format!("pop {}", w.register(self.opcode_register())),
+            0x68 | 0x6a => format!("push {}", imm),
+            0x69 | 0x6b => format!("imul {}, {}, {}", self.reg(w), self.rm(w, false), imm),
+            0x70..=0x7f => format!(
+                "j{} {}",
+                CONDITIONS[(self.opcode & 0xf) as usize],
+                self.target()
+            ),
+            0x80 => format!(
+                "{} {}, {}",
+                ALU[self.modrm().op as usize],
+                self.rm(Width::Byte, true),
+                imm
+            ),
+            0x81 | 0x83 => format!(
+                "{} {}, {}",
+                ALU[self.modrm().op as usize],
+                self.rm(w, true),
+                imm
+            ),
+            0x84 => format!(
+                "test {}, {}",
+                self.rm(Width::Byte, false),
+                self.reg(Width::Byte)
+            ),
+            0x85 => format!("test {}, {}", self.rm(w, false), self.reg(w)),
+            0x86 => format!(
+                "xchg {}, {}",
+                self.rm(Width::Byte, false),
+                self.reg(Width::Byte)
+            ),
+            0x87 => format!("xchg {}, {}", self.rm(w, false), self.reg(w)),
+            0x88 => format!(
+                "mov {}, {}",
+                self.rm(Width::Byte, false),
+                self.reg(Width::Byte)
+            ),
+            0x89 => format!("mov {}, {}", self.rm(w, false), self.reg(w)),
+            0x8a => format!(
+                "mov {}, {}",
+                self.reg(Width::Byte),
+                self.rm(Width::Byte, false)
+            ),
+            0x8b => format!("mov {}, {}", self.reg(w), self.rm(w, false)),
+            0x8c => format!(
+                "mov {}, {}",
+                self.rm(w, false),
+                SEGMENTS.get(self.modrm().op as usize).unwrap_or(&"?")
+            ),
+            0x8d => format!("lea {}, {}", self.reg(w), self.rm(w, false)),
+            0x8e => format!(
+                "mov {}, {}",
+                SEGMENTS.get(self.modrm().op as usize).unwrap_or(&"?"),
+                self.rm(Width::Word, false)
+            ),
+            0x90 => "nop".to_string(),
+            0x91..=0x97 => format!(
+                "xchg {}, {}",
+                w.register(0),
+                w.register(self.opcode_register())
+            ),
+            0x98 if self.prefixes.operand_size => "cbw".to_string(),
+            0x98 => "cwde".to_string(),
+            0x99 if self.prefixes.operand_size => "cwd".to_string(),
+            0x99 => "cdq".to_string(),
+            0x9a => format!("call far 0x{:x}:0x{:x}", self.selector, self.imm),
+            0x9b => "fwait".to_string(),
+            0x9c if self.prefixes.operand_size => "pushf".to_string(),
+            0x9c => "pushfd".to_string(),
+            0x9d if self.prefixes.operand_size => "popf".to_string(),
+            0x9d => "popfd".to_string(),
+            0xa0 => format!("mov al, [0x{:x}]", self.imm),
+            0xa1 => format!("mov {}, [0x{:x}]", w.register(0), self.imm),
+            0xa2 => format!("mov [0x{:x}], al", self.imm),
+            0xa3 => format!("mov [0x{:x}], {}", self.imm, w.register(0)),
+            0xa4 => self.string("movs", Width::Byte),
+            0xa5 => self.string("movs", w),
+            0xa6 => self.string("cmps", Width::Byte),
+            0xa7 => self.string("cmps", w),
+            0xa8 => format!("test al, {}", imm),
+            0xa9 => format!("test {}, {}", w.register(0), imm),
+            0xaa => self.string("stos", Width::Byte),
+            0xab => self.string("stos", w),
+            0xac => self.string("lods", Width::Byte),
+            0xad => self.string("lods", w),
+            0xae => self.string("scas", Width::Byte),
+            0xaf => self.string("scas", w),
+            0xb0..=0xb7 => format!(
+                "mov {}, {}",
+                Width::Byte.register(self.opcode_register()),
+                imm
+            ),
+            0xb8..=0xbf => format!("mov {}, {}", w.register(self.opcode_register()), imm),
+            0xc1 => format!(
+                "{} {}, {}",
+                SHIFT[self.modrm().op as usize],
+                self.rm(w, true),
+                imm
+            ),
+            0xc2 => format!("ret {}", imm),
+            0xc3 => "ret".to_string(),
+            0xc7 => format!("mov {}, {}", self.rm(w, true), imm),
+            0xc8 => format!("enter {}, {}", number(self.imm & 0xffff), self.imm >> 16),
+            0xc9 => "leave".to_string(),
+            0xcd => format!("int {}", imm),
+            0xd1 => format!(
+                "{} {}, 1",
+                SHIFT[self.modrm().op as usize],
+                self.rm(w, true)
+            ),
+            0xd3 => format!(
+                "{} {}, cl",
+                SHIFT[self.modrm().op as usize],
+                self.rm(w, true)
+            ),
+            0xd8..=0xdf => self.x87(),
+            0xe0 => format!("loopne {}", self.target()),
+            0xe1 => format!("loope {}", self.target()),
+            0xe2 => format!("loop {}", self.target()),
+            0xe3 if self.prefixes.address_size => format!("jcxz {}", self.target()),
+            0xe3 => format!("jecxz {}", self.target()),
+            0xe8 => format!("call {}", self.target()),
+            0xe9 | 0xeb => format!("jmp {}", self.target()),
+            0xea => format!("jmp far 0x{:x}:0x{:x}", self.selector, self.imm),
+            0xe4 => format!("in al, {}", imm),
+            0xe5 => format!("in {}, {}", w.register(0), imm),
+            0xe6 => format!("out {}, al", imm),
+            0xe7 => format!("out {}, {}", imm, w.register(0)),
+            0xec => "in al, dx".to_string(),
+            0xed => format!("in {}, dx", w.register(0)),
+            0xee => "out dx, al".to_string(),
+            0xef => format!("out dx, {}", w.register(0)),
+            0xf4 => "hlt".to_string(),
+            0xf7 => match self.modrm().op {
+                0 => format!("test {}, {}", self.rm(w, true), imm),
+                op => format!("{} {}", GROUP_F7[op as usize], self.rm(w, true)),
+            },
+            0xfc => "cld".to_string(),
+            0xfd => "std".to_string(),
+            0xfe => match self.modrm().op {
+                0 => format!("inc {}", self.rm(Width::Byte, true)),
+                1 => format!("dec {}", self.rm(Width::Byte, true)),
+                op => format!("(bad fe /{})", op),
+            },
+            0xff => format!(
+                "{} {}",
+                GROUP_FF[self.modrm().op as usize],
+                self.rm(w, true)
+            ),
+            0x0f01 => match self.modrm().op {
+                0 => format!("sgdt {}", self.rm(w, false)),
+                2 => format!("lgdt {}", self.rm(w, false)),
+                op => format!("(bad 0f01 /{})", op),
+            },
+            0x0f1f => format!("nop {}", self.rm(w, true)),
+            0x0f30 => "wrmsr".to_string(),
+            0x0f32 => "rdmsr".to_string(),
+            0x0f33 => "rdpmc".to_string(),
+            0x0f40..=0x0f4f => format!(
+                "cmov{} {}, {}",
+                CONDITIONS[(self.opcode & 0xf) as usize],
+                self.reg(w),
+                self.rm(w, false)
+            ),
+            0x0f80..=0x0f8f => format!(
+                "j{} {}",
+                CONDITIONS[(self.opcode & 0xf) as usize],
+                self.target()
+            ),
+            0x0f90..=0x0f9f => format!(
+                "set{} {}",
+                CONDITIONS[(self.opcode & 0xf) as usize],
+                self.rm(Width::Byte, true)
+            ),
+            0x0fa3 | 0x0fab | 0x0fb3 | 0x0fbb => format!(
+                "{} {}, {}",
+                BIT_TEST[(self.opcode >> 3 & 0b11) as usize],
+                self.rm(w, false),
+                self.reg(w)
+            ),
+            0x0faf => format!("imul {}, {}", self.reg(w), self.rm(w, false)),
+            0x0fb6 => format!("movzx {}, {}", self.reg(w), self.rm(Width::Byte, true)),
+            0x0fb7 => format!("movzx {}, {}", self.reg(w), self.rm(Width::Word, true)),
+            0x0fb8 => format!("popcnt {}, {}", self.reg(w), self.rm(w, false)),
+            0x0fba => match self.modrm().op {
+                op @ 4..=7 => format!(
+                    "{} {}, {}",
+                    BIT_TEST[op as usize - 4],
+                    self.rm(w, true),
+                    number(self.imm)
+                ),
+                op => format!("(bad 0fba /{})", op),
+            },
+            0x0fbc => format!("bsf {}, {}", self.reg(w), self.rm(w, false)),
+            0x0fbd => format!("bsr {}, {}", self.reg(w), self.rm(w, false)),
+            0x0fbe => format!("movsx {}, {}", self.reg(w), self.rm(Width::Byte, true)),
+            0x0fbf => format!("movsx {}, {}", self.reg(w), self.rm(Width::Word, true)),
+            0x0fc8..=0x0fcf => format!("bswap {}", w.register(self.opcode_register())),
+            opcode => format!("(bad {:02x})", opcode),
+        };
+        f.write_str(&text)
+    }
+}
+
+impl Emulator {
+    /// Renders the instruction at `address` in Intel syntax and returns it
+    /// with its length in bytes. Bytes that do not decode come out as a
+    /// single `db`.
+    pub fn disassemble(&self, address: u32) -> (String, usize) {
+        match self.decode(address) {
+            Ok(instruction) => (instruction.to_string(), instruction.length as usize),
+            Err(_) => match self.try_get_memory8(address) {
+                Ok(value) => (format!("db 0x{:02x}", value), 1),
+                Err(_) => ("(bad)".to_string(), 0),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::emulator::test_util::emulator;
+
+    fn disassemble(code: &[u8]) -> (String, usize) {
+        emulator(code).disassemble(0x7c00)
+    }
+
+    #[test]
+    fn mov_imm() {
+        assert_eq!(
+            disassemble(&[0xb8, 0x34, 0x12, 0x00, 0x00]),
+            ("mov eax, 0x1234".to_string(), 5)
+        );
+        assert_eq!(
+            disassemble(&[0x66, 0xc7, 0x05, 0x00, 0x01, 0x00, 0x00, 0x02, 0x00]),
+            ("mov word [0x100], 2".to_string(), 9)
+        );
+    }
+
+    #[test]
+    fn modrm_operands() {
+        assert_eq!(
+            disassemble(&[0x01, 0x4b, 0x08]),
+            ("add [ebx+8], ecx".to_string(), 3)
+        );
+        assert_eq!(
+            disassemble(&[0x8d, 0x4c, 0x90, 0xf0]),
+            ("lea ecx, [eax+edx*4-0x10]".to_string(), 4)
+        );
+        assert_eq!(
+            disassemble(&[0x83, 0x6d, 0xfc, 0x01]),
+            ("sub dword [ebp-4], 1".to_string(), 4)
+        );
+    }
+
+    #[test]
+    fn address16() {
+        // 67 66 prefixes select 16-bit addressing and operands
+        assert_eq!(
+            disassemble(&[0x67, 0x66, 0x01, 0x48, 0x08]),
+            ("add [bx+si+8], cx".to_string(), 5)
+        );
+        assert_eq!(
+            disassemble(&[0x67, 0x66, 0x8b, 0x06, 0x00, 0x01]),
+            ("mov ax, [0x100]".to_string(), 6)
+        );
+        assert_eq!(
+            disassemble(&[0x67, 0x66, 0x8d, 0x56, 0xfe]),
+            ("lea dx, [bp-2]".to_string(), 5)
+        );
+    }
+
+    #[test]
+    fn relative_and_strings() {
+        assert_eq!(disassemble(&[0xeb, 0xfe]), ("jmp 0x7c00".to_string(), 2));
+        assert_eq!(disassemble(&[0xf3, 0xab]), ("rep stosd".to_string(), 2));
+        assert_eq!(disassemble(&[0x0f, 0x0b]), ("db 0x0f".to_string(), 1));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::segment::Segment;
+use crate::emulator::Emulator;
+
+/// size of the Program Segment Prefix; a .com image is loaded right after it
+const PSP_SIZE: u32 = 0x100;
+/// offset of the command tail length byte in the PSP
+const COMMAND_TAIL: u32 = 0x80;
+/// longest command tail that fits before the terminating CR
+const COMMAND_TAIL_MAX: usize = 126;
+
+impl Emulator {
+    /// Loads a DOS .com image with a minimal PSP at `psp`.
+    ///
+    /// DS, ES and SS get `psp` as their base, so string instructions and
+    /// DOS services see the PSP at offset 0 as they would in the load
+    /// segment; other memory operands stay flat (see `set_segment_base()`).
+    /// The PSP starts with `INT 20h` and that address is pushed as the
+    /// return address, so a final `RET` ends the program the way it does
+    /// under DOS.
+    pub fn load_com(
+        &mut self,
+        program: &[u8],
+        command_tail: &str,
+        psp: u32,
+    ) -> Result<(), EmulatorError> {
+        let tail = command_tail.as_bytes();
+        if tail.len() > COMMAND_TAIL_MAX {
+            return Err(EmulatorError::CommandTailTooLong { length: tail.len() });
+        }
+
+        let mut prefix = [0; PSP_SIZE as usize];
+        // INT 20h (terminate program)
+        prefix[..2].copy_from_slice(&[0xcd, 0x20]);
+        let tail_start = COMMAND_TAIL as usize + 1;
+        prefix[COMMAND_TAIL as usize] = tail.len() as u8;
+        prefix[tail_start..tail_start + tail.len()].copy_from_slice(tail);
+        prefix[tail_start + tail.len()] = b'\r';
+        self.load(&prefix, psp)?;
+
+        let entry = psp + PSP_SIZE;
+        self.load(program, entry)?;
+        self.push32(psp)?;
+        for segment in [Segment::Ds, Segment::Es, Segment::Ss] {
+            self.set_segment_base(segment, psp);
+        }
+        self.set_eip(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator_with_output;
+    use crate::emulator::{RunExit, AL, BL};
+
+    #[test]
+    fn command_tail() {
+        // mov al, [0x80]; mov bl, [0x81]; ret
+        let program = [
+            0x8a, 0x05, 0x80, 0x00, 0x00, 0x00, 0x8a, 0x1d, 0x81, 0x00, 0x00, 0x00, 0xc3,
+        ];
+        let mut emu = Emulator::new(0x10000, 0, 0xfffe);
+        emu.load_com(&program, " hello", 0).unwrap();
+        assert_eq!(&emu.memory[0x80..0x88], b"\x06 hello\r");
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(emu.get_register8(AL), 6);
+        assert_eq!(emu.get_register8(BL), b' ');
+    }
+
+    #[test]
+    fn load_segment() {
+        // mov esi, 0x81; lodsb; mov edx, msg; mov ah, 9; int 21h; ret;
+        // msg: "ok$"
+        let program = [
+            0xbe, 0x81, 0x00, 0x00, 0x00, 0xac, 0xba, 0x10, 0x01, 0x00, 0x00, 0xb4, 0x09, 0xcd,
+            0x21, 0xc3, b'o', b'k', b'$',
+        ];
+        let (mut emu, output) = emulator_with_output(&[]);
+        emu.load_com(&program, "hi", 0x1000).unwrap();
+        assert_eq!(emu.segment_base(Segment::Ds), 0x1000);
+        // the return address leads to INT 20h at the start of the PSP
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(emu.get_register8(AL), b'h');
+        assert_eq!(*output.0.borrow(), b"ok");
+    }
+
+    #[test]
+    fn command_tail_too_long() {
+        let mut emu = Emulator::new(0x10000, 0, 0xfffe);
+        let tail = "x".repeat(127);
+        assert_eq!(
+            emu.load_com(&[], &tail, 0),
+            Err(EmulatorError::CommandTailTooLong { length: 127 })
+        );
+    }
+
+    #[test]
+    fn image_outside_memory() {
+        let mut emu = Emulator::new(0x10000, 0, 0xfffe);
+        assert_eq!(
+            emu.load_com(&[0xc3], "", 0xff80),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+        assert_eq!(
+            emu.load_com(&[0x90; 0x10000], "", 0),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+
+        // no room below ESP for the return address
+        let mut emu = Emulator::new(0x10000, 0, 0x10004);
+        assert_eq!(
+            emu.load_com(&[0xc3], "", 0),
+            Err(EmulatorError::OutOfBounds { address: 0x10000 })
+        );
+    }
+}
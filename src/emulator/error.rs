@@ -0,0 +1,90 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulatorError {
+    /// opcode the decoder does not know; two-byte opcodes are 0x0fxx
+    UnknownOpcode(u16),
+    /// x87 instruction form that is not emulated; EIP is left just past it
+    UnsupportedFpu { opcode: u8 },
+    /// the instruction at `address` moved ESP below the stack guard, or
+    /// pushed without moving ESP down; it has already been executed
+    StackGuard { address: u32, esp: u32 },
+    /// a DOS command tail longer than the 126 bytes the PSP can hold
+    CommandTailTooLong { length: usize },
+    /// a boot sector without the 0x55 0xaa signature at offset 510
+    InvalidBootSignature,
+    /// RDMSR/WRMSR on an MSR that is not modelled, or RDPMC; `index` is ECX
+    PrivilegedInstruction { opcode: u16, index: u32 },
+    /// instruction from the 0x0f 0x38 / 0x0f 0x3a maps (SSSE3, SSE4, ...);
+    /// `length` is its size in bytes, prefixes included
+    UnsupportedExtension { map: u8, opcode: u8, length: u32 },
+    /// an access to an address past the end of memory
+    OutOfBounds { address: u32 },
+    /// a group opcode whose ModRM /digit is not emulated; EIP is left just past it
+    UnimplementedModRM { opcode: u16, op: u8 },
+    /// a register operand where only memory is allowed, e.g. `lea eax, ecx` (#UD)
+    IllegalOperand { opcode: u16 },
+    /// DIV or IDIV by zero (#DE)
+    DivideByZero,
+    /// DIV or IDIV whose quotient does not fit the destination (#DE)
+    DivideOverflow,
+    /// INT n without a handler, or a service (AH) its handler does not provide
+    UnhandledInterrupt { vector: u8, ah: u8 },
+    /// `grow_memory()` asked for less memory than there already is
+    MemoryShrink { size: usize, requested: usize },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::UnknownOpcode(opcode) => write!(f, "Unknown opcode: {:02x}", opcode),
+            EmulatorError::UnsupportedFpu { opcode } => {
+                write!(f, "Unsupported FPU instruction: {:02x}", opcode)
+            }
+            EmulatorError::StackGuard { address, esp } => write!(
+                f,
+                "Stack check failed: ESP = {:08x} after EIP = {:08x}",
+                esp, address
+            ),
+            EmulatorError::CommandTailTooLong { length } => {
+                write!(f, "Command tail too long: {} bytes", length)
+            }
+            EmulatorError::InvalidBootSignature => write!(f, "Invalid boot sector signature"),
+            EmulatorError::PrivilegedInstruction { opcode, index } => write!(
+                f,
+                "Privileged instruction not serviced: {:04x} (ECX = {:08x})",
+                opcode, index
+            ),
+            EmulatorError::UnsupportedExtension {
+                map,
+                opcode,
+                length,
+            } => write!(
+                f,
+                "Unsupported extension: 0f {:02x} {:02x} ({} bytes)",
+                map, opcode, length
+            ),
+            EmulatorError::OutOfBounds { address } => {
+                write!(f, "Address out of bounds: {:08x}", address)
+            }
+            EmulatorError::UnimplementedModRM { opcode, op } => {
+                write!(f, "Not implemented: {:02x} /{}", opcode, op)
+            }
+            EmulatorError::IllegalOperand { opcode } => {
+                write!(f, "Illegal register operand: {:02x}", opcode)
+            }
+            EmulatorError::DivideByZero => write!(f, "Divide by zero"),
+            EmulatorError::DivideOverflow => write!(f, "Divide overflow"),
+            EmulatorError::MemoryShrink { size, requested } => write!(
+                f,
+                "Memory cannot shrink: {:#x} bytes requested, {:#x} in use",
+                requested, size
+            ),
+            EmulatorError::UnhandledInterrupt { vector, ah } => {
+                write!(f, "Unhandled interrupt: {:02x} (AH = {:02x})", vector, ah)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
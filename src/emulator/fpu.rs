@@ -0,0 +1,47 @@
+/// x87 register stack.
+///
+/// Values are kept as `f64` instead of 80-bit extended precision, and
+/// stack overflow/underflow is not detected.
+#[derive(Debug, Clone, Default)]
+pub struct Fpu {
+    registers: [f64; 8],
+    /// physical index of ST(0)
+    top: usize,
+}
+
+impl Fpu {
+    pub fn push(&mut self, value: f64) {
+        self.top = (self.top + 7) % 8;
+        self.registers[self.top] = value;
+    }
+
+    pub fn pop(&mut self) -> f64 {
+        let value = self.registers[self.top];
+        self.top = (self.top + 1) % 8;
+        value
+    }
+
+    /// ST(i)
+    pub fn st(&self, i: u8) -> f64 {
+        self.registers[(self.top + i as usize) % 8]
+    }
+
+    pub fn set_st(&mut self, i: u8, value: f64) {
+        self.registers[(self.top + i as usize) % 8] = value;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn stack_order() {
+        let mut fpu = Fpu::default();
+        fpu.push(1.0);
+        fpu.push(2.0);
+        assert_eq!(fpu.st(0), 2.0);
+        assert_eq!(fpu.st(1), 1.0);
+        assert_eq!(fpu.pop(), 2.0);
+        assert_eq!(fpu.st(0), 1.0);
+    }
+}
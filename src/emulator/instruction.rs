@@ -0,0 +1,657 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::modrm::ModRM;
+use crate::emulator::operand_size::CpuMode;
+use crate::emulator::segment::Segment;
+use crate::emulator::Emulator;
+use log::info;
+
+/// A decoded instruction. Executing it needs no further code fetches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// address of the first byte
+    pub address: u32,
+    /// prefixes in front of the opcode
+    pub prefixes: Prefixes,
+    /// opcode; two-byte opcodes are stored as 0x0fxx
+    pub opcode: u16,
+    /// ModR/M operand
+    pub modrm: Option<ModRM>,
+    /// immediate or relative offset, sign-extended when the encoding says so
+    pub imm: u32,
+    /// segment selector of a far pointer (ptr16:32 or ptr16:16), whose
+    /// offset is `imm`
+    pub selector: u16,
+    /// length in bytes
+    pub length: u32,
+}
+
+impl Instruction {
+    /// address of the following instruction
+    pub fn next(&self) -> u32 {
+        self.address.wrapping_add(self.length)
+    }
+
+    pub fn modrm(&self) -> &ModRM {
+        self.modrm.as_ref().expect("instruction has no ModRM")
+    }
+
+    /// register encoded in the low 3 bits of the opcode (e.g. `push r32`)
+    pub fn opcode_register(&self) -> u8 {
+        (self.opcode & 0b111) as u8
+    }
+}
+
+/// Legacy prefixes in front of the opcode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    /// 0xf3 (REP/REPE) or 0xf2 (REPNE); also used as a mandatory prefix
+    pub repeat: Option<Repeat>,
+    /// segment override (0x26, 0x2e, 0x36, 0x3e, 0x64, 0x65)
+    pub segment: Option<Segment>,
+    /// 16-bit operands: 0x66 in `CpuMode::Protected32`, its absence in
+    /// `CpuMode::Real16`
+    pub operand_size: bool,
+    /// 16-bit addressing: 0x67 in `CpuMode::Protected32`, its absence in
+    /// `CpuMode::Real16`
+    pub address_size: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    /// 0xf3
+    Rep,
+    /// 0xf2
+    Repne,
+}
+
+/// Immediate following the opcode (and ModRM, if any).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Immediate {
+    None,
+    /// zero-extended byte
+    Byte,
+    /// sign-extended byte, also used for rel8
+    SignedByte,
+    /// word, used under the operand-size prefix
+    Word,
+    /// dword, also used for rel32
+    Dword,
+    /// word then byte (ENTER), stored as `word | byte << 16`
+    WordByte,
+    /// dword offset then word selector (far JMP/CALL)
+    FarPointer,
+    /// word offset then word selector, under the operand-size prefix
+    FarPointer16,
+}
+
+struct Operands {
+    modrm: bool,
+    immediate: Immediate,
+}
+
+/// Reads code bytes from a cursor that only moves forward, so instruction
+/// lengths fall out of the reads instead of being counted by hand. A read
+/// past the end of memory fails with `EmulatorError::OutOfBounds`.
+pub(super) struct InstructionReader<'a> {
+    emulator: &'a Emulator,
+    /// address of the first byte
+    start: u32,
+    /// address of the next byte to read
+    cursor: u32,
+}
+
+impl<'a> InstructionReader<'a> {
+    pub(super) fn new(emulator: &'a Emulator, address: u32) -> Self {
+        InstructionReader {
+            emulator,
+            start: address,
+            cursor: address,
+        }
+    }
+
+    /// next byte without consuming it
+    pub(super) fn peek_u8(&self) -> Result<u8, EmulatorError> {
+        self.emulator.try_get_memory8(self.cursor)
+    }
+
+    pub(super) fn read_u8(&mut self) -> Result<u8, EmulatorError> {
+        let value = self.peek_u8()?;
+        self.skip(1);
+        Ok(value)
+    }
+
+    pub(super) fn read_i8(&mut self) -> Result<i8, EmulatorError> {
+        Ok(self.read_u8()? as i8)
+    }
+
+    pub(super) fn read_u16(&mut self) -> Result<u16, EmulatorError> {
+        let value = self.emulator.try_get_memory16(self.cursor)?;
+        self.skip(2);
+        Ok(value)
+    }
+
+    pub(super) fn read_u32(&mut self) -> Result<u32, EmulatorError> {
+        let value = self.emulator.try_get_memory32(self.cursor)?;
+        self.skip(4);
+        Ok(value)
+    }
+
+    pub(super) fn read_i32(&mut self) -> Result<i32, EmulatorError> {
+        Ok(self.read_u32()? as i32)
+    }
+
+    pub(super) fn skip(&mut self, length: u32) {
+        self.cursor = self.cursor.wrapping_add(length);
+    }
+
+    /// bytes read so far
+    pub(super) fn length(&self) -> u32 {
+        self.cursor.wrapping_sub(self.start)
+    }
+
+    /// Reads legacy prefixes up to the first byte that is not one.
+    pub(super) fn read_prefixes(&mut self) -> Result<Prefixes, EmulatorError> {
+        let mut prefixes = Prefixes::default();
+        loop {
+            let code = self.peek_u8()?;
+            match code {
+                0xf3 => prefixes.repeat = Some(Repeat::Rep),
+                0xf2 => prefixes.repeat = Some(Repeat::Repne),
+                0x66 => prefixes.operand_size = true,
+                0x67 => prefixes.address_size = true,
+                _ => match Segment::from_prefix(code) {
+                    Some(segment) => prefixes.segment = Some(segment),
+                    None => break,
+                },
+            }
+            self.skip(1);
+        }
+        if self.emulator.mode() == CpuMode::Real16 {
+            prefixes.operand_size = !prefixes.operand_size;
+            prefixes.address_size = !prefixes.address_size;
+        }
+        Ok(prefixes)
+    }
+
+    /// Reads a one- or two-byte (0x0fxx) opcode.
+    pub(super) fn read_opcode(&mut self) -> Result<u16, EmulatorError> {
+        Ok(match self.read_u8()? {
+            0x0f => 0x0f00 | self.read_u8()? as u16,
+            opcode => opcode as u16,
+        })
+    }
+
+    /// Reads a ModRM byte with its SIB and displacement, using 16-bit
+    /// addressing when `address16` is set.
+    pub(super) fn read_modrm(&mut self, address16: bool) -> Result<ModRM, EmulatorError> {
+        let code = self.read_u8()?;
+        let mut modrm = if address16 {
+            ModRM::from_code16(code)
+        } else {
+            ModRM::from_code(code)
+        };
+        if modrm.has_sib() {
+            modrm.set_sib(self.read_u8()?);
+        }
+        if modrm.has_disp32() {
+            modrm.set_disp32(self.read_i32()?);
+        } else if modrm.has_disp16() {
+            modrm.set_disp16(self.read_u16()? as i16);
+        } else if modrm.has_disp8() {
+            modrm.set_disp8(self.read_i8()?);
+        }
+
+        info!(
+            "mod = {}, op = {}, rm = {} ({:02X})",
+            modrm.md, modrm.op, modrm.rm, code
+        );
+        Ok(modrm)
+    }
+}
+
+impl Emulator {
+    /// Decodes the instruction at `address` without changing any state.
+    pub fn decode(&self, address: u32) -> Result<Instruction, EmulatorError> {
+        let mut reader = InstructionReader::new(self, address);
+        let prefixes = reader.read_prefixes()?;
+        let opcode = reader.read_opcode()?;
+        if opcode == 0x0f38 || opcode == 0x0f3a {
+            return Self::three_byte_opcode(&mut reader, opcode as u8, &prefixes);
+        }
+
+        let operands =
+            Self::operands(opcode, &prefixes).ok_or(EmulatorError::UnknownOpcode(opcode))?;
+
+        let modrm = operands
+            .modrm
+            .then(|| reader.read_modrm(prefixes.address_size))
+            .transpose()?;
+
+        if let Some(modrm) = &modrm {
+            if modrm.is_reg() && Self::memory_only(opcode) {
+                return Err(EmulatorError::IllegalOperand { opcode });
+            }
+        }
+
+        let mut selector = 0;
+        let imm = match Self::immediate(opcode, &operands, modrm.as_ref(), &prefixes) {
+            Immediate::None => 0,
+            Immediate::Byte => reader.read_u8()? as u32,
+            Immediate::SignedByte => reader.read_i8()? as u32,
+            Immediate::Word => reader.read_u16()? as u32,
+            Immediate::Dword => reader.read_u32()?,
+            Immediate::WordByte => reader.read_u16()? as u32 | (reader.read_u8()? as u32) << 16,
+            Immediate::FarPointer => {
+                let offset = reader.read_u32()?;
+                selector = reader.read_u16()?;
+                offset
+            }
+            Immediate::FarPointer16 => {
+                let offset = reader.read_u16()? as u32;
+                selector = reader.read_u16()?;
+                offset
+            }
+        };
+
+        Ok(Instruction {
+            address,
+            prefixes,
+            opcode,
+            modrm,
+            imm,
+            selector,
+            length: reader.length(),
+        })
+    }
+
+    /// Immediate of `opcode`, which only differs from its table entry for
+    /// the ModRM groups.
+    fn immediate(
+        opcode: u16,
+        operands: &Operands,
+        modrm: Option<&ModRM>,
+        prefixes: &Prefixes,
+    ) -> Immediate {
+        match modrm {
+            // TEST is the only member of the 0xf7 group with an immediate
+            Some(modrm) if opcode == 0xf7 && modrm.op == 0 => {
+                if prefixes.operand_size {
+                    Immediate::Word
+                } else {
+                    Immediate::Dword
+                }
+            }
+            _ => operands.immediate,
+        }
+    }
+
+    /// Sizes an instruction from the 0x0f 0x38 / 0x0f 0x3a maps, whose
+    /// opcode byte is next in `reader`. None of them are supported, but all
+    /// take a ModRM and the 0x3a map an imm8 as well, so this always fails.
+    fn three_byte_opcode(
+        reader: &mut InstructionReader,
+        map: u8,
+        prefixes: &Prefixes,
+    ) -> Result<Instruction, EmulatorError> {
+        let opcode = reader.read_u8()?;
+        reader.read_modrm(prefixes.address_size)?;
+        if map == 0x3a {
+            reader.skip(1);
+        }
+        Err(EmulatorError::UnsupportedExtension {
+            map,
+            opcode,
+            length: reader.length(),
+        })
+    }
+
+    /// Operand encoding of the opcodes supported with 16-bit operands.
+    fn operands16(opcode: u16, prefixes: &Prefixes) -> Option<Operands> {
+        let (modrm, immediate) = match opcode {
+            0x01 | 0x09 | 0x0b | 0x11 | 0x13 | 0x19 | 0x1b | 0x21 | 0x23 | 0x29 | 0x2b | 0x31
+            | 0x33 | 0x3b => (true, Immediate::None),
+            0x3d => (false, Immediate::Word),
+            0x40..=0x5f => (false, Immediate::None),
+            0x68 => (false, Immediate::Word),
+            0x69 => (true, Immediate::Word),
+            0x6a => (false, Immediate::SignedByte),
+            0x6b => (true, Immediate::SignedByte),
+            0x81 | 0xc7 => (true, Immediate::Word),
+            0x83 => (true, Immediate::SignedByte),
+            0x85 | 0x87 | 0x89 | 0x8b..=0x8e => (true, Immediate::None),
+            0x90..=0x99 | 0x9c | 0x9d => (false, Immediate::None),
+            0x9a | 0xea => (false, Immediate::FarPointer16),
+            0xa1 | 0xa3 => (false, Self::moffs(prefixes)),
+            0xa5 | 0xab | 0xad => (false, Immediate::None),
+            0xa9 => (false, Immediate::Word),
+            0xb8..=0xbf => (false, Immediate::Word),
+            0xc1 => (true, Immediate::Byte),
+            0xc2 => (false, Immediate::Word),
+            0xc3 | 0xc9 => (false, Immediate::None),
+            0xc8 => (false, Immediate::WordByte),
+            0xd1 | 0xd3 | 0xf7 | 0xff => (true, Immediate::None),
+            0xe5 | 0xe7 => (false, Immediate::Byte),
+            0xe8 | 0xe9 => (false, Immediate::Word),
+            0xed | 0xef => (false, Immediate::None),
+            0x0f01 | 0x0f1f => (true, Immediate::None),
+            0x0f40..=0x0f4f => (true, Immediate::None),
+            0x0f80..=0x0f8f => (false, Immediate::Word),
+            0x0fa3 | 0x0fab | 0x0fb3 | 0x0fbb | 0x0fbc | 0x0fbd => (true, Immediate::None),
+            0x0faf | 0x0fb6 | 0x0fb7 | 0x0fbe | 0x0fbf => (true, Immediate::None),
+            0x0fba => (true, Immediate::Byte),
+            0x0fb8 if prefixes.repeat == Some(Repeat::Rep) => (true, Immediate::None),
+            _ => return None,
+        };
+        Some(Operands { modrm, immediate })
+    }
+
+    /// The moffs of MOV AL/eAX, [moffs] (0xa0..0xa3) is as wide as an address.
+    fn moffs(prefixes: &Prefixes) -> Immediate {
+        if prefixes.address_size {
+            Immediate::Word
+        } else {
+            Immediate::Dword
+        }
+    }
+
+    /// Opcodes whose ModRM must name a memory operand.
+    pub(super) fn memory_only(opcode: u16) -> bool {
+        matches!(opcode, 0x8d)
+    }
+
+    /// Operand encoding of each supported opcode.
+    fn operands(opcode: u16, prefixes: &Prefixes) -> Option<Operands> {
+        if prefixes.operand_size && !Self::ignores_operand_size(opcode) {
+            return Self::operands16(opcode, prefixes);
+        }
+        let (modrm, immediate) = match opcode {
+            0x00..=0x02
+            | 0x09
+            | 0x0b
+            | 0x11
+            | 0x13
+            | 0x19
+            | 0x1b
+            | 0x21
+            | 0x23
+            | 0x28
+            | 0x29
+            | 0x2b
+            | 0x31
+            | 0x33
+            | 0x38
+            | 0x3a
+            | 0x3b => (true, Immediate::None),
+            0x3c => (false, Immediate::Byte),
+            0x3d => (false, Immediate::Dword),
+            0x40..=0x5f => (false, Immediate::None),
+            0x68 => (false, Immediate::Dword),
+            0x69 => (true, Immediate::Dword),
+            0x6a => (false, Immediate::SignedByte),
+            0x6b => (true, Immediate::SignedByte),
+            0x70..=0x7f => (false, Immediate::SignedByte),
+            0x80 => (true, Immediate::Byte),
+            0x81 => (true, Immediate::Dword),
+            0x83 => (true, Immediate::SignedByte),
+            0x84..=0x8e => (true, Immediate::None),
+            0x90..=0x99 | 0x9b..=0x9d => (false, Immediate::None),
+            0x9a => (false, Immediate::FarPointer),
+            0xa0..=0xa3 => (false, Self::moffs(prefixes)),
+            0xa8 => (false, Immediate::Byte),
+            0xa9 => (false, Immediate::Dword),
+            0xa4 | 0xa5 | 0xaa..=0xad => (false, Immediate::None),
+            0xb0..=0xb7 => (false, Immediate::Byte),
+            0xb8..=0xbf => (false, Immediate::Dword),
+            0xc1 => (true, Immediate::Byte),
+            0xc2 => (false, Immediate::Word),
+            0xc3 => (false, Immediate::None),
+            0xc7 => (true, Immediate::Dword),
+            0xc8 => (false, Immediate::WordByte),
+            0xc9 => (false, Immediate::None),
+            0xcd => (false, Immediate::Byte),
+            0xd1 | 0xd3 | 0xd8..=0xdf => (true, Immediate::None),
+            0xe0..=0xe3 => (false, Immediate::SignedByte),
+            0xe8 | 0xe9 => (false, Immediate::Dword),
+            0xea => (false, Immediate::FarPointer),
+            0xeb => (false, Immediate::SignedByte),
+            0xe4..=0xe7 => (false, Immediate::Byte),
+            0xec..=0xef => (false, Immediate::None),
+            0xf4 | 0xfc | 0xfd => (false, Immediate::None),
+            0xf7 | 0xfe | 0xff => (true, Immediate::None),
+            0x0f01 | 0x0f1f => (true, Immediate::None),
+            0x0f30 | 0x0f32 | 0x0f33 => (false, Immediate::None),
+            0x0f40..=0x0f4f => (true, Immediate::None),
+            0x0f80..=0x0f8f => (false, Immediate::Dword),
+            0x0f90..=0x0f9f => (true, Immediate::None),
+            0x0fa3 | 0x0fab | 0x0fb3 | 0x0fbb | 0x0fbc | 0x0fbd => (true, Immediate::None),
+            0x0faf | 0x0fb6 | 0x0fb7 | 0x0fbe | 0x0fbf => (true, Immediate::None),
+            0x0fba => (true, Immediate::Byte),
+            0x0fb8 if prefixes.repeat == Some(Repeat::Rep) => (true, Immediate::None),
+            0x0fc8..=0x0fcf => (false, Immediate::None),
+            _ => return None,
+        };
+        Some(Operands { modrm, immediate })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+
+    #[test]
+    fn decode_imm32() {
+        // mov eax, 0x1234
+        let emu = emulator(&[0xb8, 0x34, 0x12, 0x00, 0x00]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(
+            instruction,
+            Instruction {
+                address: 0x7c00,
+                prefixes: Prefixes::default(),
+                opcode: 0xb8,
+                modrm: None,
+                imm: 0x1234,
+                selector: 0,
+                length: 5,
+            }
+        );
+        assert_eq!(instruction.next(), 0x7c05);
+        assert_eq!(emu.eip(), 0x7c00);
+    }
+
+    #[test]
+    fn decode_modrm_and_signed_imm8() {
+        // sub dword [ebp-4], -1
+        let emu = emulator(&[0x83, 0x6d, 0xfc, 0xff]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        let modrm = instruction.modrm();
+        assert_eq!((modrm.md, modrm.op, modrm.rm, modrm.disp), (1, 5, 5, -4));
+        assert_eq!(instruction.imm, 0xffffffff);
+        assert_eq!(instruction.length, 4);
+    }
+
+    #[test]
+    fn decode_mandatory_prefix() {
+        // popcnt eax, ecx
+        let emu = emulator(&[0xf3, 0x0f, 0xb8, 0xc1, 0x0f, 0xb8, 0xc1]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(instruction.prefixes.repeat, Some(Repeat::Rep));
+        assert_eq!(instruction.opcode, 0x0fb8);
+        assert_eq!(instruction.length, 4);
+        assert_eq!(
+            emu.decode(0x7c04),
+            Err(EmulatorError::UnknownOpcode(0x0fb8))
+        );
+    }
+
+    /// Re-encodes a decoded instruction, sizing its immediate from the
+    /// opcode table.
+    fn encode(instruction: &Instruction) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match instruction.prefixes.repeat {
+            Some(Repeat::Rep) => bytes.push(0xf3),
+            Some(Repeat::Repne) => bytes.push(0xf2),
+            None => {}
+        }
+        if instruction.prefixes.operand_size {
+            bytes.push(0x66);
+        }
+        if instruction.prefixes.address_size {
+            bytes.push(0x67);
+        }
+        match instruction.prefixes.segment {
+            Some(Segment::Es) => bytes.push(0x26),
+            Some(Segment::Cs) => bytes.push(0x2e),
+            Some(Segment::Ss) => bytes.push(0x36),
+            Some(Segment::Ds) => bytes.push(0x3e),
+            Some(Segment::Fs) => bytes.push(0x64),
+            Some(Segment::Gs) => bytes.push(0x65),
+            None => {}
+        }
+        if instruction.opcode > 0xff {
+            bytes.push(0x0f);
+        }
+        bytes.push(instruction.opcode as u8);
+        if let Some(modrm) = &instruction.modrm {
+            bytes.extend(modrm.to_bytes());
+        }
+        let operands = Emulator::operands(instruction.opcode, &instruction.prefixes).unwrap();
+        let immediate = Emulator::immediate(
+            instruction.opcode,
+            &operands,
+            instruction.modrm.as_ref(),
+            &instruction.prefixes,
+        );
+        let (imm_length, selector) = match immediate {
+            Immediate::None => (0, false),
+            Immediate::Byte | Immediate::SignedByte => (1, false),
+            Immediate::Word => (2, false),
+            Immediate::WordByte => (3, false),
+            Immediate::Dword => (4, false),
+            Immediate::FarPointer => (4, true),
+            Immediate::FarPointer16 => (2, true),
+        };
+        bytes.extend_from_slice(&instruction.imm.to_le_bytes()[..imm_length]);
+        if selector {
+            bytes.extend_from_slice(&instruction.selector.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0x01, 0x4b, 0x08], "add [ebx+8], ecx"),
+            (&[0x3c, 0x41], "cmp al, 0x41"),
+            (&[0x3d, 0x78, 0x56, 0x34, 0x12], "cmp eax, 0x12345678"),
+            (&[0x53], "push ebx"),
+            (&[0x6a, 0xfe], "push -2"),
+            (&[0x75, 0xf0], "jnz 0x7bf2"),
+            (&[0x83, 0x6d, 0xfc, 0xff], "sub dword [ebp-4], -1"),
+            (&[0x89, 0x05, 0x00, 0x01, 0x00, 0x00], "mov [0x100], eax"),
+            (&[0xb4, 0x0e], "mov ah, 0xe"),
+            (&[0xbf, 0x00, 0x80, 0x0b, 0x00], "mov edi, 0xb8000"),
+            (
+                &[0xc7, 0x45, 0xf8, 0x01, 0x00, 0x00, 0x00],
+                "mov dword [ebp-8], 1",
+            ),
+            (&[0xc8, 0x10, 0x00, 0x01], "enter 0x10, 1"),
+            (
+                &[0xdd, 0x83, 0x00, 0x01, 0x00, 0x00],
+                "fld qword [ebx+0x100]",
+            ),
+            (&[0xe8, 0xfb, 0xff, 0xff, 0xff], "call 0x7c00"),
+            (&[0xff, 0xc1], "inc ecx"),
+            (&[0xf3, 0x0f, 0xb8, 0xc1], "popcnt eax, ecx"),
+            (&[0x0f, 0xc9], "bswap ecx"),
+            (&[0x2e, 0xac], "cs lodsb"),
+            (&[0xf7, 0xc1, 0x00, 0x01, 0x00, 0x00], "test ecx, 0x100"),
+            (&[0xf7, 0xe1], "mul ecx"),
+            (
+                &[0x66, 0xc7, 0x45, 0xf8, 0x34, 0x12],
+                "mov word [ebp-8], 0x1234",
+            ),
+            (&[0x66, 0x83, 0xeb, 0x01], "sub bx, 1"),
+            (&[0x66, 0xf7, 0xc1, 0x00, 0x01], "test cx, 0x100"),
+            (
+                &[0xea, 0x00, 0x7c, 0x00, 0x00, 0x08, 0x00],
+                "jmp far 0x8:0x7c00",
+            ),
+            (&[0x67, 0x01, 0x48, 0x08], "add [bx+si+8], ecx"),
+        ];
+        for &(code, text) in cases {
+            let emu = emulator(code);
+            let instruction = emu.decode(0x7c00).unwrap();
+            assert_eq!(instruction.length as usize, code.len(), "{:02x?}", code);
+            assert_eq!(encode(&instruction), code, "{:?}", instruction);
+            assert_eq!(instruction.to_string(), text, "{:02x?}", code);
+        }
+    }
+
+    #[test]
+    fn decode_three_byte_opcode() {
+        // pshufb mm0, [ebx+8]; 0f 3a 14 /r ib (pextrb without its 0x66 prefix)
+        let emu = emulator(&[0x0f, 0x38, 0x00, 0x43, 0x08, 0x0f, 0x3a, 0x14, 0xc8, 0x02]);
+        assert_eq!(
+            emu.decode(0x7c00),
+            Err(EmulatorError::UnsupportedExtension {
+                map: 0x38,
+                opcode: 0x00,
+                length: 5
+            })
+        );
+        assert_eq!(
+            emu.decode(0x7c05),
+            Err(EmulatorError::UnsupportedExtension {
+                map: 0x3a,
+                opcode: 0x14,
+                length: 5
+            })
+        );
+    }
+
+    #[test]
+    fn decode_lea_register_operand() {
+        // lea eax, ecx
+        let emu = emulator(&[0x8d, 0xc1]);
+        assert_eq!(
+            emu.decode(0x7c00),
+            Err(EmulatorError::IllegalOperand { opcode: 0x8d })
+        );
+    }
+
+    #[test]
+    fn reader_lengths() {
+        // mov eax, 0x12345678; push 0x1000
+        let mut emu = emulator(&[0xb8, 0x78, 0x56, 0x34, 0x12, 0x68, 0x00, 0x10, 0x00, 0x00]);
+        let mut reader = InstructionReader::new(&emu, 0x7c00);
+        assert_eq!(reader.read_opcode(), Ok(0xb8));
+        assert_eq!(reader.read_u32(), Ok(0x12345678));
+        assert_eq!(reader.length(), 5);
+
+        emu.step().unwrap();
+        assert_eq!(emu.eip(), 0x7c05);
+        emu.step().unwrap();
+        assert_eq!(emu.eip(), 0x7c0a);
+    }
+
+    #[test]
+    fn decode_segment_override() {
+        // mov eax, fs:[0x10]
+        let emu = emulator(&[0x64, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00]);
+        let instruction = emu.decode(0x7c00).unwrap();
+        assert_eq!(instruction.prefixes.segment, Some(Segment::Fs));
+        assert_eq!(instruction.length, 7);
+        assert_eq!(instruction.to_string(), "mov eax, fs:[0x10]");
+    }
+
+    #[test]
+    fn decode_unknown_opcode() {
+        let emu = emulator(&[0x0f, 0x0b]);
+        assert_eq!(
+            emu.decode(0x7c00),
+            Err(EmulatorError::UnknownOpcode(0x0f0b))
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::Instruction;
+use crate::emulator::segment::Segment;
+use crate::emulator::serial::SERIAL_PORT;
+use crate::emulator::{Emulator, AH, AL, DL, EDX};
+
+/// Services `INT n` in place of an interrupt vector table. It runs with EIP
+/// already past the INT instruction; nothing is pushed on the stack.
+pub type InterruptHandler = Box<dyn FnMut(&mut Emulator) -> Result<(), EmulatorError>>;
+
+/// Handlers every emulator starts with. Output goes to the serial console.
+pub(super) fn default_handlers() -> Vec<(u8, InterruptHandler)> {
+    vec![
+        (0x10, Box::new(Emulator::bios_video)),
+        (0x20, Box::new(Emulator::dos_terminate)),
+        (0x21, Box::new(Emulator::dos_services)),
+    ]
+}
+
+impl Emulator {
+    /// Replaces the handler of `INT n`, including the built-in ones.
+    pub fn set_interrupt_handler(&mut self, n: u8, handler: InterruptHandler) {
+        self.interrupt_handlers.insert(n, handler);
+    }
+
+    /// INT imm8 (0xcd)
+    pub(super) fn int_imm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let vector = instruction.imm as u8;
+        let ah = self.get_register8(AH);
+        let mut handler = self
+            .interrupt_handlers
+            .remove(&vector)
+            .ok_or(EmulatorError::UnhandledInterrupt { vector, ah })?;
+        let result = handler(self);
+        self.interrupt_handlers.entry(vector).or_insert(handler);
+        result
+    }
+
+    /// INT 10h; only AH = 0x0e (teletype output of AL)
+    fn bios_video(&mut self) -> Result<(), EmulatorError> {
+        match self.get_register8(AH) {
+            0x0e => self.io_out8(SERIAL_PORT, self.get_register8(AL)),
+            ah => return Err(EmulatorError::UnhandledInterrupt { vector: 0x10, ah }),
+        }
+        Ok(())
+    }
+
+    /// INT 20h, and INT 21h AH = 0x4c: ends the program by jumping to 0,
+    /// which `run()` reports as `RunExit::Terminated`
+    fn dos_terminate(&mut self) -> Result<(), EmulatorError> {
+        self.set_eip(0);
+        Ok(())
+    }
+
+    /// INT 21h; AH = 0x02 writes DL, AH = 0x09 writes the '$'-terminated
+    /// string at DS:EDX, AH = 0x4c terminates
+    fn dos_services(&mut self) -> Result<(), EmulatorError> {
+        match self.get_register8(AH) {
+            0x02 => self.io_out8(SERIAL_PORT, self.get_register8(DL)),
+            0x4c => self.dos_terminate()?,
+            0x09 => {
+                let mut address = self
+                    .segment_base(Segment::Ds)
+                    .wrapping_add(self.get_register32(EDX));
+                loop {
+                    let value = self.try_get_memory8(address)?;
+                    if value == b'$' {
+                        break;
+                    }
+                    self.io_out8(SERIAL_PORT, value);
+                    address = address.wrapping_add(1);
+                }
+            }
+            ah => return Err(EmulatorError::UnhandledInterrupt { vector: 0x21, ah }),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator_with_output;
+    use crate::emulator::{RunExit, EAX};
+
+    #[test]
+    fn dos_write_character() {
+        // mov ah, 2; mov dl, 'A'; int 21h
+        let (mut emu, output) = emulator_with_output(&[0xb4, 0x02, 0xb2, 0x41, 0xcd, 0x21]);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"A");
+        assert_eq!(emu.eip(), 0x7c06);
+    }
+
+    #[test]
+    fn dos_write_string() {
+        // mov edx, 0x100; mov ah, 9; int 21h; mov ax, 0x0e21; int 10h
+        let (mut emu, output) = emulator_with_output(&[
+            0xba, 0x00, 0x01, 0x00, 0x00, 0xb4, 0x09, 0xcd, 0x21, 0x66, 0xb8, 0x21, 0x0e, 0xcd,
+            0x10,
+        ]);
+        emu.load(b"hi$", 0x100).unwrap();
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"hi!");
+    }
+
+    #[test]
+    fn dos_terminate() {
+        // mov ah, 0x4c; int 21h; hlt
+        let (mut emu, _) = emulator_with_output(&[0xb4, 0x4c, 0xcd, 0x21, 0xf4]);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert!(!emu.is_halted());
+    }
+
+    #[test]
+    fn custom_handler() {
+        // int 80h; int 81h
+        let (mut emu, _) = emulator_with_output(&[0xcd, 0x80, 0xcd, 0x81]);
+        emu.set_interrupt_handler(
+            0x80,
+            Box::new(|emu| {
+                emu.set_register32(EAX, 42);
+                Ok(())
+            }),
+        );
+        emu.step().unwrap();
+        assert_eq!(emu.get_register32(EAX), 42);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::UnhandledInterrupt {
+                vector: 0x81,
+                ah: 0
+            })
+        );
+    }
+}
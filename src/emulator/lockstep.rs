@@ -0,0 +1,79 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::Instruction;
+use crate::emulator::state::CpuState;
+use crate::emulator::Emulator;
+
+/// First point where two emulators running in lockstep disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// number of steps completed before the diverging one
+    pub step: u64,
+    /// the instruction `left` executed in the diverging step
+    pub instruction: Instruction,
+    /// state of `left` after the step
+    pub left: CpuState,
+    /// state of `right` after the step
+    pub right: CpuState,
+}
+
+/// Steps `left` and `right` together, comparing their `CpuState` after every
+/// step, until either diverges, both reach EIP 0, or `max_steps` steps ran.
+/// Returns the first divergence, if any; an error from either emulator aborts.
+pub fn lockstep(
+    left: &mut Emulator,
+    right: &mut Emulator,
+    max_steps: u64,
+) -> Result<Option<Divergence>, EmulatorError> {
+    for step in 0..max_steps {
+        if left.eip() == 0 && right.eip() == 0 {
+            break;
+        }
+        let instruction = left.decode(left.eip())?;
+        left.step()?;
+        right.step()?;
+        let (left, right) = (left.cpu_state(), right.cpu_state());
+        if left != right {
+            return Ok(Some(Divergence {
+                step,
+                instruction,
+                left,
+                right,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::EBX;
+
+    // mov eax, 1; mov ebx, 2; add ebx, eax; push 0; ret
+    const CODE: [u8; 15] = [
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0xbb, 0x02, 0x00, 0x00, 0x00, 0x01, 0xc3, 0x6a, 0x00, 0xc3,
+    ];
+
+    #[test]
+    fn same_program_does_not_diverge() {
+        let mut left = emulator(&CODE);
+        let mut right = emulator(&CODE);
+        assert_eq!(lockstep(&mut left, &mut right, 100), Ok(None));
+        assert_eq!(left.eip(), 0);
+        assert_eq!(left.registers[EBX as usize], 3);
+    }
+
+    #[test]
+    fn perturbed_copy_diverges() {
+        let mut left = emulator(&CODE);
+        let mut perturbed = CODE;
+        perturbed[6] = 0x03;
+        let mut right = emulator(&perturbed);
+        let divergence = lockstep(&mut left, &mut right, 100).unwrap().unwrap();
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.instruction.opcode, 0xbb);
+        assert_eq!(divergence.left.registers[EBX as usize], 2);
+        assert_eq!(divergence.right.registers[EBX as usize], 3);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::operand_size::CpuMode;
+use crate::emulator::port::PortDevice;
+use crate::emulator::serial::Serial;
+use crate::emulator::Emulator;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+/// default memory size: 1 MiB
+const DEFAULT_MEMORY_SIZE: usize = 0x100000;
+
+/// Why `MachineBuilder::build()` failed.
+#[derive(Debug)]
+pub enum BuildError {
+    /// the entry point, the stack or an image lies outside memory
+    Emulator(EmulatorError),
+    /// an image could not be read
+    Io(io::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Emulator(err) => write!(f, "{}", err),
+            BuildError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<EmulatorError> for BuildError {
+    fn from(err: EmulatorError) -> Self {
+        BuildError::Emulator(err)
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+/// Collects everything needed to set up an `Emulator` and builds it in one go.
+///
+/// ```no_run
+/// # use nemu::emulator::machine::MachineBuilder;
+/// let mut emu = MachineBuilder::new()
+///     .memory(0x100000)
+///     .load("boot.bin", 0x7c00)
+///     .entry(0x7c00)
+///     .build()?;
+/// # Ok::<(), nemu::emulator::machine::BuildError>(())
+/// ```
+#[derive(Default)]
+pub struct MachineBuilder {
+    memory: Option<usize>,
+    images: Vec<(PathBuf, u32)>,
+    serial: Option<Serial>,
+    devices: Vec<(u16, Box<dyn PortDevice>)>,
+    entry: u32,
+    stack: Option<u32>,
+    mode: Option<CpuMode>,
+    poison_init: bool,
+}
+
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    /// memory size in bytes; 1 MiB unless set
+    pub fn memory(mut self, size: usize) -> Self {
+        self.memory = Some(size);
+        self
+    }
+
+    /// loads the file at `path` to `base` when the machine is built
+    pub fn load(mut self, path: impl Into<PathBuf>, base: u32) -> Self {
+        self.images.push((path.into(), base));
+        self
+    }
+
+    /// connects the serial console to `reader` and `writer` instead of stdio
+    pub fn serial(mut self, reader: impl BufRead + 'static, writer: impl Write + 'static) -> Self {
+        self.serial = Some(Serial::new(Box::new(reader), Box::new(writer)));
+        self
+    }
+
+    /// attaches `device` to `port`, as `Emulator::attach_port()` does
+    pub fn device(mut self, port: u16, device: Box<dyn PortDevice>) -> Self {
+        self.devices.push((port, device));
+        self
+    }
+
+    /// initial EIP
+    pub fn entry(mut self, address: u32) -> Self {
+        self.entry = address;
+        self
+    }
+
+    /// initial ESP; the entry address unless set
+    pub fn stack(mut self, address: u32) -> Self {
+        self.stack = Some(address);
+        self
+    }
+
+    /// default operand size; `CpuMode::Protected32` unless set
+    pub fn mode(mut self, mode: CpuMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// fills registers and memory with `POISON` before images are loaded
+    pub fn poison_init(mut self, enabled: bool) -> Self {
+        self.poison_init = enabled;
+        self
+    }
+
+    /// Builds the emulator. Fails if the entry point or the stack lies outside
+    /// memory, or if an image cannot be read or does not fit in memory.
+    pub fn build(self) -> Result<Emulator, BuildError> {
+        let size = self.memory.unwrap_or(DEFAULT_MEMORY_SIZE);
+        let stack = self.stack.unwrap_or(self.entry);
+        // ESP may sit at the very end of memory since PUSH decrements first
+        for (address, limit) in [(self.entry, size), (stack, size + 1)] {
+            if address as usize >= limit {
+                return Err(EmulatorError::OutOfBounds { address }.into());
+            }
+        }
+        let mut emulator = Emulator::new(size, self.entry, stack);
+        if self.poison_init {
+            emulator.set_poison_init(true);
+            emulator.reset(self.entry, stack);
+            emulator.clear_memory();
+        }
+        if let Some(mode) = self.mode {
+            emulator.set_mode(mode);
+        }
+        for (path, base) in &self.images {
+            let image = std::fs::read(path)?;
+            emulator.load(&image, *base)?;
+        }
+        if let Some(serial) = self.serial {
+            emulator.set_serial(serial);
+        }
+        for (port, device) in self.devices {
+            emulator.attach_port(port, device);
+        }
+        Ok(emulator)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::SharedBuffer;
+    use crate::emulator::RunExit;
+
+    /// Port device that always reads as the same byte.
+    struct Constant(u8);
+
+    impl PortDevice for Constant {
+        fn in8(&mut self, _port: u16) -> u8 {
+            self.0
+        }
+        fn out8(&mut self, _port: u16, _value: u8) {}
+    }
+
+    #[test]
+    fn build_and_run() {
+        // mov edx, 0x3f8; in al, dx; out dx, al; in al, 0x60; out dx, al; ret
+        let code = [
+            0xba, 0xf8, 0x03, 0x00, 0x00, 0xec, 0xee, 0xe4, 0x60, 0xee, 0xc3,
+        ];
+        let path = std::env::temp_dir().join(format!("nemu-machine-{}.bin", std::process::id()));
+        std::fs::write(&path, code).unwrap();
+        let output = SharedBuffer::default();
+
+        let mut emu = MachineBuilder::new()
+            .memory(0x10000)
+            .load(&path, 0x7c00)
+            .serial(&b"hello\n"[..], output.clone())
+            .device(0x60, Box::new(Constant(b'!')))
+            .entry(0x7c00)
+            .stack(0x8000)
+            .build()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(*output.0.borrow(), b"h!");
+    }
+
+    #[test]
+    fn entry_and_stack_in_memory() {
+        let emu = MachineBuilder::new()
+            .memory(0x10000)
+            .entry(0x7c00)
+            .stack(0x10000)
+            .mode(CpuMode::Real16)
+            .build()
+            .unwrap();
+        assert_eq!(emu.eip(), 0x7c00);
+        assert_eq!(emu.mode(), CpuMode::Real16);
+
+        let result = MachineBuilder::new()
+            .memory(0x10000)
+            .entry(0x7c00)
+            .stack(0x10004)
+            .build();
+        assert!(matches!(
+            result,
+            Err(BuildError::Emulator(EmulatorError::OutOfBounds {
+                address: 0x10004
+            }))
+        ));
+        // the stack defaults to the entry point, which must be inside memory
+        assert!(MachineBuilder::new()
+            .memory(0x100)
+            .entry(0x100)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn image_too_large() {
+        let path = std::env::temp_dir().join(format!("nemu-large-{}.bin", std::process::id()));
+        std::fs::write(&path, [0x90; 0x20]).unwrap();
+        let result = MachineBuilder::new().memory(0x10).load(&path, 0).build();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(BuildError::Emulator(EmulatorError::OutOfBounds { .. }))
+        ));
+    }
+}
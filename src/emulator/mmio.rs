@@ -0,0 +1,101 @@
+use crate::emulator::Emulator;
+use std::cell::RefCell;
+use std::ops::Range;
+
+/// A device whose registers are mapped into memory with `map_io()`.
+/// Offsets are relative to the start of the mapped range.
+pub trait IoDevice {
+    fn read(&mut self, offset: u32) -> u8;
+    fn write(&mut self, offset: u32, value: u8);
+}
+
+/// A mapped range and the device behind it. Reads go through `&Emulator`,
+/// hence the `RefCell`.
+pub(super) struct MappedDevice {
+    range: Range<u32>,
+    device: RefCell<Box<dyn IoDevice>>,
+}
+
+impl Emulator {
+    /// Routes byte accesses to physical addresses in `range` to `device`
+    /// instead of memory. The range may lie past the end of memory. Earlier
+    /// mappings win where ranges overlap.
+    pub fn map_io(&mut self, range: Range<u32>, device: Box<dyn IoDevice>) {
+        self.mmio.push(MappedDevice {
+            range,
+            device: RefCell::new(device),
+        });
+    }
+
+    /// The device mapped at physical address `index` and the offset into it.
+    fn mapped_device(&self, index: usize) -> Option<(&MappedDevice, u32)> {
+        let address = u32::try_from(index).ok()?;
+        self.mmio
+            .iter()
+            .find(|mapped| mapped.range.contains(&address))
+            .map(|mapped| (mapped, address - mapped.range.start))
+    }
+
+    pub(super) fn is_mapped(&self, index: usize) -> bool {
+        self.mapped_device(index).is_some()
+    }
+
+    pub(super) fn mmio_read(&self, index: usize) -> Option<u8> {
+        let (mapped, offset) = self.mapped_device(index)?;
+        Some(mapped.device.borrow_mut().read(offset))
+    }
+
+    /// Returns false if nothing is mapped at `index`.
+    pub(super) fn mmio_write(&self, index: usize, value: u8) -> bool {
+        match self.mapped_device(index) {
+            Some((mapped, offset)) => {
+                mapped.device.borrow_mut().write(offset, value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::{EAX, EBX};
+    use std::rc::Rc;
+
+    struct Recorder(Rc<RefCell<Vec<(u32, u8)>>>);
+
+    impl IoDevice for Recorder {
+        fn read(&mut self, offset: u32) -> u8 {
+            0x80 | offset as u8
+        }
+        fn write(&mut self, offset: u32, value: u8) {
+            self.0.borrow_mut().push((offset, value));
+        }
+    }
+
+    #[test]
+    fn mapped_range() {
+        // mov [0x1000], eax; mov bl, [0x1002]; mov [0x1004], bl
+        let code = [
+            0x89, 0x05, 0x00, 0x10, 0x00, 0x00, 0x8a, 0x1d, 0x02, 0x10, 0x00, 0x00, 0x88, 0x1d,
+            0x04, 0x10, 0x00, 0x00,
+        ];
+        let mut emu = emulator(&code);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        emu.map_io(0x1000..0x1004, Box::new(Recorder(writes.clone())));
+        emu.registers[EAX as usize] = 0x44332211;
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(
+            *writes.borrow(),
+            vec![(0, 0x11), (1, 0x22), (2, 0x33), (3, 0x44)]
+        );
+        assert_eq!(emu.memory[0x1000..0x1004], [0; 4]);
+        assert_eq!(emu.registers[EBX as usize], 0x82);
+        // just past the range is plain memory
+        assert_eq!(emu.memory[0x1004], 0x82);
+    }
+}
@@ -1,6 +1,6 @@
 use bit_field::BitField;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ModRM {
     /// mod
     pub md: u8,
@@ -12,6 +12,8 @@ pub struct ModRM {
     pub sib: u8,
     /// displacement
     pub disp: i32,
+    /// 16-bit addressing: no SIB, a disp16 and the `[bx+si]`-style bases
+    pub address16: bool,
 }
 
 impl ModRM {
@@ -22,6 +24,15 @@ impl ModRM {
             rm: code.get_bits(0..3),
             sib: 0,
             disp: 0,
+            address16: false,
+        }
+    }
+
+    /// Parses a ModRM byte with 16-bit addressing.
+    pub fn from_code16(code: u8) -> ModRM {
+        ModRM {
+            address16: true,
+            ..ModRM::from_code(code)
         }
     }
 
@@ -30,15 +41,39 @@ impl ModRM {
     }
 
     pub fn has_sib(&self) -> bool {
-        self.md != 3 && self.rm == 0b100
+        !self.address16 && self.md != 3 && self.rm == 0b100
     }
 
     pub fn has_disp8(&self) -> bool {
         self.md == 0b01
     }
 
+    /// Also true for a SIB with base = 5 under mod = 0, so call it after `set_sib()`.
     pub fn has_disp32(&self) -> bool {
-        self.md == 0b10 || (self.md == 0b00 && self.rm == 0b101)
+        !self.address16
+            && (self.md == 0b10
+                || (self.md == 0b00 && self.rm == 0b101)
+                || (self.md == 0b00 && self.has_sib() && self.base() == 0b101))
+    }
+
+    /// 16-bit addressing only; rm = 6 under mod = 0 is disp16 without a base
+    pub fn has_disp16(&self) -> bool {
+        self.address16 && (self.md == 0b10 || (self.md == 0b00 && self.rm == 0b110))
+    }
+
+    /// SIB scale as a shift amount (index * 1, 2, 4 or 8)
+    pub fn scale(&self) -> u8 {
+        self.sib.get_bits(6..8)
+    }
+
+    /// SIB index register; 4 means no index
+    pub fn index(&self) -> u8 {
+        self.sib.get_bits(3..6)
+    }
+
+    /// SIB base register; 5 under mod = 0 means disp32 without a base
+    pub fn base(&self) -> u8 {
+        self.sib.get_bits(0..3)
     }
 
     pub fn set_sib(&mut self, sib: u8) {
@@ -49,9 +84,29 @@ impl ModRM {
         self.disp = disp as i32;
     }
 
+    pub fn set_disp16(&mut self, disp: i16) {
+        self.disp = disp as i32;
+    }
+
     pub fn set_disp32(&mut self, disp: i32) {
         self.disp = disp;
     }
+
+    /// Encodes back into ModRM, SIB and displacement bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.md << 6 | self.op << 3 | self.rm];
+        if self.has_sib() {
+            bytes.push(self.sib);
+        }
+        if self.has_disp32() {
+            bytes.extend_from_slice(&self.disp.to_le_bytes());
+        } else if self.has_disp16() {
+            bytes.extend_from_slice(&(self.disp as i16).to_le_bytes());
+        } else if self.has_disp8() {
+            bytes.push(self.disp as u8);
+        }
+        bytes
+    }
 }
 
 #[cfg(test)]
@@ -68,4 +123,36 @@ mod test {
         assert!(!modrm.has_disp32());
         assert!(!modrm.has_disp8());
     }
+
+    #[test]
+    fn parse_sib() {
+        let mut modrm = ModRM::from_code(0x04);
+        modrm.set_sib(0xf5);
+        assert_eq!((modrm.scale(), modrm.index(), modrm.base()), (3, 6, 5));
+        assert!(modrm.has_disp32());
+    }
+
+    #[test]
+    fn to_bytes() {
+        let mut modrm = ModRM::from_code(0x43);
+        modrm.set_disp8(-4);
+        assert_eq!(modrm.to_bytes(), vec![0x43, 0xfc]);
+        let mut modrm = ModRM::from_code(0x05);
+        modrm.set_disp32(0x100);
+        assert_eq!(modrm.to_bytes(), vec![0x05, 0x00, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn parse_modrm16() {
+        // [si] has no SIB, [0x100] and [bp+si+0x100] take a disp16
+        let modrm = ModRM::from_code16(0x04);
+        assert!(!modrm.has_sib() && !modrm.has_disp16());
+        let mut modrm = ModRM::from_code16(0x06);
+        assert!(modrm.has_disp16() && !modrm.has_disp32());
+        modrm.set_disp16(0x100);
+        assert_eq!(modrm.to_bytes(), vec![0x06, 0x00, 0x01]);
+        let mut modrm = ModRM::from_code16(0x82);
+        modrm.set_disp16(-2);
+        assert_eq!(modrm.to_bytes(), vec![0x82, 0xfe, 0xff]);
+    }
 }
@@ -0,0 +1,94 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::{Emulator, EAX, ECX, EDX};
+
+impl Emulator {
+    /// Models the MSR `index`; RDMSR and WRMSR only work on modelled MSRs.
+    pub fn set_msr(&mut self, index: u32, value: u64) {
+        self.msrs.insert(index, value);
+    }
+
+    pub fn get_msr(&self, index: u32) -> Option<u64> {
+        self.msrs.get(&index).copied()
+    }
+
+    /// RDMSR: EDX:EAX = MSR[ECX]
+    pub(super) fn rdmsr(&mut self) -> Result<(), EmulatorError> {
+        let index = self.get_register32(ECX);
+        let value = self
+            .get_msr(index)
+            .ok_or(EmulatorError::PrivilegedInstruction {
+                opcode: 0x0f32,
+                index,
+            })?;
+        self.set_register32(EAX, value as u32);
+        self.set_register32(EDX, (value >> 32) as u32);
+        Ok(())
+    }
+
+    /// WRMSR: MSR[ECX] = EDX:EAX
+    pub(super) fn wrmsr(&mut self) -> Result<(), EmulatorError> {
+        let index = self.get_register32(ECX);
+        let value = (self.get_register32(EDX) as u64) << 32 | self.get_register32(EAX) as u64;
+        match self.msrs.get_mut(&index) {
+            Some(msr) => {
+                *msr = value;
+                Ok(())
+            }
+            None => Err(EmulatorError::PrivilegedInstruction {
+                opcode: 0x0f30,
+                index,
+            }),
+        }
+    }
+
+    /// RDPMC: performance counters are not modelled, so this always fails.
+    pub(super) fn rdpmc(&mut self) -> Result<(), EmulatorError> {
+        Err(EmulatorError::PrivilegedInstruction {
+            opcode: 0x0f33,
+            index: self.get_register32(ECX),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+
+    #[test]
+    fn rdmsr_wrmsr() {
+        // rdmsr; wrmsr; rdmsr
+        let mut emu = emulator(&[0x0f, 0x32, 0x0f, 0x30, 0x0f, 0x32]);
+        emu.set_msr(0x10, 0x1122334455667788);
+        emu.registers[ECX as usize] = 0x10;
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0x55667788);
+        assert_eq!(emu.registers[EDX as usize], 0x11223344);
+
+        emu.registers[EAX as usize] = 1;
+        emu.step().unwrap();
+        assert_eq!(emu.get_msr(0x10), Some(0x1122334400000001));
+
+        emu.registers[ECX as usize] = 0x1b;
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::PrivilegedInstruction {
+                opcode: 0x0f32,
+                index: 0x1b
+            })
+        );
+        assert_eq!(emu.eip(), 0x7c06);
+    }
+
+    #[test]
+    fn rdpmc() {
+        let mut emu = emulator(&[0x0f, 0x33]);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::PrivilegedInstruction {
+                opcode: 0x0f33,
+                index: 0
+            })
+        );
+    }
+}
@@ -0,0 +1,946 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::Instruction;
+use crate::emulator::modrm::ModRM;
+use crate::emulator::segment::Segment;
+use crate::emulator::{
+    Emulator, FlagOp, OperandWidth, CL, EAX, EBP, EDI, EDX, EFLAGS_RESERVED, ESI, ESP, POPFD_MASK,
+};
+use bit_field::BitField;
+use std::num::Wrapping;
+
+/// Default operand and address size. 0x66 and 0x67 switch to the other one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuMode {
+    /// 16-bit operands and addressing by default, as in real-mode programs
+    Real16,
+    /// 32-bit operands and addressing by default
+    Protected32,
+}
+
+/// 16-bit forms selected by the operand size. Only the opcodes listed in
+/// `execute16()` are decoded with 16-bit operands; those in
+/// `ignores_operand_size()` run from the 32-bit tables either way.
+impl Emulator {
+    pub fn mode(&self) -> CpuMode {
+        self.mode
+    }
+
+    /// Sets the default operand and address size; `CpuMode::Protected32` unless changed.
+    /// `reset()` keeps it.
+    pub fn set_mode(&mut self, mode: CpuMode) {
+        self.mode = mode;
+    }
+
+    /// Opcodes whose operands do not depend on the operand size: byte
+    /// operations, short jumps and LOOP, flag and port byte I/O, INT, HLT
+    /// and the MSR instructions. BSWAP is listed too; its 16-bit form is
+    /// undefined and swaps the whole register here.
+    pub(super) fn ignores_operand_size(opcode: u16) -> bool {
+        matches!(
+            opcode,
+            0x00 | 0x02
+                | 0x28
+                | 0x38
+                | 0x3a
+                | 0x3c
+                | 0x70..=0x7f
+                | 0x80
+                | 0x84
+                | 0x86
+                | 0x88
+                | 0x8a
+                | 0x9b
+                | 0xa0
+                | 0xa2
+                | 0xa4
+                | 0xa8
+                | 0xaa
+                | 0xac
+                | 0xb0..=0xb7
+                | 0xcd
+                | 0xd8..=0xdf
+                | 0xe0..=0xe4
+                | 0xe6
+                | 0xeb
+                | 0xec
+                | 0xee
+                | 0xf4
+                | 0xfc
+                | 0xfd
+                | 0xfe
+                | 0x0f30
+                | 0x0f32
+                | 0x0f33
+                | 0x0f90..=0x0f9f
+                | 0x0fc8..=0x0fcf
+        )
+    }
+
+    pub(super) fn execute16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        match instruction.opcode {
+            0x01 | 0x09 | 0x0b | 0x11 | 0x13 | 0x19 | 0x1b | 0x21 | 0x23 | 0x29 | 0x2b | 0x31
+            | 0x33 | 0x3b => return self.alu_rm16_r16(instruction),
+            0x3d => self.cmp_ax_imm16(instruction),
+            0x40..=0x47 => self.inc_r16(instruction),
+            0x48..=0x4f => self.dec_r16(instruction),
+            0x50..=0x57 => return self.push_r16(instruction),
+            0x58..=0x5f => return self.pop_r16(instruction),
+            0x68 | 0x6a => return self.push16(instruction.imm as u16),
+            0x69 | 0x6b => return self.imul_r16_rm16_imm(instruction),
+            0x81 | 0x83 => return self.code_81_16(instruction),
+            0x85 => return self.test_rm16_r16(instruction),
+            0x87 => return self.xchg_rm16_r16(instruction),
+            0x89 => return self.mov_rm16_r16(instruction),
+            0x90 | 0x0f1f => {}
+            // MOVZX/MOVSX r16, r/m16 are plain moves
+            0x8b | 0x0fb7 | 0x0fbf => return self.mov_r16_rm16(instruction),
+            0x8c => return self.mov_rm16_sreg(instruction),
+            0x8d => self.lea_r16_m(instruction),
+            0x8e => return self.mov_sreg_rm16(instruction),
+            0x91..=0x97 => self.xchg_ax_r16(instruction),
+            0x98 => self.cbw(),
+            0x99 => self.cwd(),
+            0x9a => return self.call_far16(instruction),
+            0x9c => return self.pushf(),
+            0x9d => return self.popf(),
+            0xa1 => return self.mov_ax_moffs16(instruction),
+            0xa3 => return self.mov_moffs16_ax(instruction),
+            0xa5 => return self.repeat(instruction, Self::movs_m16),
+            0xa9 => self.test_ax_imm16(instruction),
+            0xab => return self.repeat(instruction, Self::stos_m16),
+            0xad => return self.repeat(instruction, Self::lods_m16),
+            0xb8..=0xbf => self.mov_r16_imm16(instruction),
+            0xc1 => return self.shift_rm16(instruction, instruction.imm),
+            0xc2 => return self.ret16_imm16(instruction),
+            0xc3 => return self.ret16(),
+            0xc7 => return self.mov_rm16_imm16(instruction),
+            0xc8 => return self.enter16(instruction),
+            0xc9 => return self.leave16(),
+            0xd1 => return self.shift_rm16(instruction, 1),
+            0xd3 => return self.shift_rm16(instruction, self.get_register8(CL) as u32),
+            0xe5 => self.in_ax_imm8(instruction),
+            0xe7 => self.out_imm8_ax(instruction),
+            0xe8 => return self.call_rel16(instruction),
+            0xe9 => self.jmp_rel16(instruction),
+            0xea => self.jmp_far(instruction),
+            0xed => self.in_ax_dx(),
+            0xef => self.out_dx_ax(),
+            0xf7 => return self.code_f7_16(instruction),
+            0xff => return self.code_ff_16(instruction),
+            0x0f01 => return self.code_0f01(instruction),
+            0x0f40..=0x0f4f => return self.cmovcc16(instruction),
+            0x0f80..=0x0f8f => self.jcc_rel16(instruction),
+            0x0fa3 | 0x0fab | 0x0fb3 | 0x0fbb => return self.bit_rm16_r16(instruction),
+            0x0faf => return self.imul_r16_rm16(instruction),
+            0x0fb6 => return self.movzx_r16_rm8(instruction),
+            0x0fb8 => return self.popcnt16(instruction),
+            0x0fba => return self.code_0fba_16(instruction),
+            0x0fbc | 0x0fbd => return self.bit_scan16(instruction),
+            0x0fbe => return self.movsx_r16_rm8(instruction),
+            opcode => return Err(EmulatorError::UnknownOpcode(opcode)),
+        }
+        Ok(())
+    }
+
+    /// ADD, OR, ADC, SBB, AND, SUB, XOR or CMP (`op`, in ModRM /digit
+    /// order) of two words. Returns the result, which CMP does not store.
+    fn alu16(&mut self, op: u8, v1: u16, v2: u16) -> u16 {
+        let carry = self.get_carry() as u32;
+        match op {
+            0 | 2 => {
+                let carry = if op == 2 { carry } else { 0 };
+                let result = v1 as u32 + v2 as u32 + carry;
+                self.update_eflags_add16(v1, v2, result);
+                result as u16
+            }
+            3 | 5 | 7 => {
+                let carry = if op == 3 { carry } else { 0 };
+                let result = (v1 as u32).wrapping_sub(v2 as u32).wrapping_sub(carry);
+                self.update_eflags_sub16(v1, v2, result);
+                result as u16
+            }
+            _ => {
+                let result = match op {
+                    1 => v1 | v2,
+                    4 => v1 & v2,
+                    _ => v1 ^ v2,
+                };
+                self.update_eflags_logic16(result);
+                result
+            }
+        }
+    }
+    /// ALU r/m16, r16 and, with opcode bit 1 set, r16, r/m16; bits 3..5 of
+    /// the opcode select the operation.
+    fn alu_rm16_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let op = (instruction.opcode >> 3 & 0b111) as u8;
+        let r16 = self.get_r16(modrm);
+        let rm16 = self.get_rm16(modrm)?;
+        if instruction.opcode & 0b10 == 0 {
+            let result = self.alu16(op, rm16, r16);
+            if op != 7 {
+                self.set_rm16(modrm, result)?;
+            }
+        } else {
+            let result = self.alu16(op, r16, rm16);
+            if op != 7 {
+                self.set_r16(modrm, result);
+            }
+        }
+        Ok(())
+    }
+    fn cmp_ax_imm16(&mut self, instruction: &Instruction) {
+        let ax = self.get_register16(EAX);
+        let imm16 = instruction.imm as u16;
+        let result = (ax as u32).wrapping_sub(imm16 as u32);
+        self.update_eflags_sub16(ax, imm16, result);
+    }
+    /// 0x81 /op r/m16, imm16 and 0x83 /op r/m16, imm8 (sign-extended)
+    fn code_81_16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        let result = self.alu16(modrm.op, rm16, instruction.imm as u16);
+        if modrm.op == 7 {
+            Ok(())
+        } else {
+            self.set_rm16(modrm, result)
+        }
+    }
+    fn test_rm16_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.get_rm16(modrm)? & self.get_r16(modrm);
+        self.update_eflags_logic16(result);
+        Ok(())
+    }
+    fn test_ax_imm16(&mut self, instruction: &Instruction) {
+        let result = self.get_register16(EAX) & instruction.imm as u16;
+        self.update_eflags_logic16(result);
+    }
+
+    /// INC and DEC leave CF alone, as their other forms do.
+    fn inc16(&mut self, value: u16) -> u16 {
+        let result = value as u32 + 1;
+        let carry = self.get_carry();
+        self.update_eflags_add16(value, 1, result);
+        self.set_carry(carry);
+        result as u16
+    }
+    fn dec16(&mut self, value: u16) -> u16 {
+        let result = (value as u32).wrapping_sub(1);
+        let carry = self.get_carry();
+        self.update_eflags_sub16(value, 1, result);
+        self.set_carry(carry);
+        result as u16
+    }
+    fn inc_r16(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let result = self.inc16(self.get_register16(reg));
+        self.set_register16(reg, result);
+    }
+    fn dec_r16(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let result = self.dec16(self.get_register16(reg));
+        self.set_register16(reg, result);
+    }
+
+    /// 0xf7 group: TEST r/m16, imm16, NOT, NEG, and MUL, IMUL, DIV, IDIV
+    /// on DX:AX.
+    fn code_f7_16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        match modrm.op {
+            0 => self.update_eflags_logic16(rm16 & instruction.imm as u16),
+            2 => self.set_rm16(modrm, !rm16)?,
+            3 => {
+                let result = 0u32.wrapping_sub(rm16 as u32);
+                self.update_eflags_sub16(0, rm16, result);
+                self.set_rm16(modrm, result as u16)?;
+            }
+            4 => {
+                let result = self.get_register16(EAX) as u32 * rm16 as u32;
+                self.set_register16(EAX, result as u16);
+                self.set_register16(EDX, (result >> 16) as u16);
+                let upper = result >> 16 != 0;
+                self.set_carry(upper);
+                self.set_overflow(upper);
+            }
+            5 => {
+                let result = self.imul16(self.get_register16(EAX), rm16);
+                self.set_register16(EAX, result as u16);
+                self.set_register16(EDX, (result >> 16) as u16);
+            }
+            6 => return self.div16(rm16),
+            7 => return self.idiv16(rm16),
+            op => {
+                return Err(EmulatorError::UnimplementedModRM {
+                    opcode: instruction.opcode,
+                    op,
+                })
+            }
+        }
+        Ok(())
+    }
+    /// IMUL r16, r/m16, imm16 (0x69) and imm8 (0x6b)
+    fn imul_r16_rm16_imm(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.imul16(self.get_rm16(modrm)?, instruction.imm as u16);
+        self.set_r16(modrm, result as u16);
+        Ok(())
+    }
+    /// IMUL r16, r/m16 (0x0f 0xaf)
+    fn imul_r16_rm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let result = self.imul16(self.get_r16(modrm), self.get_rm16(modrm)?);
+        self.set_r16(modrm, result as u16);
+        Ok(())
+    }
+    /// Signed 32-bit product; CF and OF tell whether it is wider than 16 bits.
+    fn imul16(&mut self, v1: u16, v2: u16) -> i32 {
+        let result = v1 as i16 as i32 * v2 as i16 as i32;
+        let truncated = result != result as i16 as i32;
+        self.set_carry(truncated);
+        self.set_overflow(truncated);
+        result
+    }
+    /// AX = DX:AX / divisor, DX = remainder, with the same divide errors as
+    /// `div_rm32()`.
+    fn div16(&mut self, divisor: u16) -> Result<(), EmulatorError> {
+        if divisor == 0 {
+            return Err(EmulatorError::DivideByZero);
+        }
+        let dividend = (self.get_register16(EDX) as u32) << 16 | self.get_register16(EAX) as u32;
+        let quotient = dividend / divisor as u32;
+        if quotient > u16::MAX as u32 {
+            return Err(EmulatorError::DivideOverflow);
+        }
+        self.set_register16(EAX, quotient as u16);
+        self.set_register16(EDX, (dividend % divisor as u32) as u16);
+        Ok(())
+    }
+    /// Signed `div16()`
+    fn idiv16(&mut self, divisor: u16) -> Result<(), EmulatorError> {
+        let divisor = divisor as i16 as i32;
+        if divisor == 0 {
+            return Err(EmulatorError::DivideByZero);
+        }
+        let dividend =
+            ((self.get_register16(EDX) as u32) << 16 | self.get_register16(EAX) as u32) as i32;
+        let quotient = dividend
+            .checked_div(divisor)
+            .filter(|&quotient| quotient == quotient as i16 as i32)
+            .ok_or(EmulatorError::DivideOverflow)?;
+        self.set_register16(EAX, quotient as u16);
+        self.set_register16(EDX, (dividend % divisor) as u16);
+        Ok(())
+    }
+
+    /// 16-bit `shift_rm32()`. The count is still masked to 5 bits, so RCL
+    /// and RCR rotate the 17-bit CF:r/m16 and shifts may clear the operand.
+    fn shift_rm16(&mut self, instruction: &Instruction, count: u32) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let count = count & 0x1f;
+        let rm16 = self.get_rm16(modrm)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let (result, carry) = match modrm.op {
+            0 => {
+                let result = rm16.rotate_left(count);
+                (result, result.get_bit(0))
+            }
+            1 => {
+                let result = rm16.rotate_right(count);
+                (result, result.get_bit(15))
+            }
+            2 | 3 => {
+                let wide = (self.get_carry() as u64) << 16 | rm16 as u64;
+                let count = count % 17;
+                let rotated = if modrm.op == 2 {
+                    wide << count | wide >> (17 - count)
+                } else {
+                    wide >> count | wide << (17 - count)
+                };
+                (rotated as u16, rotated.get_bit(16))
+            }
+            5 => {
+                let value = rm16 as u32;
+                ((value >> count) as u16, (value >> (count - 1)).get_bit(0))
+            }
+            7 => {
+                let signed = rm16 as i16 as i32;
+                ((signed >> count) as u16, (signed >> (count - 1)).get_bit(0))
+            }
+            _ => {
+                let wide = (rm16 as u64) << count;
+                (wide as u16, wide.get_bit(16))
+            }
+        };
+        // only defined for 1-bit shifts and rotates
+        let overflow = match modrm.op {
+            1 | 3 => result.get_bit(15) != result.get_bit(14),
+            5 => rm16.get_bit(15),
+            7 => false,
+            _ => result.get_bit(15) != carry,
+        };
+        self.set_rm16(modrm, result)?;
+        self.set_carry(carry);
+        self.set_overflow(overflow);
+        if modrm.op >= 4 {
+            // rotates leave ZF, SF and PF alone
+            self.set_zero(result == 0);
+            self.set_sign(result.get_bit(15));
+            self.set_parity(Self::even_parity(result as u8));
+        }
+        Ok(())
+    }
+
+    fn mov_rm16_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r16 = self.get_r16(modrm);
+        self.set_rm16(modrm, r16)
+    }
+    fn mov_r16_rm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        self.set_r16(modrm, rm16);
+        Ok(())
+    }
+    fn mov_r16_imm16(&mut self, instruction: &Instruction) {
+        self.set_register16(instruction.opcode_register(), instruction.imm as u16);
+    }
+    fn mov_rm16_imm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.set_rm16(instruction.modrm(), instruction.imm as u16)
+    }
+
+    fn xchg_rm16_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let r16 = self.get_r16(modrm);
+        let rm16 = self.get_rm16(modrm)?;
+        self.set_r16(modrm, rm16);
+        self.set_rm16(modrm, r16)
+    }
+    /// XCHG AX, r16 (0x91..0x97)
+    fn xchg_ax_r16(&mut self, instruction: &Instruction) {
+        let reg = instruction.opcode_register();
+        let ax = self.get_register16(EAX);
+        self.set_register16(EAX, self.get_register16(reg));
+        self.set_register16(reg, ax);
+    }
+    /// CBW: AX = AL, sign-extended
+    fn cbw(&mut self) {
+        let al = self.get_register32(EAX) as i8;
+        self.set_register16(EAX, al as u16);
+    }
+    /// CWD: DX = the sign of AX
+    fn cwd(&mut self) {
+        let ax = self.get_register16(EAX) as i16;
+        self.set_register16(EDX, (ax >> 15) as u16);
+    }
+    /// LEA r16, m keeps the low word of the effective address.
+    fn lea_r16_m(&mut self, instruction: &Instruction) {
+        let modrm = instruction.modrm();
+        let address = self.calc_memory_address(modrm);
+        self.set_r16(modrm, address as u16);
+    }
+    fn mov_ax_moffs16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory16(instruction.imm)?;
+        self.set_register16(EAX, value);
+        Ok(())
+    }
+    fn mov_moffs16_ax(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.try_set_memory16(instruction.imm, self.get_register16(EAX))
+    }
+    fn movzx_r16_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm8(modrm)? as u16;
+        self.set_r16(modrm, value);
+        Ok(())
+    }
+    fn movsx_r16_rm8(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let value = self.get_rm8(modrm)? as i8 as u16;
+        self.set_r16(modrm, value);
+        Ok(())
+    }
+    /// CMOVcc r16, r/m16, reading the source either way like `cmovcc()`
+    fn cmovcc16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        if self.condition((instruction.opcode & 0xf) as u8) {
+            self.set_r16(modrm, rm16);
+        }
+        Ok(())
+    }
+
+    fn movs_m16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.try_get_memory16(self.string_source_address(instruction))?;
+        self.try_set_memory16(self.string_destination_address(instruction), value)?;
+        self.advance_string_index(instruction, ESI, 2);
+        self.advance_string_index(instruction, EDI, 2);
+        Ok(())
+    }
+    fn stos_m16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_destination_address(instruction);
+        self.try_set_memory16(address, self.get_register16(EAX))?;
+        self.advance_string_index(instruction, EDI, 2);
+        Ok(())
+    }
+    fn lods_m16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let address = self.string_source_address(instruction);
+        let value = self.try_get_memory16(address)?;
+        self.set_register16(EAX, value);
+        self.advance_string_index(instruction, ESI, 2);
+        Ok(())
+    }
+
+    /// 16-bit `bit_rm32_r32()`; a memory operand is addressed in words.
+    fn bit_rm16_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let op = 4 + (instruction.opcode >> 3 & 0b11) as u8;
+        let offset = self.get_r16(modrm);
+        let bit = (offset % 16) as u32;
+        if modrm.is_reg() {
+            let value = self.get_register16(modrm.rm) as u32;
+            if let Some(result) = self.bit_operation(op, value, bit) {
+                self.set_register16(modrm.rm, result as u16);
+            }
+        } else {
+            let address = self
+                .calc_memory_address(modrm)
+                .wrapping_add(((offset as i16 >> 4) * 2) as u32);
+            let value = self.try_get_memory16(address)? as u32;
+            if let Some(result) = self.bit_operation(op, value, bit) {
+                self.try_set_memory16(address, result as u16)?;
+            }
+        }
+        Ok(())
+    }
+    /// 16-bit `code_0fba()`; the bit index is taken modulo 16.
+    fn code_0fba_16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        if modrm.op < 4 {
+            return Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op: modrm.op,
+            });
+        }
+        let rm16 = self.get_rm16(modrm)? as u32;
+        if let Some(result) = self.bit_operation(modrm.op, rm16, instruction.imm % 16) {
+            self.set_rm16(modrm, result as u16)?;
+        }
+        Ok(())
+    }
+    /// 16-bit `bit_scan()`
+    fn bit_scan16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        self.set_zero(rm16 == 0);
+        if rm16 != 0 {
+            let index = if instruction.opcode == 0x0fbc {
+                rm16.trailing_zeros()
+            } else {
+                15 - rm16.leading_zeros()
+            };
+            self.set_r16(modrm, index as u16);
+        }
+        Ok(())
+    }
+    fn popcnt16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let rm16 = self.get_rm16(modrm)?;
+        self.set_r16(modrm, rm16.count_ones() as u16);
+        self.update_eflags_popcnt(rm16 == 0);
+        Ok(())
+    }
+
+    fn push_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.get_register16(instruction.opcode_register());
+        self.push16(value)
+    }
+    fn pop_r16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let value = self.pop16()?;
+        self.set_register16(instruction.opcode_register(), value);
+        Ok(())
+    }
+    fn push16(&mut self, value: u16) -> Result<(), EmulatorError> {
+        let address = self.get_register32(ESP).wrapping_sub(2);
+        self.try_set_memory16(address, value)?;
+        self.set_register32(ESP, address);
+        Ok(())
+    }
+    fn pop16(&mut self) -> Result<u16, EmulatorError> {
+        let address = self.get_register32(ESP);
+        let value = self.try_get_memory16(address)?;
+        self.set_register32(ESP, address.wrapping_add(2));
+        Ok(value)
+    }
+
+    /// PUSHF (0x9c): the low word of EFLAGS
+    fn pushf(&mut self) -> Result<(), EmulatorError> {
+        self.push16((self.eflags | EFLAGS_RESERVED) as u16)
+    }
+    /// POPF (0x9d) only changes the low word of EFLAGS.
+    fn popf(&mut self) -> Result<(), EmulatorError> {
+        let value = self.pop16()? as u32;
+        let mask = POPFD_MASK & 0xffff;
+        self.eflags = self.eflags & !mask | value & mask;
+        Ok(())
+    }
+
+    /// 16-bit `enter()`: BP and the copied frame pointers are words.
+    fn enter16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let size = instruction.imm & 0xffff;
+        let level = (instruction.imm >> 16) & 0x1f;
+        self.push16(self.get_register16(EBP))?;
+        let frame = self.get_register32(ESP);
+        if level > 0 {
+            let mut bp = self.get_register32(EBP);
+            for _ in 1..level {
+                bp = bp.wrapping_sub(2);
+                self.push16(self.try_get_memory16(bp)?)?;
+            }
+            self.push16(frame as u16)?;
+        }
+        self.set_register16(EBP, frame as u16);
+        let esp = self.get_register32(ESP).wrapping_sub(size);
+        self.set_register32(ESP, esp);
+        Ok(())
+    }
+    fn leave16(&mut self) -> Result<(), EmulatorError> {
+        self.set_register32(ESP, self.get_register32(EBP));
+        let bp = self.pop16()?;
+        self.set_register16(EBP, bp);
+        Ok(())
+    }
+
+    /// CALL rel16 (0xe8); IP wraps within 64 KiB.
+    fn call_rel16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push16(self.eip.0 as u16)?;
+        self.jmp_rel16(instruction);
+        Ok(())
+    }
+    /// JMP rel16 (0xe9)
+    fn jmp_rel16(&mut self, instruction: &Instruction) {
+        let ip = (self.eip.0 as u16).wrapping_add(instruction.imm as u16);
+        self.eip = Wrapping(ip as u32);
+    }
+    /// RET (0xc3) popping a 16-bit IP
+    fn ret16(&mut self) -> Result<(), EmulatorError> {
+        let ip = self.pop16()?;
+        self.eip = Wrapping(ip as u32);
+        Ok(())
+    }
+
+    /// RET imm16 (0xc2) popping a 16-bit IP
+    fn ret16_imm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.ret16()?;
+        let esp = self.get_register32(ESP).wrapping_add(instruction.imm);
+        self.set_register32(ESP, esp);
+        Ok(())
+    }
+    /// Jcc rel16 (0x0f 0x80..0x8f)
+    fn jcc_rel16(&mut self, instruction: &Instruction) {
+        if self.condition((instruction.opcode & 0xf) as u8) {
+            self.jmp_rel16(instruction);
+        }
+    }
+    /// CALL ptr16:16 (0x9a): pushes CS and IP as words, then loads CS:IP.
+    fn call_far16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        self.push16(self.segment(Segment::Cs))?;
+        self.push16(self.eip.0 as u16)?;
+        self.jmp_far(instruction);
+        Ok(())
+    }
+    /// 0xff group: INC, DEC, and near CALL and JMP to a 16-bit IP
+    fn code_ff_16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        match modrm.op {
+            0 => {
+                let result = self.inc16(self.get_rm16(modrm)?);
+                self.set_rm16(modrm, result)
+            }
+            1 => {
+                let result = self.dec16(self.get_rm16(modrm)?);
+                self.set_rm16(modrm, result)
+            }
+            2 => {
+                let target = self.get_rm16(modrm)?;
+                self.push16(self.eip.0 as u16)?;
+                self.eip = Wrapping(target as u32);
+                Ok(())
+            }
+            4 => {
+                self.eip = Wrapping(self.get_rm16(modrm)? as u32);
+                Ok(())
+            }
+            op => Err(EmulatorError::UnimplementedModRM {
+                opcode: instruction.opcode,
+                op,
+            }),
+        }
+    }
+
+    fn in_ax_imm8(&mut self, instruction: &Instruction) {
+        let value = self.io_in16(instruction.imm as u16);
+        self.set_register16(EAX, value);
+    }
+    fn in_ax_dx(&mut self) {
+        let value = self.io_in16(self.dx_port());
+        self.set_register16(EAX, value);
+    }
+    fn out_imm8_ax(&mut self, instruction: &Instruction) {
+        let value = self.get_register16(EAX);
+        self.io_out16(instruction.imm as u16, value);
+    }
+    fn out_dx_ax(&mut self) {
+        let value = self.get_register16(EAX);
+        self.io_out16(self.dx_port(), value);
+    }
+
+    /// 16-bit counterpart of `update_eflags_add()`.
+    fn update_eflags_add16(&mut self, v1: u16, v2: u16, result: u32) {
+        self.update_eflags(
+            v1 as u32,
+            v2 as u32,
+            result as u64,
+            OperandWidth::Word,
+            FlagOp::Add,
+        );
+    }
+
+    /// 16-bit counterpart of `update_eflags_sub()`.
+    fn update_eflags_sub16(&mut self, v1: u16, v2: u16, result: u32) {
+        self.update_eflags(
+            v1 as u32,
+            v2 as u32,
+            result as u64,
+            OperandWidth::Word,
+            FlagOp::Sub,
+        );
+    }
+
+    /// 16-bit counterpart of `update_eflags_logic()`.
+    fn update_eflags_logic16(&mut self, result: u16) {
+        self.set_carry(false);
+        self.set_overflow(false);
+        self.set_zero(result == 0);
+        self.set_parity(Self::even_parity(result as u8));
+        self.set_sign(result.get_bit(15));
+    }
+
+    pub(super) fn get_register16(&self, reg: u8) -> u16 {
+        self.get_register32(reg) as u16
+    }
+    /// Writes the low 16 bits of a register, keeping the upper half.
+    pub(super) fn set_register16(&mut self, reg: u8, value: u16) {
+        let upper = self.get_register32(reg) & 0xffff0000;
+        self.set_register32(reg, upper | value as u32);
+    }
+    fn get_r16(&self, modrm: &ModRM) -> u16 {
+        self.get_register16(modrm.op)
+    }
+    fn set_r16(&mut self, modrm: &ModRM, value: u16) {
+        self.set_register16(modrm.op, value);
+    }
+    fn set_rm16(&mut self, modrm: &ModRM, value: u16) -> Result<(), EmulatorError> {
+        if modrm.is_reg() {
+            self.set_register16(modrm.rm, value);
+            Ok(())
+        } else {
+            let address = self.calc_memory_address(modrm);
+            self.try_set_memory16(address, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::descriptor::DescriptorTable;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::{RunExit, EBX, ECX};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn mov_ax_imm16() {
+        // mov ax, 0x1234; mov word [0x100], ax
+        let mut emu = emulator(&[
+            0x66, 0xb8, 0x34, 0x12, 0x66, 0x89, 0x05, 0x00, 0x01, 0x00, 0x00,
+        ]);
+        emu.registers[EAX as usize] = 0xdeadbeef;
+        emu.memory[0x100..0x104].fill(0xff);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0xdead1234);
+        assert_eq!(emu.eip(), 0x7c04);
+        emu.step().unwrap();
+        assert_eq!(emu.memory[0x100..0x104], [0x34, 0x12, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn real16_default_operand_size() {
+        // mov ax, 0x1234 needs no prefix in Real16; 66 mov eax, 0x12345678
+        let code = [0xb8, 0x34, 0x12, 0x66, 0xb8, 0x78, 0x56, 0x34, 0x12];
+        let mut emu = emulator(&code);
+        emu.set_mode(CpuMode::Real16);
+        emu.registers[EAX as usize] = 0xdeadbeef;
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0xdead1234);
+        assert_eq!(emu.eip(), 0x7c03);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0x12345678);
+        assert_eq!(emu.eip(), 0x7c09);
+
+        // the same bytes in Protected32 read b8 as mov eax, imm32
+        let mut emu = emulator(&code);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0xb8661234);
+        assert_eq!(emu.eip(), 0x7c05);
+    }
+
+    #[test]
+    fn real16_program() {
+        // jmp short +2; mov ah, 0xff; mov ah, 0x0e; mov al, 'A'; call +1;
+        // hlt; int 80h; ret
+        let mut emu = emulator(&[
+            0xeb, 0x02, 0xb4, 0xff, 0xb4, 0x0e, 0xb0, 0x41, 0xe8, 0x01, 0x00, 0xf4, 0xcd, 0x80,
+            0xc3,
+        ]);
+        emu.set_mode(CpuMode::Real16);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let recorded = seen.clone();
+        emu.set_interrupt_handler(
+            0x80,
+            Box::new(move |emu| {
+                recorded.borrow_mut().push(emu.get_register32(EAX));
+                Ok(())
+            }),
+        );
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(*seen.borrow(), vec![0x0e41]);
+        assert_eq!(emu.eip(), 0x7c0c);
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+        assert_eq!(emu.decode(0x7c08).unwrap().to_string(), "call 0x7c0c");
+    }
+
+    #[test]
+    fn real16_string_loop_lgdt() {
+        // mov di, 0x200; mov cx, 4; mov al, 0xaa; rep stosb; mov cx, 3;
+        // xor bx, bx; inc bx; loop -3; lgdt [bx+si+0x100]; hlt
+        let mut emu = emulator(&[
+            0xbf, 0x00, 0x02, 0xb9, 0x04, 0x00, 0xb0, 0xaa, 0xf3, 0xaa, 0xb9, 0x03, 0x00, 0x31,
+            0xdb, 0x43, 0xe2, 0xfd, 0x0f, 0x01, 0x90, 0x00, 0x01, 0xf4,
+        ]);
+        emu.set_mode(CpuMode::Real16);
+        // only DI, CX and SI count with 16-bit addressing
+        emu.registers[EDI as usize] = 0x12340000;
+        emu.registers[ECX as usize] = 0xffff0000;
+        emu.registers[ESI as usize] = 0xabcd000d;
+        // limit 0x17, base 0x12345678 at bx + si + 0x100
+        emu.load(&[0x17, 0x00, 0x78, 0x56, 0x34, 0x12], 0x110)
+            .unwrap();
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.memory[0x200..0x205], [0xaa, 0xaa, 0xaa, 0xaa, 0x00]);
+        assert_eq!(emu.registers[EDI as usize], 0x12340204);
+        assert_eq!(emu.registers[ECX as usize], 0xffff0000);
+        assert_eq!(emu.registers[EBX as usize] & 0xffff, 3);
+        // LGDT with 16-bit operands drops the top byte of the base
+        assert_eq!(
+            emu.gdtr(),
+            DescriptorTable {
+                limit: 0x17,
+                base: 0x345678
+            }
+        );
+        assert_eq!(
+            emu.decode(0x7c12).unwrap().to_string(),
+            "lgdt [bx+si+0x100]"
+        );
+    }
+
+    #[test]
+    fn real16_groups() {
+        // mov ax, 0x1234; mov cx, 0x10; mul cx; div cx; shl ax, 4;
+        // dec word [bx]; jnz rel16 +1; hlt; pushf; popf; hlt
+        let mut emu = emulator(&[
+            0xb8, 0x34, 0x12, 0xb9, 0x10, 0x00, 0xf7, 0xe1, 0xf7, 0xf1, 0xc1, 0xe0, 0x04, 0xff,
+            0x0f, 0x0f, 0x85, 0x01, 0x00, 0xf4, 0x9c, 0x9d, 0xf4,
+        ]);
+        emu.set_mode(CpuMode::Real16);
+        emu.registers[EBX as usize] = 0x100;
+        emu.memory[0x100..0x102].copy_from_slice(&[0x02, 0x00]);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.registers[EAX as usize] & 0xffff, 0x2340);
+        assert_eq!(emu.registers[EDX as usize] & 0xffff, 0x0001);
+        assert!(emu.get_carry());
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize] & 0xffff, 0x1234);
+        assert_eq!(emu.registers[EDX as usize] & 0xffff, 0);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EAX as usize] & 0xffff, 0x2340);
+        assert!(emu.get_carry());
+        emu.step().unwrap();
+        assert_eq!(emu.memory[0x100..0x102], [0x01, 0x00]);
+        assert_eq!(emu.run(), Ok(RunExit::Halted));
+        assert_eq!(emu.eip(), 0x7c17);
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+    }
+
+    #[test]
+    fn arithmetic16() {
+        // add bx, cx; cmp bx, 0x8000; sub bx, 1 (83 /5)
+        let mut emu = emulator(&[
+            0x66, 0x01, 0xcb, 0x66, 0x81, 0xfb, 0x00, 0x80, 0x66, 0x83, 0xeb, 0x01,
+        ]);
+        emu.registers[EBX as usize] = 0x0001ffff;
+        emu.registers[ECX as usize] = 0x00000001;
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x00010000);
+        assert!(emu.get_carry());
+        assert!(emu.get_zero());
+        emu.step().unwrap();
+        assert!(emu.get_carry());
+        assert!(emu.get_overflow());
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x0001ffff);
+        assert!(emu.get_sign());
+    }
+
+    #[test]
+    fn logic16() {
+        // or bx, 0x8000; and bx, 0x00ff; xor bx, -1 (83 /6)
+        let mut emu = emulator(&[
+            0x66, 0x81, 0xcb, 0x00, 0x80, 0x66, 0x81, 0xe3, 0xff, 0x00, 0x66, 0x83, 0xf3, 0xff,
+        ]);
+        emu.registers[EBX as usize] = 0x00010001;
+        emu.set_carry(true);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x00018001);
+        assert!(emu.get_sign());
+        assert!(!emu.get_carry());
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x00010001);
+        assert!(!emu.get_sign());
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x0001fffe);
+        assert!(emu.get_sign());
+        assert!(!emu.get_overflow());
+    }
+
+    #[test]
+    fn push_pop16() {
+        // push ax; pop bx
+        let mut emu = emulator(&[0x66, 0x50, 0x66, 0x5b]);
+        emu.registers[EAX as usize] = 0x12345678;
+        emu.registers[EBX as usize] = 0xffffffff;
+        emu.step().unwrap();
+        assert_eq!(emu.registers[ESP as usize], 0x7bfe);
+        assert_eq!(emu.get_memory16(0x7bfe), 0x5678);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[ESP as usize], 0x7c00);
+        assert_eq!(emu.registers[EBX as usize], 0xffff5678);
+    }
+}
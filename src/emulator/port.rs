@@ -0,0 +1,94 @@
+use crate::emulator::Emulator;
+
+/// A device behind one or more I/O ports, attached with `attach_port()`.
+pub trait PortDevice {
+    fn in8(&mut self, port: u16) -> u8;
+    fn out8(&mut self, port: u16, value: u8);
+}
+
+impl Emulator {
+    /// Routes IN/OUT on `port` to `device`, replacing whatever was attached
+    /// there. Attached devices take precedence over the clock and serial
+    /// ports.
+    pub fn attach_port(&mut self, port: u16, device: Box<dyn PortDevice>) {
+        self.ports.insert(port, device);
+    }
+
+    pub fn detach_port(&mut self, port: u16) -> Option<Box<dyn PortDevice>> {
+        self.ports.remove(&port)
+    }
+
+    /// Reads from the device at `port`, if any.
+    pub(super) fn port_in8(&mut self, port: u16) -> Option<u8> {
+        Some(self.ports.get_mut(&port)?.in8(port))
+    }
+
+    /// Writes to the device at `port`; returns false if there is none.
+    pub(super) fn port_out8(&mut self, port: u16, value: u8) -> bool {
+        match self.ports.get_mut(&port) {
+            Some(device) => {
+                device.out8(port, value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::{AL, EAX};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Keyboard controller data port answering with the last byte written.
+    struct Latch(Rc<RefCell<Vec<u8>>>);
+
+    impl PortDevice for Latch {
+        fn in8(&mut self, _port: u16) -> u8 {
+            self.0.borrow().last().copied().unwrap_or(0xff)
+        }
+        fn out8(&mut self, _port: u16, value: u8) {
+            self.0.borrow_mut().push(value);
+        }
+    }
+
+    #[test]
+    fn keyboard_port() {
+        // mov edx, 0x60; in al, dx; mov al, 0xf4; out dx, al; in al, dx
+        let code = [0xba, 0x60, 0x00, 0x00, 0x00, 0xec, 0xb0, 0xf4, 0xee, 0xec];
+        let mut emu = emulator(&code);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        emu.attach_port(0x60, Box::new(Latch(written.clone())));
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.get_register8(AL), 0xff);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(*written.borrow(), vec![0xf4]);
+        emu.set_register8(AL, 0);
+        emu.step().unwrap();
+        assert_eq!(emu.get_register8(AL), 0xf4);
+    }
+
+    #[test]
+    fn immediate_and_wide_ports() {
+        // out 0x60, al; mov edx, 0x60; out dx, eax; in ax, 0x60
+        let code = [
+            0xe6, 0x60, 0xba, 0x60, 0x00, 0x00, 0x00, 0xef, 0x66, 0xe5, 0x60,
+        ];
+        let mut emu = emulator(&code);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        for port in 0x60..0x64 {
+            emu.attach_port(port, Box::new(Latch(written.clone())));
+        }
+        emu.set_register32(EAX, 0x44332211);
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert_eq!(*written.borrow(), vec![0x11, 0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(emu.get_register32(EAX), 0x44334444);
+    }
+}
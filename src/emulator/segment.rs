@@ -0,0 +1,131 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::Instruction;
+use crate::emulator::Emulator;
+
+/// Segment registers, in their ModRM/`Sreg` encoding order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Es = 0,
+    Cs = 1,
+    Ss = 2,
+    Ds = 3,
+    Fs = 4,
+    Gs = 5,
+}
+
+pub const SEGMENT_COUNT: usize = 6;
+
+impl Segment {
+    /// Segment selected by an override prefix byte.
+    pub fn from_prefix(prefix: u8) -> Option<Segment> {
+        match prefix {
+            0x26 => Some(Segment::Es),
+            0x2e => Some(Segment::Cs),
+            0x36 => Some(Segment::Ss),
+            0x3e => Some(Segment::Ds),
+            0x64 => Some(Segment::Fs),
+            0x65 => Some(Segment::Gs),
+            _ => None,
+        }
+    }
+
+    /// Segment named by the reg field of a ModRM (`Sreg`); 6 and 7 are
+    /// reserved.
+    pub fn from_sreg(sreg: u8) -> Option<Segment> {
+        [
+            Segment::Es,
+            Segment::Cs,
+            Segment::Ss,
+            Segment::Ds,
+            Segment::Fs,
+            Segment::Gs,
+        ]
+        .get(sreg as usize)
+        .copied()
+    }
+}
+
+impl Emulator {
+    /// Selector held by a segment register. Loading one does not change the
+    /// segment base; see `set_segment_base()`.
+    pub fn segment(&self, segment: Segment) -> u16 {
+        self.segments[segment as usize]
+    }
+    pub fn set_segment(&mut self, segment: Segment, selector: u16) {
+        self.segments[segment as usize] = selector;
+    }
+
+    /// MOV r/m16, Sreg (0x8c). A register destination gets the selector
+    /// zero-extended, or only its low word with 16-bit operands.
+    pub(super) fn mov_rm16_sreg(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let segment = Segment::from_sreg(modrm.op).ok_or(EmulatorError::UnimplementedModRM {
+            opcode: instruction.opcode,
+            op: modrm.op,
+        })?;
+        let selector = self.segment(segment);
+        if !modrm.is_reg() {
+            let address = self.calc_memory_address(modrm);
+            self.try_set_memory16(address, selector)?;
+        } else if instruction.prefixes.operand_size {
+            let value = self.get_register32(modrm.rm) & 0xffff0000 | selector as u32;
+            self.set_register32(modrm.rm, value);
+        } else {
+            self.set_register32(modrm.rm, selector as u32);
+        }
+        Ok(())
+    }
+
+    /// MOV Sreg, r/m16 (0x8e); CS cannot be loaded this way. Only the
+    /// selector changes, and since no interrupts are delivered, loading SS
+    /// needs no one-instruction interrupt shadow.
+    pub(super) fn mov_sreg_rm16(&mut self, instruction: &Instruction) -> Result<(), EmulatorError> {
+        let modrm = instruction.modrm();
+        let segment = match Segment::from_sreg(modrm.op) {
+            Some(segment) if segment != Segment::Cs => segment,
+            _ => {
+                return Err(EmulatorError::UnimplementedModRM {
+                    opcode: instruction.opcode,
+                    op: modrm.op,
+                })
+            }
+        };
+        let selector = self.get_rm16(modrm)?;
+        self.set_segment(segment, selector);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::{EAX, EBX};
+
+    #[test]
+    fn mov_sreg() {
+        // mov ds, ax; mov ebx, ds; mov [0x100], ds; mov es, [0x100]; mov cs, ax
+        let code = [
+            0x8e, 0xd8, 0x8c, 0xdb, 0x8c, 0x1d, 0x00, 0x01, 0x00, 0x00, 0x8e, 0x05, 0x00, 0x01,
+            0x00, 0x00, 0x8e, 0xc8,
+        ];
+        let mut emu = emulator(&code);
+        emu.registers[EAX as usize] = 0xffff0023;
+        emu.registers[EBX as usize] = 0xffffffff;
+        emu.step().unwrap();
+        assert_eq!(emu.segment(Segment::Ds), 0x23);
+        emu.step().unwrap();
+        assert_eq!(emu.registers[EBX as usize], 0x23);
+        emu.step().unwrap();
+        assert_eq!(emu.memory[0x100..0x102], [0x23, 0x00]);
+        emu.step().unwrap();
+        assert_eq!(emu.segment(Segment::Es), 0x23);
+        assert_eq!(
+            emu.step(),
+            Err(EmulatorError::UnimplementedModRM {
+                opcode: 0x8e,
+                op: 1
+            })
+        );
+    }
+}
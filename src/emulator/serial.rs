@@ -0,0 +1,126 @@
+use crate::emulator::Emulator;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+
+/// I/O port of the serial console (COM1 data register)
+pub const SERIAL_PORT: u16 = 0x03f8;
+
+/// Backing streams of the serial console; stdin/stdout by default.
+pub struct Serial {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    /// bytes from `feed_input()`, read before `input`
+    queued: VecDeque<u8>,
+    /// whether `input` is read once `queued` runs dry
+    fallback: bool,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new(
+            Box::new(BufReader::new(std::io::stdin())),
+            Box::new(std::io::stdout()),
+        )
+    }
+}
+
+impl Serial {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Serial {
+        Serial {
+            input,
+            output,
+            queued: VecDeque::new(),
+            fallback: true,
+        }
+    }
+
+    /// Returns the next queued byte, or else reads a line and returns its
+    /// first byte, or 0 at end of input.
+    pub(super) fn read(&mut self) -> u8 {
+        if let Some(value) = self.queued.pop_front() {
+            return value;
+        }
+        if !self.fallback {
+            return 0;
+        }
+        let mut buf = String::new();
+        self.input
+            .read_line(&mut buf)
+            .expect("serial input is dead");
+        buf.as_bytes().first().copied().unwrap_or(0)
+    }
+
+    /// Writes an ASCII byte as is and anything else as two hex digits.
+    pub(super) fn write(&mut self, value: u8) {
+        if value.is_ascii() {
+            write!(self.output, "{}", value as char)
+        } else {
+            write!(self.output, "{:02x}", value)
+        }
+        .expect("serial output is dead");
+    }
+}
+
+impl Emulator {
+    /// Connects the serial console at `SERIAL_PORT` to other streams.
+    pub fn set_serial(&mut self, serial: Serial) {
+        self.serial = serial;
+    }
+
+    /// Sends serial console output to `sink` instead of stdout, keeping the
+    /// input side as it is.
+    pub fn set_output(&mut self, sink: Box<dyn Write>) {
+        self.serial.output = sink;
+    }
+
+    /// Queues bytes for the serial console; each read of `SERIAL_PORT` takes
+    /// one of them before the input stream is touched.
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.serial.queued.extend(bytes);
+    }
+
+    /// Whether the serial console reads its input stream once the queued
+    /// bytes run out (the default), or returns 0 instead.
+    pub fn set_input_fallback(&mut self, fallback: bool) {
+        self.serial.fallback = fallback;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::test_util::SharedBuffer;
+    use crate::emulator::RunExit;
+
+    #[test]
+    fn output_to_buffer() {
+        // mov edx, 0x3f8; mov al, 'o'; out dx, al; mov al, 0x80; out dx, al
+        let code = [
+            0xba, 0xf8, 0x03, 0x00, 0x00, 0xb0, 0x6f, 0xee, 0xb0, 0x80, 0xee,
+        ];
+        let output = SharedBuffer::default();
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x8000);
+        emu.load(&code, 0x7c00).unwrap();
+        emu.set_output(Box::new(output.clone()));
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(*output.0.borrow(), b"o80");
+    }
+
+    #[test]
+    fn fed_input_is_echoed() {
+        // mov edx, 0x3f8; 3 x (in al, dx; out dx, al); ret
+        let code = [
+            0xba, 0xf8, 0x03, 0x00, 0x00, 0xec, 0xee, 0xec, 0xee, 0xec, 0xee, 0xc3,
+        ];
+        let output = SharedBuffer::default();
+        let mut emu = Emulator::new(0x10000, 0x7c00, 0x8000);
+        emu.load(&code, 0x7c00).unwrap();
+        emu.set_serial(Serial::new(Box::new(&b"x\n"[..]), Box::new(output.clone())));
+        emu.feed_input(b"hi");
+        emu.set_input_fallback(false);
+        assert_eq!(emu.run(), Ok(RunExit::Terminated));
+        assert_eq!(*output.0.borrow(), b"hi\0");
+    }
+}
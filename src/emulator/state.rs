@@ -0,0 +1,192 @@
+use crate::emulator::descriptor::DescriptorTable;
+use crate::emulator::fpu::Fpu;
+use crate::emulator::operand_size::CpuMode;
+use crate::emulator::segment::SEGMENT_COUNT;
+use crate::emulator::{Emulator, FLAG_NAMES, REGISTER_COUNT};
+use std::collections::BTreeMap;
+use std::num::Wrapping;
+
+/// Architectural CPU state, without memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    /// general purpose registers, indexed by `EAX`..`EDI`
+    pub registers: [u32; REGISTER_COUNT],
+    pub eflags: u32,
+    pub eip: u32,
+}
+
+/// Everything `restore()` needs to resume execution exactly where
+/// `snapshot()` was taken. Devices, hooks and debugging aids are not part
+/// of it.
+#[derive(Debug, Clone)]
+pub struct EmulatorSnapshot {
+    pub cpu: CpuState,
+    pub fpu: Fpu,
+    pub segments: [u16; SEGMENT_COUNT],
+    pub segment_bases: [u32; SEGMENT_COUNT],
+    pub gdtr: DescriptorTable,
+    /// modelled MSRs and their values
+    pub msrs: BTreeMap<u32, u64>,
+    pub mode: CpuMode,
+    pub a20_enabled: bool,
+    /// see `set_address_wrap()`
+    pub address_wrap: Option<usize>,
+    pub halted: bool,
+    pub instruction_count: u64,
+    /// copy of the whole memory
+    pub memory: Box<[u8]>,
+}
+
+impl Emulator {
+    pub fn snapshot(&self) -> EmulatorSnapshot {
+        EmulatorSnapshot {
+            cpu: self.cpu_state(),
+            fpu: self.fpu.clone(),
+            segments: self.segments,
+            segment_bases: self.segment_bases,
+            gdtr: self.gdtr,
+            msrs: self.msrs.clone(),
+            mode: self.mode,
+            a20_enabled: self.a20_enabled,
+            address_wrap: self.address_wrap,
+            halted: self.halted,
+            instruction_count: self.instruction_count,
+            memory: self.memory.clone().into_boxed_slice(),
+        }
+    }
+
+    /// Returns to `snapshot`, including its memory size. Memory is copied
+    /// into the existing allocation where it fits.
+    pub fn restore(&mut self, snapshot: &EmulatorSnapshot) {
+        self.registers = snapshot.cpu.registers;
+        self.eflags = snapshot.cpu.eflags;
+        self.eip = Wrapping(snapshot.cpu.eip);
+        self.fpu = snapshot.fpu.clone();
+        self.segments = snapshot.segments;
+        self.segment_bases = snapshot.segment_bases;
+        self.gdtr = snapshot.gdtr;
+        self.msrs = snapshot.msrs.clone();
+        self.mode = snapshot.mode;
+        self.a20_enabled = snapshot.a20_enabled;
+        self.address_wrap = snapshot.address_wrap;
+        self.halted = snapshot.halted;
+        self.instruction_count = snapshot.instruction_count;
+        self.memory.clear();
+        self.memory.extend_from_slice(&snapshot.memory);
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            eflags: self.eflags,
+            eip: self.eip(),
+        }
+    }
+
+    /// FNV-1a hash of the whole memory, for comparing runs cheaply.
+    pub fn memory_hash(&self) -> u64 {
+        self.memory.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// The CPU state as a JSON object with a fixed key order, e.g.
+    /// `{"registers":{"EAX":1,...},"eflags":{"raw":2,"CF":false,...},"eip":31744,"memory_hash":"..."}`.
+    /// With `with_memory_hash` false the `memory_hash` key is left out.
+    pub fn state_json(&self, with_memory_hash: bool) -> String {
+        let state = self.cpu_state();
+        let registers: Vec<String> = state
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("\"{}\":{}", Self::register_name(i), value))
+            .collect();
+        let mut eflags = vec![format!("\"raw\":{}", state.eflags)];
+        eflags.extend(
+            FLAG_NAMES
+                .iter()
+                .map(|&name| format!("\"{}\":{}", name, self.flag(name).unwrap())),
+        );
+        let mut json = format!(
+            "{{\"registers\":{{{}}},\"eflags\":{{{}}},\"eip\":{}",
+            registers.join(","),
+            eflags.join(","),
+            state.eip
+        );
+        if with_memory_hash {
+            json.push_str(&format!(",\"memory_hash\":\"{:016x}\"", self.memory_hash()));
+        }
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::emulator::descriptor::DescriptorTable;
+    use crate::emulator::operand_size::CpuMode;
+    use crate::emulator::test_util::emulator;
+    use crate::emulator::EAX;
+
+    #[test]
+    fn state_json() {
+        // mov eax, 0x1234; mov ecx, 0xffffffff; cmp eax, ecx
+        let code = [
+            0xb8, 0x34, 0x12, 0x00, 0x00, 0xb9, 0xff, 0xff, 0xff, 0xff, 0x3b, 0xc1,
+        ];
+        let mut emu = emulator(&code);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        let json = emu.state_json(false);
+        assert_eq!(
+            json,
+            "{\"registers\":{\"EAX\":4660,\"ECX\":4294967295,\"EDX\":0,\"EBX\":0,\
+             \"ESP\":31744,\"EBP\":0,\"ESI\":0,\"EDI\":0},\
+             \"eflags\":{\"raw\":5,\"CF\":true,\"PF\":true,\"AF\":false,\"ZF\":false,\
+             \"SF\":false,\"TF\":false,\"IF\":false,\"DF\":false,\"OF\":false},\
+             \"eip\":31756}"
+        );
+        assert!(emu.state_json(true).ends_with(&format!(
+            ",\"memory_hash\":\"{:016x}\"}}",
+            emu.memory_hash()
+        )));
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        // mov [0x100], eax; inc eax; lgdt [0x200]; hlt
+        let code = [
+            0x89, 0x05, 0x00, 0x01, 0x00, 0x00, 0x40, 0x0f, 0x01, 0x15, 0x00, 0x02, 0x00, 0x00,
+            0xf4,
+        ];
+        let mut emu = emulator(&code);
+        emu.registers[EAX as usize] = 0x1234;
+        emu.load(&[0x17, 0x00, 0x00, 0x10, 0x00, 0x00], 0x200)
+            .unwrap();
+        emu.set_msr(0x10, 1);
+        let snapshot = emu.snapshot();
+        let hash = emu.memory_hash();
+
+        emu.run().unwrap();
+        assert!(emu.is_halted());
+        assert_ne!(emu.memory_hash(), hash);
+        assert_eq!(emu.gdtr().base, 0x1000);
+        emu.set_msr(0x10, 2);
+        emu.set_msr(0x1b, 3);
+        emu.set_mode(CpuMode::Real16);
+        emu.set_a20(false);
+
+        emu.restore(&snapshot);
+        assert_eq!(emu.cpu_state(), snapshot.cpu);
+        assert_eq!(emu.gdtr(), DescriptorTable::default());
+        assert_eq!((emu.get_msr(0x10), emu.get_msr(0x1b)), (Some(1), None));
+        assert_eq!(emu.mode(), CpuMode::Protected32);
+        assert!(emu.is_a20_enabled());
+        assert_eq!(emu.memory_hash(), hash);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.instruction_count(), 0);
+        emu.run().unwrap();
+        assert_eq!(emu.registers[EAX as usize], 0x1235);
+    }
+}
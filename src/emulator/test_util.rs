@@ -0,0 +1,33 @@
+use crate::emulator::Emulator;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// 64 KiB of memory with `code` at 0x7c00, which is also EIP and ESP.
+pub(crate) fn emulator(code: &[u8]) -> Emulator {
+    let mut emu = Emulator::new(0x10000, 0x7c00, 0x7c00);
+    emu.load(code, 0x7c00).unwrap();
+    emu
+}
+
+/// `emulator()` with the serial output captured.
+pub(crate) fn emulator_with_output(code: &[u8]) -> (Emulator, SharedBuffer) {
+    let output = SharedBuffer::default();
+    let mut emu = emulator(code);
+    emu.set_output(Box::new(output.clone()));
+    (emu, output)
+}
+
+/// Writer whose output stays readable after the emulator takes it.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
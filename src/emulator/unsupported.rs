@@ -0,0 +1,217 @@
+use crate::emulator::error::EmulatorError;
+use crate::emulator::instruction::InstructionReader;
+use crate::emulator::Emulator;
+
+/// An opcode `run()` skipped because the emulator does not implement it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedOpcode {
+    /// opcode; two-byte opcodes are 0x0fxx
+    pub opcode: u16,
+    /// likely mnemonic, "?" when unknown
+    pub mnemonic: &'static str,
+    /// EIP of the first occurrence
+    pub address: u32,
+    /// how many times it was hit
+    pub count: u64,
+}
+
+/// Best-effort encoding of an opcode the decoder does not support:
+/// mnemonic, whether a ModRM follows, and the immediate size in bytes with
+/// 32-bit operands and addresses. See `skip_unsupported_opcode()` for the
+/// adjustments to other sizes and to the 0xf6/0xf7 groups.
+fn hint(opcode: u16) -> (&'static str, bool, u32) {
+    const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+    const JCC: [&str; 16] = [
+        "jo", "jno", "jc", "jnc", "jz", "jnz", "jbe", "jnbe", "js", "jns", "jp", "jnp", "jl",
+        "jnl", "jle", "jnle",
+    ];
+    match opcode {
+        0x00..=0x3f if opcode & 0b111 < 6 => {
+            let mnemonic = ALU[(opcode >> 3) as usize];
+            match opcode & 0b111 {
+                0..=3 => (mnemonic, true, 0),
+                4 => (mnemonic, false, 1),
+                _ => (mnemonic, false, 4),
+            }
+        }
+        0x06 | 0x0e | 0x16 | 0x1e => ("push sreg", false, 0),
+        0x07 | 0x17 | 0x1f => ("pop sreg", false, 0),
+        0x27 => ("daa", false, 0),
+        0x2f => ("das", false, 0),
+        0x37 => ("aaa", false, 0),
+        0x3f => ("aas", false, 0),
+        0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => ("segment prefix", false, 0),
+        0x40..=0x47 => ("inc", false, 0),
+        0x48..=0x4f => ("dec", false, 0),
+        0x50..=0x57 => ("push", false, 0),
+        0x58..=0x5f => ("pop", false, 0),
+        0x60 => ("pushad", false, 0),
+        0x61 => ("popad", false, 0),
+        0x62 => ("bound", true, 0),
+        0x63 => ("arpl", true, 0),
+        0x66 => ("operand size prefix", false, 0),
+        0x67 => ("address size prefix", false, 0),
+        0x68 => ("push", false, 4),
+        0x69 => ("imul", true, 4),
+        0x6a => ("push", false, 1),
+        0x6b => ("imul", true, 1),
+        0x6c | 0x6d => ("ins", false, 0),
+        0x6e | 0x6f => ("outs", false, 0),
+        0x70..=0x7f => (JCC[(opcode & 0xf) as usize], false, 1),
+        0x80 | 0x82 | 0x83 => ("arith imm8", true, 1),
+        0x81 => ("arith imm32", true, 4),
+        0x84 | 0x85 => ("test", true, 0),
+        0x86 | 0x87 => ("xchg", true, 0),
+        0x88..=0x8c | 0x8e => ("mov", true, 0),
+        0x8d => ("lea", true, 0),
+        0x8f => ("pop", true, 0),
+        0x90 => ("nop", false, 0),
+        0x91..=0x97 => ("xchg", false, 0),
+        0x98 => ("cwde", false, 0),
+        0x99 => ("cdq", false, 0),
+        0x9a => ("call far", false, 6),
+        0x9b => ("fwait", false, 0),
+        0x9c => ("pushfd", false, 0),
+        0x9d => ("popfd", false, 0),
+        0x9e => ("sahf", false, 0),
+        0x9f => ("lahf", false, 0),
+        0xa0..=0xa3 => ("mov moffs", false, 4),
+        0xa4 | 0xa5 => ("movs", false, 0),
+        0xa6 | 0xa7 => ("cmps", false, 0),
+        0xa8 => ("test", false, 1),
+        0xa9 => ("test", false, 4),
+        0xaa | 0xab => ("stos", false, 0),
+        0xac | 0xad => ("lods", false, 0),
+        0xae | 0xaf => ("scas", false, 0),
+        0xb0..=0xb7 => ("mov", false, 1),
+        0xb8..=0xbf => ("mov", false, 4),
+        0xc0 | 0xc1 => ("shift imm8", true, 1),
+        0xc2 => ("ret", false, 2),
+        0xc3 => ("ret", false, 0),
+        0xc4 => ("les", true, 0),
+        0xc5 => ("lds", true, 0),
+        0xc6 => ("mov", true, 1),
+        0xc7 => ("mov", true, 4),
+        0xc8 => ("enter", false, 3),
+        0xc9 => ("leave", false, 0),
+        0xca => ("retf", false, 2),
+        0xcb => ("retf", false, 0),
+        0xcc => ("int3", false, 0),
+        0xcd => ("int", false, 1),
+        0xce => ("into", false, 0),
+        0xcf => ("iret", false, 0),
+        0xd0..=0xd3 => ("shift", true, 0),
+        0xd4 => ("aam", false, 1),
+        0xd5 => ("aad", false, 1),
+        0xd6 => ("salc", false, 0),
+        0xd7 => ("xlat", false, 0),
+        0xd8..=0xdf => ("x87", true, 0),
+        0xe0 => ("loopne", false, 1),
+        0xe1 => ("loope", false, 1),
+        0xe2 => ("loop", false, 1),
+        0xe3 => ("jecxz", false, 1),
+        0xe4 | 0xe5 => ("in", false, 1),
+        0xe6 | 0xe7 => ("out", false, 1),
+        0xe8 => ("call", false, 4),
+        0xe9 => ("jmp", false, 4),
+        0xea => ("jmp far", false, 6),
+        0xeb => ("jmp", false, 1),
+        0xec | 0xed => ("in", false, 0),
+        0xee | 0xef => ("out", false, 0),
+        0xf0 => ("lock prefix", false, 0),
+        0xf1 => ("int1", false, 0),
+        0xf2 => ("repne prefix", false, 0),
+        0xf3 => ("rep prefix", false, 0),
+        0xf4 => ("hlt", false, 0),
+        0xf5 => ("cmc", false, 0),
+        0xf6 => ("arith group r/m8", true, 0),
+        0xf7 => ("arith group r/m32", true, 0),
+        0xf8 => ("clc", false, 0),
+        0xf9 => ("stc", false, 0),
+        0xfa => ("cli", false, 0),
+        0xfb => ("sti", false, 0),
+        0xfc => ("cld", false, 0),
+        0xfd => ("std", false, 0),
+        0xfe => ("inc/dec r/m8", true, 0),
+        0xff => ("inc/dec/call/jmp/push r/m32", true, 0),
+        0x0f01 => ("lgdt/sgdt group", true, 0),
+        0x0f0b => ("ud2", false, 0),
+        0x0f1f => ("nop", true, 0),
+        0x0f30 => ("wrmsr", false, 0),
+        0x0f31 => ("rdtsc", false, 0),
+        0x0f32 => ("rdmsr", false, 0),
+        0x0f33 => ("rdpmc", false, 0),
+        0x0f40..=0x0f4f => ("cmovcc", true, 0),
+        0x0f80..=0x0f8f => (JCC[(opcode & 0xf) as usize], false, 4),
+        0x0f90..=0x0f9f => ("setcc", true, 0),
+        0x0fa2 => ("cpuid", false, 0),
+        0x0fa3 => ("bt", true, 0),
+        0x0fab => ("bts", true, 0),
+        0x0faf => ("imul", true, 0),
+        0x0fb3 => ("btr", true, 0),
+        0x0fb6 | 0x0fb7 => ("movzx", true, 0),
+        0x0fb8 => ("popcnt", true, 0),
+        0x0fba => ("bt group", true, 1),
+        0x0fbb => ("btc", true, 0),
+        0x0fbc => ("bsf", true, 0),
+        0x0fbd => ("bsr", true, 0),
+        0x0fbe | 0x0fbf => ("movsx", true, 0),
+        0x0fc8..=0x0fcf => ("bswap", false, 0),
+        _ => ("?", false, 0),
+    }
+}
+
+impl Emulator {
+    /// When enabled, `run()` records unknown opcodes and skips them using a
+    /// guessed length instead of failing. See `unsupported_report()`.
+    pub fn set_skip_unsupported(&mut self, skip: bool) {
+        self.skip_unsupported = skip;
+    }
+
+    /// Unknown opcodes skipped so far, most frequent first.
+    pub fn unsupported_report(&self) -> Vec<UnsupportedOpcode> {
+        let mut report: Vec<_> = self.unsupported.values().cloned().collect();
+        report.sort_by(|a, b| b.count.cmp(&a.count).then(a.opcode.cmp(&b.opcode)));
+        report
+    }
+
+    /// Records the unknown opcode at EIP and moves EIP past its guessed length.
+    pub(super) fn skip_unsupported_opcode(&mut self, opcode: u16) -> Result<(), EmulatorError> {
+        let address = self.eip.0;
+        let (mnemonic, has_modrm, mut imm_length) = hint(opcode);
+        let mut reader = InstructionReader::new(self, address);
+        let prefixes = reader.read_prefixes()?;
+        reader.read_opcode()?;
+        if has_modrm {
+            let modrm = reader.read_modrm(prefixes.address_size)?;
+            // TEST r/m, imm (/0, /1) is the only member of its group with one
+            match opcode {
+                0xf6 if modrm.op < 2 => imm_length = 1,
+                0xf7 if modrm.op < 2 => imm_length = 4,
+                _ => {}
+            }
+        }
+        // moffs follow the address size, everything else the operand size
+        let narrow = match opcode {
+            0xa0..=0xa3 => prefixes.address_size,
+            _ => prefixes.operand_size,
+        };
+        if narrow && matches!(imm_length, 4 | 6) {
+            imm_length -= 2;
+        }
+        reader.skip(imm_length);
+        let length = reader.length();
+
+        self.unsupported
+            .entry(opcode)
+            .or_insert(UnsupportedOpcode {
+                opcode,
+                mnemonic,
+                address,
+                count: 0,
+            })
+            .count += 1;
+        self.eip += length;
+        Ok(())
+    }
+}
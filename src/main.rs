@@ -1,30 +1,21 @@
-use log::info;
-use std::fs::File;
-use std::io::Read;
+use std::error::Error;
 
-use crate::emulator::Emulator;
+use log::{error, info};
 
-pub mod emulator;
+use nemu::emulator::machine::MachineBuilder;
 
-fn main() -> std::io::Result<()> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    let mut emu = Emulator::new(0x4_000_000, 0x7c00, 0x7c00);
-    let mut file = File::open("./tolset_p86/exec-io-test/select.bin")?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary).unwrap();
-    for (i, &code) in binary.iter().enumerate() {
-        emu.memory[i + 0x7c00] = code;
-    }
+    let mut emu = MachineBuilder::new()
+        .memory(0x4_000_000)
+        .load("./tolset_p86/exec-io-test/select.bin", 0x7c00)
+        .entry(0x7c00)
+        .build()?;
 
-    loop {
-        let instruction = emu.instruction();
-        instruction(&mut emu);
-        if emu.eip.0 == 0 {
-            break;
-        }
-    }
-
-    info!("Program terminated successfully.");
+    let exit = emu
+        .run()
+        .inspect_err(|err| error!("Program aborted: {}", err));
     info!("{}", emu.dump());
+    info!("Program stopped: {:?}", exit?);
     Ok(())
 }